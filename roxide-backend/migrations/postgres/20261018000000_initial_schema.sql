@@ -0,0 +1,30 @@
+-- Same version as the initial schema of SQLite. PostgreSQL databases have no previous schema,
+-- the tables are created with their constraints.
+CREATE TABLE blobs (
+    digest TEXT PRIMARY KEY,
+    size BIGINT NOT NULL CHECK (size >= 0),
+    ref_count BIGINT NOT NULL
+);
+
+CREATE TABLE files (
+    id TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL DEFAULT '',
+    expiration_date BIGINT NOT NULL,
+    upload_date BIGINT NOT NULL,
+    token_used TEXT NOT NULL,
+    content_type TEXT NOT NULL DEFAULT 'unknown',
+    download_count BIGINT NOT NULL DEFAULT 0 CHECK (download_count >= 0),
+    public BOOLEAN NOT NULL DEFAULT TRUE,
+    size BIGINT NOT NULL CHECK (size >= 0),
+    blob TEXT NOT NULL
+);
+
+CREATE TABLE uploads (
+    id TEXT PRIMARY KEY,
+    title TEXT,
+    duration BIGINT,
+    public BOOLEAN,
+    token_used TEXT,
+    upload_length BIGINT,
+    creation_date BIGINT
+);
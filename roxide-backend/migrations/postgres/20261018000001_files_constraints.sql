@@ -0,0 +1,11 @@
+-- Same version as the constraints of SQLite, the tables already have them.
+
+-- Used by the cleaner
+CREATE INDEX files_expiration_date ON files (expiration_date);
+-- Used by the rate limit
+CREATE INDEX files_token_used ON files (token_used, upload_date);
+-- Used to find the files sharing a blob
+CREATE INDEX files_blob ON files (blob);
+-- Used by the rate limit and the cleaning of abandoned uploads
+CREATE INDEX uploads_token_used ON uploads (token_used, creation_date);
+CREATE INDEX uploads_creation_date ON uploads (creation_date);
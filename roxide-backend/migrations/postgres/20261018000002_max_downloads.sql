@@ -0,0 +1,3 @@
+-- Number of downloads after which a file is deleted, unlimited when NULL.
+ALTER TABLE files ADD COLUMN max_downloads BIGINT CHECK (max_downloads > 0);
+ALTER TABLE uploads ADD COLUMN max_downloads BIGINT CHECK (max_downloads > 0);
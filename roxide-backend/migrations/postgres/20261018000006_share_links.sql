@@ -0,0 +1,11 @@
+-- Named links to a file, each with its own expiration date, download limit and counter.
+CREATE TABLE share_links (
+    slug TEXT PRIMARY KEY NOT NULL,
+    file_id TEXT NOT NULL,
+    name TEXT NOT NULL DEFAULT '',
+    creation_date BIGINT NOT NULL,
+    expiration_date BIGINT,
+    max_downloads BIGINT CHECK (max_downloads > 0),
+    download_count BIGINT NOT NULL DEFAULT 0 CHECK (download_count >= 0)
+);
+CREATE INDEX share_links_file_id ON share_links (file_id);
@@ -0,0 +1,14 @@
+-- Groups of files shared under one link, with their own title, expiration date and visibility.
+CREATE TABLE collections (
+    id TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL DEFAULT '',
+    token_used TEXT NOT NULL,
+    creation_date BIGINT NOT NULL,
+    expiration_date BIGINT NOT NULL,
+    visibility TEXT NOT NULL DEFAULT 'public' CHECK (visibility IN ('public', 'unlisted', 'private'))
+);
+
+-- Collection a file belongs to, if any.
+ALTER TABLE files ADD COLUMN collection_id TEXT;
+ALTER TABLE uploads ADD COLUMN collection_id TEXT;
+CREATE INDEX files_collection_id ON files (collection_id);
@@ -0,0 +1,2 @@
+-- Language of the text of a paste, used to highlight it.
+ALTER TABLE files ADD COLUMN language TEXT;
@@ -0,0 +1,5 @@
+-- Orders of the public listing, paginated by (column, id).
+CREATE INDEX files_list_upload_date ON files (visibility, upload_date, id);
+CREATE INDEX files_list_size ON files (visibility, size, id);
+CREATE INDEX files_list_download_count ON files (visibility, download_count, id);
+CREATE INDEX files_list_title ON files (visibility, title, id);
@@ -0,0 +1,11 @@
+-- Text of the files for the full-text search: their title and the content of the text files.
+CREATE TABLE file_search (
+    file_id TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL DEFAULT '',
+    content TEXT NOT NULL DEFAULT '',
+    document tsvector GENERATED ALWAYS AS (setweight(to_tsvector('simple', title), 'A') || setweight(to_tsvector('simple', content), 'B')) STORED
+);
+CREATE INDEX file_search_document ON file_search USING GIN (document);
+
+-- The files uploaded before are found by their title.
+INSERT INTO file_search (file_id, title) SELECT id, COALESCE(title, '') FROM files;
@@ -0,0 +1,4 @@
+-- Id of an attempt to unlock a file: the attempt is recorded before the password is checked, and
+-- deleted if the password is right.
+ALTER TABLE unlock_attempts ADD COLUMN id TEXT;
+CREATE INDEX unlock_attempts_id ON unlock_attempts (id);
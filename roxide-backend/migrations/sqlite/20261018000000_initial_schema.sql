@@ -0,0 +1,30 @@
+-- Schema created by the versions of Roxide without migrations, databases created by these
+-- versions already have these tables.
+CREATE TABLE IF NOT EXISTS files (
+    id TEXT,
+    title TEXT,
+    expiration_date UNSIGNED BIG INT,
+    upload_date UNSIGNED BIG INT,
+    token_used TEXT,
+    content_type TEXT,
+    download_count UNSIGNED BIG INT,
+    public BOOL,
+    size UNSIGNED BIG INT,
+    blob TEXT
+);
+
+CREATE TABLE IF NOT EXISTS blobs (
+    digest TEXT PRIMARY KEY,
+    size UNSIGNED BIG INT,
+    ref_count UNSIGNED BIG INT
+);
+
+CREATE TABLE IF NOT EXISTS uploads (
+    id TEXT PRIMARY KEY,
+    title TEXT,
+    duration BIG INT,
+    public BOOL,
+    token_used TEXT,
+    upload_length UNSIGNED BIG INT,
+    creation_date UNSIGNED BIG INT
+);
@@ -0,0 +1,38 @@
+-- Rebuild files with a primary key and the columns that are always set marked NOT NULL. The
+-- missing values are repaired: a file without an expiration date expires at once, so the
+-- cleaner deletes it with its content. A file without an id or a blob, or an id shared by
+-- several files, cannot be repaired and makes the migration fail, the database has to be fixed
+-- by hand.
+CREATE TABLE files_new (
+    id TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL DEFAULT '',
+    expiration_date BIGINT NOT NULL,
+    upload_date BIGINT NOT NULL,
+    token_used TEXT NOT NULL,
+    content_type TEXT NOT NULL DEFAULT 'unknown',
+    download_count BIGINT NOT NULL DEFAULT 0 CHECK (download_count >= 0),
+    public BOOLEAN NOT NULL DEFAULT TRUE,
+    size BIGINT NOT NULL CHECK (size >= 0),
+    blob TEXT NOT NULL
+);
+
+INSERT INTO files_new (id, title, expiration_date, upload_date, token_used, content_type, download_count, public, size, blob)
+SELECT id, COALESCE(title, ''), COALESCE(expiration_date, 0), COALESCE(upload_date, 0), COALESCE(token_used, ''), COALESCE(content_type, 'unknown'), MAX(COALESCE(download_count, 0), 0), COALESCE(public, TRUE), MAX(COALESCE(size, (SELECT size FROM blobs WHERE blobs.digest = files.blob), 0), 0), blob
+FROM files;
+
+DROP TABLE files;
+ALTER TABLE files_new RENAME TO files;
+
+-- The references of the blobs are counted again, the blobs left without a reference are
+-- deleted with their content by the next maintenance run.
+UPDATE blobs SET ref_count = (SELECT count(1) FROM files WHERE files.blob = blobs.digest);
+
+-- Used by the cleaner
+CREATE INDEX files_expiration_date ON files (expiration_date);
+-- Used by the rate limit
+CREATE INDEX files_token_used ON files (token_used, upload_date);
+-- Used to find the files sharing a blob
+CREATE INDEX files_blob ON files (blob);
+-- Used by the rate limit and the cleaning of abandoned uploads
+CREATE INDEX uploads_token_used ON uploads (token_used, creation_date);
+CREATE INDEX uploads_creation_date ON uploads (creation_date);
@@ -0,0 +1,12 @@
+-- Argon2 hash of the password of a file, not protected when NULL.
+ALTER TABLE files ADD COLUMN password_hash TEXT;
+ALTER TABLE uploads ADD COLUMN password_hash TEXT;
+
+-- Failed attempts to unlock a file, to limit them per file and per client.
+CREATE TABLE unlock_attempts (
+    file_id TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    attempt_date BIGINT NOT NULL
+);
+CREATE INDEX unlock_attempts_file_id ON unlock_attempts (file_id, attempt_date);
+CREATE INDEX unlock_attempts_ip ON unlock_attempts (ip, attempt_date);
@@ -0,0 +1,19 @@
+-- Visibility of a file: listed (public), not listed (unlisted), or downloadable only by its
+-- uploader and the tokens it is shared with (private).
+ALTER TABLE files ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public' CHECK (visibility IN ('public', 'unlisted', 'private'));
+UPDATE files SET visibility = 'unlisted' WHERE NOT public;
+ALTER TABLE files DROP COLUMN public;
+
+ALTER TABLE uploads ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public' CHECK (visibility IN ('public', 'unlisted', 'private'));
+UPDATE uploads SET visibility = 'unlisted' WHERE NOT public;
+ALTER TABLE uploads DROP COLUMN public;
+
+-- Tokens a private file is shared with, besides its uploader.
+CREATE TABLE file_grants (
+    file_id TEXT NOT NULL,
+    token TEXT NOT NULL,
+    PRIMARY KEY (file_id, token)
+);
+
+-- Used by the list of the files
+CREATE INDEX files_visibility ON files (visibility, expiration_date);
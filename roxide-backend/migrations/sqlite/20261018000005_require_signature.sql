@@ -0,0 +1,3 @@
+-- Files only available through a signed link.
+ALTER TABLE files ADD COLUMN require_signature BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE uploads ADD COLUMN require_signature BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,26 @@
+-- Text of the files for the full-text search: their title and the content of the text files.
+CREATE TABLE file_search (
+    id INTEGER PRIMARY KEY,
+    file_id TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL DEFAULT '',
+    content TEXT NOT NULL DEFAULT ''
+);
+
+-- Full-text index of file_search, kept up to date by the triggers.
+CREATE VIRTUAL TABLE file_search_index USING fts5(title, content, content = 'file_search', content_rowid = 'id');
+
+CREATE TRIGGER file_search_insert AFTER INSERT ON file_search BEGIN
+    INSERT INTO file_search_index (rowid, title, content) VALUES (new.id, new.title, new.content);
+END;
+
+CREATE TRIGGER file_search_delete AFTER DELETE ON file_search BEGIN
+    INSERT INTO file_search_index (file_search_index, rowid, title, content) VALUES ('delete', old.id, old.title, old.content);
+END;
+
+CREATE TRIGGER file_search_update AFTER UPDATE ON file_search BEGIN
+    INSERT INTO file_search_index (file_search_index, rowid, title, content) VALUES ('delete', old.id, old.title, old.content);
+    INSERT INTO file_search_index (rowid, title, content) VALUES (new.id, new.title, new.content);
+END;
+
+-- The files uploaded before are found by their title.
+INSERT INTO file_search (file_id, title) SELECT id, COALESCE(title, '') FROM files WHERE id IS NOT NULL;
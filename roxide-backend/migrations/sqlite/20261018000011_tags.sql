@@ -0,0 +1,12 @@
+-- Tags of the files, each name stored once.
+CREATE TABLE tags (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE file_tags (
+    file_id TEXT NOT NULL,
+    tag_id INTEGER NOT NULL,
+    PRIMARY KEY (file_id, tag_id)
+);
+CREATE INDEX file_tags_tag_id ON file_tags (tag_id);
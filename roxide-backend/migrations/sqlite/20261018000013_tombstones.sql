@@ -0,0 +1,7 @@
+-- Ids of the files deleted because they expired or reached their number of downloads, so that
+-- they keep answering 410 for a while. Purged by the maintenance.
+CREATE TABLE tombstones (
+    id TEXT PRIMARY KEY NOT NULL,
+    deletion_date BIGINT NOT NULL
+);
+CREATE INDEX tombstones_deletion_date ON tombstones (deletion_date);
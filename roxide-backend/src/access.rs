@@ -0,0 +1,403 @@
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+
+use rocket_db_pools::Connection;
+
+use sqlx::{AnyConnection, Row};
+
+use crate::password::Unlock;
+use crate::signature::UrlSigner;
+use crate::user::{check_owner, OwnerToken};
+use crate::{bearer_token, is_token_valid, AppConfig, Canard, FileId, RoxideError};
+
+/// Who can see a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Visibility {
+    /// The file is listed by `/list` and anyone can download it.
+    Public,
+    /// The file is not listed, anyone knowing its id can download it.
+    Unlisted,
+    /// The file is not listed, only its uploader and the tokens it is shared with can download
+    /// it.
+    Private,
+}
+
+impl Visibility {
+    /// Value of the visibility in the `visibility` column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+
+    /// Parse the visibility sent with an upload.
+    pub fn parse(visibility: &str) -> Result<Self, RoxideError> {
+        match visibility {
+            "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "private" => Ok(Visibility::Private),
+            _ => Err(RoxideError::InvalidVisibility),
+        }
+    }
+
+    /// Read the `visibility` column, the database only holds valid values.
+    pub fn from_column(visibility: &str) -> Self {
+        match visibility {
+            "private" => Visibility::Private,
+            "unlisted" => Visibility::Unlisted,
+            _ => Visibility::Public,
+        }
+    }
+
+    /// Visibility of the previous `unlisted` and `public` fields, kept for the existing clients.
+    pub fn from_public(public: bool) -> Self {
+        if public {
+            Visibility::Public
+        } else {
+            Visibility::Unlisted
+        }
+    }
+}
+
+/// Request guard that gathers what gives access to a file: the token of the requester, sent as
+/// a bearer token (`Authorization: Bearer <token>`), the signature of a share link
+/// (`?exp=<expiration>&sig=<signature>`), and what unlocks a password-protected file.
+pub struct FileAccess<'r> {
+    token: Option<&'r str>,
+    link: Option<(Option<i64>, Option<&'r str>)>,
+    signer: Option<&'r UrlSigner>,
+    shared: bool,
+    unlock: Unlock<'r>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for FileAccess<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let token = match (bearer_token(req), req.rocket().state::<AppConfig>()) {
+            (Some(token), Some(app_config)) if is_token_valid(token, app_config) => Some(token),
+            _ => None,
+        };
+        let expiration = req.query_value::<i64>("exp");
+        let signature = req.query_value::<&str>("sig");
+        let link = (expiration.is_some() || signature.is_some()).then(|| {
+            (
+                expiration.and_then(|expiration| expiration.ok()),
+                signature.and_then(|signature| signature.ok()),
+            )
+        });
+        req.guard::<Unlock<'r>>().await.map(|unlock| FileAccess {
+            token,
+            link,
+            signer: req.rocket().state::<UrlSigner>(),
+            shared: false,
+            unlock,
+        })
+    }
+}
+
+impl<'r> FileAccess<'r> {
+    pub fn unlock(&self) -> &Unlock<'r> {
+        &self.unlock
+    }
+
+    /// Valid token sent by the requester, if any.
+    pub fn token(&self) -> Option<&'r str> {
+        self.token
+    }
+
+    /// Access given by the share link whose URL has *query*, instead of the query of the
+    /// request. Used by the routes that receive the URL of a file, like `/oembed`.
+    pub fn with_link_of(self, query: Option<&'r str>) -> Self {
+        let mut expiration = None;
+        let mut signature = None;
+        for pair in query.unwrap_or("").split('&') {
+            match pair.split_once('=') {
+                Some(("exp", value)) => expiration = Some(value.parse::<i64>().ok()),
+                Some(("sig", value)) => signature = Some(value),
+                _ => {}
+            }
+        }
+        let link = (expiration.is_some() || signature.is_some())
+            .then(|| (expiration.flatten(), signature));
+        Self { link, ..self }
+    }
+
+    /// Access granted by a share link of the file (`/s/<slug>`), checked by its route.
+    pub fn through_share_link(self) -> Self {
+        Self {
+            shared: true,
+            ..self
+        }
+    }
+
+    /// Indicate if the request comes from a valid share link of the file *id*.
+    ///
+    /// A link with a wrong signature, or that expired, is an error.
+    fn is_signed(&self, id: &FileId) -> Result<bool, RoxideError> {
+        match (self.link, self.signer) {
+            (None, _) => Ok(false),
+            (Some((Some(expiration), Some(signature))), Some(signer)) => {
+                signer.verify(id, expiration, signature).map(|_| true)
+            }
+            _ => Err(RoxideError::InvalidSignature),
+        }
+    }
+
+    /// Check that the requester can see the file *id* of *visibility*, uploaded with
+    /// *token_used*.
+    ///
+    /// A signed or named share link gives access to the file, whatever its visibility. A file
+    /// that *requires_signature* is only available through a share link. A private file the
+    /// requester cannot see does not exist for it.
+    pub async fn check(
+        &self,
+        db: &mut AnyConnection,
+        id: &FileId,
+        visibility: Visibility,
+        token_used: &str,
+        requires_signature: bool,
+    ) -> Result<(), RoxideError> {
+        if self.shared || self.is_signed(id)? {
+            return Ok(());
+        }
+        if requires_signature {
+            return Err(RoxideError::SignatureRequired);
+        }
+        if visibility != Visibility::Private {
+            return Ok(());
+        }
+        let token = self.token.ok_or(RoxideError::NotFound)?;
+        if token == token_used {
+            return Ok(());
+        }
+        sqlx::query("SELECT token FROM file_grants WHERE file_id = $1 AND token = $2")
+            .bind(id.get_id())
+            .bind(token)
+            .fetch_optional(&mut *db)
+            .await?
+            .map(|_| ())
+            .ok_or(RoxideError::NotFound)
+    }
+}
+
+/// Function that deletes the access list of the file *id*, with the file.
+pub async fn delete_grants(db: &mut AnyConnection, id: &FileId) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM file_grants WHERE file_id = $1")
+        .bind(id.get_id())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that lists the tokens a file is shared with, on behalf of its uploader.
+#[get("/file/<id>/access")]
+async fn access_list(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+) -> Result<Json<Vec<String>>, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    let rows = sqlx::query("SELECT token FROM file_grants WHERE file_id = $1 ORDER BY token")
+        .bind(id.get_id())
+        .fetch_all(&mut *db)
+        .await?;
+    Ok(Json(
+        rows.iter()
+            .map(|row| row.get::<String, &str>("token"))
+            .collect(),
+    ))
+}
+
+/// Function that shares a file with *token*, on behalf of its uploader.
+///
+/// Sharing a file again with the same token is not an error. The access list only matters once
+/// the file is private.
+#[put("/file/<id>/access/<token>")]
+async fn grant(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+    token: &str,
+) -> Result<Status, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    sqlx::query("INSERT INTO file_grants (file_id, token) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(id.get_id())
+        .bind(token)
+        .execute(&mut *db)
+        .await?;
+    Ok(Status::NoContent)
+}
+
+/// Function that stops sharing a file with *token*, on behalf of its uploader.
+#[delete("/file/<id>/access/<token>")]
+async fn revoke(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+    token: &str,
+) -> Result<Status, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    sqlx::query("DELETE FROM file_grants WHERE file_id = $1 AND token = $2")
+        .bind(id.get_id())
+        .bind(token)
+        .execute(&mut *db)
+        .await?;
+    Ok(Status::NoContent)
+}
+
+/// Function that mounts the routes that share the private files.
+/// - access_list (to list the tokens a file is shared with).
+/// - grant (to share a file with a token).
+/// - revoke (to stop sharing a file with a token).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Access stage", |rocket| async {
+        rocket.mount("/", routes![access_list, grant, revoke])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, Value};
+    use rocket::State;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::storage::Storage;
+    use crate::test_client;
+    use crate::user::{find_file, test_file};
+
+    /// Route that answers 200 if the requester can download the file *id*.
+    #[get("/check/<id>")]
+    async fn check(
+        storage: &State<Storage>,
+        mut db: Connection<Canard>,
+        id: FileId,
+        access: FileAccess<'_>,
+    ) -> Result<Status, RoxideError> {
+        find_file(storage, &mut db, &id, &access).await?;
+        Ok(Status::Ok)
+    }
+
+    /// Register the files *public*, *unlisted* and *private* uploaded with `token`, and build a
+    /// client of the routes of the access list.
+    async fn client_with_files(dir: &std::path::Path) -> Client {
+        let pool = test_pool(dir).await;
+        let mut db = pool.acquire().await.unwrap();
+        for visibility in ["public", "unlisted", "private"] {
+            test_file(
+                &mut db,
+                visibility,
+                &format!("visibility = '{}'", visibility),
+            )
+            .await;
+        }
+        test_client(dir, routes![check, access_list, grant, revoke]).await
+    }
+
+    /// Status of the download of the file *id* by *token*, if any.
+    async fn download(client: &Client, id: &str, token: Option<&str>) -> Status {
+        let mut request = client.get(format!("/check/{}", id));
+        if let Some(token) = token {
+            request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+        }
+        request.dispatch().await.status()
+    }
+
+    async fn tokens(client: &Client, id: &str) -> Value {
+        let response = client
+            .get(format!("/file/{}/access", id))
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json::<Value>().await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn visibility_decides_who_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_files(dir.path()).await;
+
+        for token in [None, Some("token"), Some("other")] {
+            assert_eq!(download(&client, "public", token).await, Status::Ok);
+            assert_eq!(download(&client, "unlisted", token).await, Status::Ok);
+        }
+        assert_eq!(
+            download(&client, "private", Some("token")).await,
+            Status::Ok
+        );
+        assert_eq!(
+            download(&client, "private", Some("other")).await,
+            Status::NotFound
+        );
+        assert_eq!(download(&client, "private", None).await, Status::NotFound);
+        assert_eq!(
+            download(&client, "missing", Some("token")).await,
+            Status::NotFound
+        );
+    }
+
+    #[rocket::async_test]
+    async fn private_files_are_shared_then_unshared() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_files(dir.path()).await;
+        let access = |id: &str, owner: &str, token: &str, grant: bool| {
+            let uri = format!("/file/{}/access/{}", id, token);
+            let request = if grant {
+                client.put(uri)
+            } else {
+                client.delete(uri)
+            };
+            request
+                .header(Header::new("Authorization", format!("Bearer {}", owner)))
+                .dispatch()
+        };
+
+        // Only the uploader shares its file
+        let response = access("private", "other", "other", true).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(
+            download(&client, "private", Some("other")).await,
+            Status::NotFound
+        );
+
+        for _ in 0..2 {
+            let response = access("private", "token", "other", true).await;
+            assert_eq!(response.status(), Status::NoContent);
+        }
+        assert_eq!(tokens(&client, "private").await, json!(["other"]));
+        assert_eq!(
+            download(&client, "private", Some("other")).await,
+            Status::Ok
+        );
+        assert_eq!(
+            download(&client, "private", Some("third")).await,
+            Status::NotFound
+        );
+
+        let response = access("private", "other", "other", false).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = access("private", "token", "other", false).await;
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(tokens(&client, "private").await, json!([]));
+        assert_eq!(
+            download(&client, "private", Some("other")).await,
+            Status::NotFound
+        );
+        assert_eq!(
+            download(&client, "private", Some("token")).await,
+            Status::Ok
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Request, State};
+
+use rocket_db_pools::Connection;
+
+use sqlx::any::AnyRow;
+use sqlx::{Acquire, Row};
+
+use crate::access::Visibility;
+use crate::storage::Storage;
+use crate::user::delete_file;
+use crate::{bearer_token, AppConfig, Canard, FileId, RoxideError};
+
+/// Request guard that checks the request carries an admin token.
+///
+/// The token is sent as a bearer token (`Authorization: Bearer <token>`) and must be one of
+/// the `admin_tokens` of the configuration: a request without a token gets 401, a request
+/// with another token gets 403.
+pub struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = RoxideError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let is_admin = match (bearer_token(req), req.rocket().state::<AppConfig>()) {
+            (Some(token), Some(app_config)) => app_config.admin_tokens.iter().any(|t| t == token),
+            (Some(_), None) => false,
+            (None, _) => {
+                return request::Outcome::Failure((Status::Unauthorized, RoxideError::InvalidToken))
+            }
+        };
+        if is_admin {
+            request::Outcome::Success(AdminToken)
+        } else {
+            request::Outcome::Failure((Status::Forbidden, RoxideError::Forbidden))
+        }
+    }
+}
+
+/// All the information about a file, as seen by an admin.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminFileData {
+    id: String,
+    title: String,
+    upload_date: i64,
+    expiration_date: i64,
+    token_used: String,
+    content_type: String,
+    download_count: i64,
+    size: i64,
+    visibility: Visibility,
+    max_downloads: Option<i64>,
+    protected: bool,
+    require_signature: bool,
+}
+
+impl AdminFileData {
+    fn from_row(row: &AnyRow) -> Self {
+        AdminFileData {
+            id: row.get::<String, &str>("id"),
+            title: row.get::<String, &str>("title"),
+            upload_date: row.get::<i64, &str>("upload_date"),
+            expiration_date: row.get::<i64, &str>("expiration_date"),
+            token_used: row.get::<String, &str>("token_used"),
+            content_type: row.get::<String, &str>("content_type"),
+            download_count: row.get::<i64, &str>("download_count"),
+            size: row.get::<i64, &str>("size"),
+            visibility: Visibility::from_column(row.get::<&str, &str>("visibility")),
+            max_downloads: row.get::<Option<i64>, &str>("max_downloads"),
+            protected: row.get::<bool, &str>("protected"),
+            require_signature: row.get::<bool, &str>("require_signature"),
+        }
+    }
+}
+
+/// Condition of the files that are still available: not expired and not out of downloads.
+const LIVE_FILES: &str =
+    "expiration_date > $1 AND (max_downloads IS NULL OR download_count < max_downloads)";
+
+/// Columns selected to build an AdminFileData.
+const ADMIN_FILE_COLUMNS: &str = "id, title, upload_date, expiration_date, token_used, content_type, download_count, size, visibility, max_downloads, password_hash IS NOT NULL AS protected, require_signature";
+
+/// Changes that an admin can apply to a file, the missing fields are left untouched.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FileUpdate {
+    expiration_date: Option<i64>,
+    visibility: Option<Visibility>,
+    /// Previous form of the visibility, `false` for an unlisted file.
+    public: Option<bool>,
+}
+
+/// Function that lists all the available files, whatever their visibility.
+#[get("/files")]
+async fn files(
+    _admin: AdminToken,
+    mut db: Connection<Canard>,
+) -> Result<Json<Vec<AdminFileData>>, RoxideError> {
+    let now = Utc::now().timestamp();
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM files WHERE {}",
+        ADMIN_FILE_COLUMNS, LIVE_FILES
+    ))
+    .bind(now)
+    .fetch_all(&mut *db)
+    .await?;
+
+    Ok(Json(rows.iter().map(AdminFileData::from_row).collect()))
+}
+
+/// Function that lists all the available files, grouped by the token used to upload them.
+#[get("/uploads")]
+async fn uploads(
+    _admin: AdminToken,
+    mut db: Connection<Canard>,
+) -> Result<Json<BTreeMap<String, Vec<AdminFileData>>>, RoxideError> {
+    let now = Utc::now().timestamp();
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM files WHERE {} ORDER BY upload_date",
+        ADMIN_FILE_COLUMNS, LIVE_FILES
+    ))
+    .bind(now)
+    .fetch_all(&mut *db)
+    .await?;
+
+    let mut uploads = BTreeMap::<String, Vec<AdminFileData>>::new();
+    for file in rows.iter().map(AdminFileData::from_row) {
+        uploads
+            .entry(file.token_used.clone())
+            .or_default()
+            .push(file);
+    }
+    Ok(Json(uploads))
+}
+
+/// Function that changes the expiration date or the visibility of a file.
+#[patch("/files/<id>", data = "<update>")]
+async fn update(
+    _admin: AdminToken,
+    mut db: Connection<Canard>,
+    id: FileId,
+    update: Json<FileUpdate>,
+) -> Result<Json<AdminFileData>, RoxideError> {
+    let mut tx = db.begin().await?;
+    let row = sqlx::query(&format!(
+        "UPDATE files SET expiration_date = COALESCE($1, expiration_date), visibility = COALESCE($2, visibility) WHERE id = $3 RETURNING {}",
+        ADMIN_FILE_COLUMNS
+    ))
+    .bind(update.expiration_date)
+    .bind(
+        update
+            .visibility
+            .or_else(|| update.public.map(Visibility::from_public))
+            .map(Visibility::as_str),
+    )
+    .bind(id.get_id())
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(AdminFileData::from_row(&row)))
+}
+
+/// Function that deletes a file.
+#[delete("/files/<id>")]
+async fn delete(
+    _admin: AdminToken,
+    storage: &State<Storage>,
+    mut db: Connection<Canard>,
+    id: FileId,
+) -> Result<Status, RoxideError> {
+    delete_file(&mut db, storage, &id).await?;
+    Ok(Status::NoContent)
+}
+
+/// Function that mounts the routes for admin URL in Rocket, under `/admin`.
+/// - files (to list all the files).
+/// - uploads (to list the files by token).
+/// - update (to change the expiration date or the visibility of a file).
+/// - delete (to delete a file).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Admin stage", |rocket| async {
+        rocket.mount("/admin", routes![files, uploads, update, delete])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::Value;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::test_client;
+    use crate::user::test_file;
+
+    /// Register the files *a* (public), *b* (private, uploaded by `other`), *expired* and
+    /// *downloaded* (out of downloads), and build a client of the admin routes, mounted under `/`.
+    async fn client_with_files(dir: &std::path::Path) -> Client {
+        let pool = test_pool(dir).await;
+        let mut db = pool.acquire().await.unwrap();
+        test_file(&mut db, "a", "upload_date = 1").await;
+        test_file(
+            &mut db,
+            "b",
+            "upload_date = 2, visibility = 'private', token_used = 'other'",
+        )
+        .await;
+        test_file(&mut db, "expired", "expiration_date = 1").await;
+        test_file(
+            &mut db,
+            "downloaded",
+            "max_downloads = 1, download_count = 1",
+        )
+        .await;
+        test_client(dir, routes![files, uploads, update, delete]).await
+    }
+
+    async fn get(client: &Client, uri: &str) -> Value {
+        let response = client
+            .get(uri.to_string())
+            .header(Header::new("Authorization", "Bearer admin"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json::<Value>().await.unwrap()
+    }
+
+    fn ids(files: &Value) -> Vec<&str> {
+        files
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["id"].as_str().unwrap())
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn admin_token_is_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_files(dir.path()).await;
+
+        let response = client.get("/files").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/files")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .delete("/files/a")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(ids(&get(&client, "/files").await).len(), 2);
+    }
+
+    #[rocket::async_test]
+    async fn available_files_are_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_files(dir.path()).await;
+
+        let files = get(&client, "/files").await;
+        let mut listed = ids(&files);
+        listed.sort_unstable();
+        assert_eq!(listed, ["a", "b"]);
+
+        let uploads = get(&client, "/uploads").await;
+        let uploads = uploads.as_object().unwrap();
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(ids(&uploads["token"]), ["a"]);
+        assert_eq!(ids(&uploads["other"]), ["b"]);
+        assert_eq!(uploads["other"][0]["visibility"], "private");
+    }
+
+    #[rocket::async_test]
+    async fn files_are_updated_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_files(dir.path()).await;
+
+        let patch = |id: &str, body: &'static str| {
+            client
+                .patch(format!("/files/{}", id))
+                .header(Header::new("Authorization", "Bearer admin"))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+        let response = patch("b", r#"{"visibility": "unlisted"}"#).await;
+        assert_eq!(response.status(), Status::Ok);
+        let file = response.into_json::<Value>().await.unwrap();
+        assert_eq!(file["visibility"], "unlisted");
+        let expiration = file["expiration_date"].as_i64().unwrap();
+
+        let response = patch("b", r#"{"expiration_date": 4102444800}"#).await;
+        let file = response.into_json::<Value>().await.unwrap();
+        assert_eq!(file["expiration_date"], 4102444800i64);
+        assert_ne!(expiration, 4102444800);
+        assert_eq!(file["visibility"], "unlisted");
+        let files = get(&client, "/files").await;
+        let b = files
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|file| file["id"] == "b");
+        assert_eq!(b.unwrap()["visibility"], "unlisted");
+
+        let response = patch("b", r#"{"visibility": "hidden"}"#).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let response = patch("missing", r#"{"visibility": "public"}"#).await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let delete = |id: &str| {
+            client
+                .delete(format!("/files/{}", id))
+                .header(Header::new("Authorization", "Bearer admin"))
+                .dispatch()
+        };
+        assert_eq!(delete("b").await.status(), Status::NoContent);
+        assert_eq!(delete("b").await.status(), Status::NotFound);
+        assert_eq!(ids(&get(&client, "/files").await), ["a"]);
+    }
+}
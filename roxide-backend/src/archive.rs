@@ -0,0 +1,834 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use chrono::{Datelike, TimeZone, Timelike, Utc};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use rocket::fairing::AdHoc;
+use rocket::http::ContentType;
+use rocket::request::FromParam;
+use rocket::response::{self, Responder};
+use rocket::tokio::io::{self as tokio_io, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rocket::{Request, Response, State};
+
+use rocket_db_pools::Connection;
+
+use sqlx::any::AnyRow;
+use sqlx::{AnyConnection, Row};
+
+use crate::access::FileAccess;
+use crate::collection::collection_entries;
+use crate::db::DbPool;
+use crate::storage::{ObjectReader, Storage, StorageBackend};
+use crate::user::open_file;
+use crate::{Canard, FileId, RoxideError};
+
+/// Size of the buffer between the task that writes an archive and the response.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Largest value of the 32-bit fields of a ZIP archive, larger values need ZIP64 records.
+const ZIP32_MAX: u64 = 0xFFFF_FFFF;
+
+/// Largest number of files selected by their ids in an archive.
+const MAX_ARCHIVE_FILES: usize = 1000;
+
+/// Size of the blocks of a tar archive.
+const TAR_BLOCK: usize = 512;
+
+/// Largest size of a file in a tar header (11 octal digits), larger sizes need a pax header.
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// Formats of the archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum ArchiveFormat {
+    /// ZIP archive, without compression.
+    Zip,
+    /// Tar archive compressed with gzip.
+    #[field(value = "tar.gz")]
+    #[field(value = "tgz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            ArchiveFormat::Zip => ContentType::ZIP,
+            ArchiveFormat::TarGz => ContentType::GZIP,
+        }
+    }
+}
+
+/// A stored file to put in an archive.
+pub struct ArchiveEntry {
+    /// Id of the file, its download is counted once the archive is sent.
+    pub id: String,
+    /// Name of the file in the archive.
+    pub name: String,
+    /// Key of the content in the storage.
+    pub key: String,
+    pub size: u64,
+    /// Timestamp of the modification date of the file.
+    pub modified: i64,
+}
+
+/// Function that turns the *title* of a file into a name usable in an archive, and not *used* by
+/// the other files of the archive.
+///
+/// The separators and the control characters are replaced, an empty title is replaced by
+/// *fallback*, and a name already used gets a number before its extension (`name (2).txt`).
+fn entry_name(used: &mut HashSet<String>, title: &str, fallback: &str) -> String {
+    let name = title
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let name = match name.as_str() {
+        "" | "." | ".." => fallback.to_string(),
+        _ => name,
+    };
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name.as_str(), ""),
+    };
+    let mut candidate = name.clone();
+    let mut n = 2;
+    while used.contains(&candidate) {
+        candidate = format!("{} ({}){}", stem, n, extension);
+        n += 1;
+    }
+    used.insert(candidate.clone());
+    candidate
+}
+
+/// Function that turns the *rows* of files into the entries of an archive.
+///
+/// The rows hold the columns `id`, `title`, `blob`, `size` and `upload_date` of the files.
+pub fn row_entries(rows: &[&AnyRow]) -> Vec<ArchiveEntry> {
+    let mut used = HashSet::new();
+    rows.iter()
+        .map(|row| {
+            let id = row.get::<String, &str>("id");
+            ArchiveEntry {
+                name: entry_name(&mut used, row.get::<&str, &str>("title"), &id),
+                key: row.get::<String, &str>("blob"),
+                size: row.get::<i64, &str>("size") as u64,
+                modified: row.get::<i64, &str>("upload_date"),
+                id,
+            }
+        })
+        .collect()
+}
+
+/// Function that counts a download of each file of an archive that was sent.
+///
+/// The files deleted in the meantime are skipped.
+async fn count_downloads(pool: &DbPool, entries: &[ArchiveEntry]) -> Result<(), RoxideError> {
+    let mut db = pool.acquire().await?;
+    for entry in entries {
+        sqlx::query("UPDATE files SET download_count = download_count+1 WHERE id = $1")
+            .bind(&entry.id)
+            .execute(&mut *db)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Function that streams an archive of *entries* in *format*, built while it is read.
+///
+/// Neither the archive nor its files are buffered: a task writes the archive into a pipe as fast
+/// as the client reads it. An error of the storage interrupts the archive, which the client sees
+/// truncated.
+///
+/// The downloads of the files are counted once the whole archive is sent: an archive interrupted
+/// by an error or by the client does not count.
+pub fn stream(
+    storage: Storage,
+    pool: DbPool,
+    entries: Vec<ArchiveEntry>,
+    format: ArchiveFormat,
+) -> ObjectReader {
+    let (reader, mut writer) = tokio_io::duplex(PIPE_SIZE);
+    rocket::tokio::spawn(async move {
+        let written = match format {
+            ArchiveFormat::Zip => write_zip(&*storage, &entries, &mut writer).await,
+            ArchiveFormat::TarGz => write_tar_gz(&*storage, &entries, &mut writer).await,
+        };
+        match written {
+            Ok(()) => {
+                if let Err(err) = count_downloads(&pool, &entries).await {
+                    eprintln!("Cannot count the downloads of the archive: {:?}", err);
+                }
+            }
+            Err(err) => eprintln!("Cannot write the archive: {:?}", err),
+        }
+    });
+    Box::pin(reader)
+}
+
+/// Convert a timestamp to the MS-DOS time and date of the ZIP headers.
+fn dos_date_time(timestamp: i64) -> (u16, u16) {
+    match Utc.timestamp_opt(timestamp, 0).single() {
+        Some(date) if date.year() >= 1980 => (
+            ((date.hour() << 11) | (date.minute() << 5) | (date.second() / 2)) as u16,
+            (((date.year() as u32 - 1980) << 9) | (date.month() << 5) | date.day()) as u16,
+        ),
+        // 1980-01-01, the earliest date of the format
+        _ => (0, 0x21),
+    }
+}
+
+/// What the central directory needs to know of a written file.
+struct ZipRecord {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    time: u16,
+    date: u16,
+}
+
+/// Write a ZIP archive of *entries* into *out*, the files are stored without compression.
+///
+/// The CRC of a file is only known once it is written, so it follows the file in a data
+/// descriptor. The ZIP64 records are only used for the files, offsets and archives that need them.
+async fn write_zip<W: AsyncWrite + Unpin>(
+    storage: &dyn StorageBackend,
+    entries: &[ArchiveEntry],
+    out: &mut W,
+) -> io::Result<()> {
+    // General purpose flags: data descriptor and UTF-8 names
+    const FLAGS: u16 = 0x0808;
+
+    let mut offset = 0u64;
+    let mut records = Vec::with_capacity(entries.len());
+    let mut buffer = vec![0u8; PIPE_SIZE];
+    for entry in entries {
+        let zip64 = entry.size >= ZIP32_MAX;
+        let (time, date) = dos_date_time(entry.modified);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        header.extend_from_slice(&FLAGS.to_le_bytes());
+        // Stored, without compression
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        // The CRC and the sizes are in the data descriptor
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let sizes = if zip64 { ZIP32_MAX as u32 } else { 0 };
+        header.extend_from_slice(&sizes.to_le_bytes());
+        header.extend_from_slice(&sizes.to_le_bytes());
+        header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 20u16 } else { 0u16 }).to_le_bytes());
+        header.extend_from_slice(entry.name.as_bytes());
+        if zip64 {
+            header.extend_from_slice(&0x0001u16.to_le_bytes());
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&0u64.to_le_bytes());
+            header.extend_from_slice(&0u64.to_le_bytes());
+        }
+        out.write_all(&header).await?;
+
+        let mut reader = storage.stream(&entry.key, None).await?;
+        let mut crc = crc32fast::Hasher::new();
+        let mut written = 0u64;
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            crc.update(&buffer[..read]);
+            out.write_all(&buffer[..read]).await?;
+            written += read as u64;
+        }
+        if written != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} changed while it was archived", entry.key),
+            ));
+        }
+        let crc = crc.finalize();
+
+        let mut descriptor = Vec::new();
+        descriptor.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend_from_slice(&written.to_le_bytes());
+            descriptor.extend_from_slice(&written.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(written as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(written as u32).to_le_bytes());
+        }
+        out.write_all(&descriptor).await?;
+
+        records.push(ZipRecord {
+            name: entry.name.clone(),
+            crc,
+            size: written,
+            offset,
+            time,
+            date,
+        });
+        offset += (header.len() + descriptor.len()) as u64 + written;
+    }
+
+    let directory_offset = offset;
+    let mut directory = Vec::new();
+    for record in &records {
+        let large_size = record.size >= ZIP32_MAX;
+        let large_offset = record.offset >= ZIP32_MAX;
+        let mut extra = Vec::new();
+        if large_size {
+            extra.extend_from_slice(&record.size.to_le_bytes());
+            extra.extend_from_slice(&record.size.to_le_bytes());
+        }
+        if large_offset {
+            extra.extend_from_slice(&record.offset.to_le_bytes());
+        }
+        let zip64 = !extra.is_empty();
+        let version: u16 = if zip64 { 45 } else { 20 };
+        let size = if large_size { ZIP32_MAX } else { record.size } as u32;
+        let local_offset = if large_offset {
+            ZIP32_MAX
+        } else {
+            record.offset
+        } as u32;
+
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&version.to_le_bytes());
+        directory.extend_from_slice(&version.to_le_bytes());
+        directory.extend_from_slice(&FLAGS.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&record.time.to_le_bytes());
+        directory.extend_from_slice(&record.date.to_le_bytes());
+        directory.extend_from_slice(&record.crc.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&(record.name.len() as u16).to_le_bytes());
+        let extra_len = if zip64 { extra.len() + 4 } else { 0 };
+        directory.extend_from_slice(&(extra_len as u16).to_le_bytes());
+        // Comment, disk, internal and external attributes
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u32.to_le_bytes());
+        directory.extend_from_slice(&local_offset.to_le_bytes());
+        directory.extend_from_slice(record.name.as_bytes());
+        if zip64 {
+            directory.extend_from_slice(&0x0001u16.to_le_bytes());
+            directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&extra);
+        }
+    }
+    let directory_size = directory.len() as u64;
+    let count = records.len() as u64;
+
+    let mut end = Vec::new();
+    if count >= 0xFFFF || directory_offset >= ZIP32_MAX || directory_size >= ZIP32_MAX {
+        let end64_offset = directory_offset + directory_size;
+        end.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+        end.extend_from_slice(&44u64.to_le_bytes());
+        end.extend_from_slice(&45u16.to_le_bytes());
+        end.extend_from_slice(&45u16.to_le_bytes());
+        end.extend_from_slice(&0u32.to_le_bytes());
+        end.extend_from_slice(&0u32.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&directory_size.to_le_bytes());
+        end.extend_from_slice(&directory_offset.to_le_bytes());
+
+        end.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+        end.extend_from_slice(&0u32.to_le_bytes());
+        end.extend_from_slice(&end64_offset.to_le_bytes());
+        end.extend_from_slice(&1u32.to_le_bytes());
+    }
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+    end.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+    end.extend_from_slice(&(directory_size.min(ZIP32_MAX) as u32).to_le_bytes());
+    end.extend_from_slice(&(directory_offset.min(ZIP32_MAX) as u32).to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+
+    out.write_all(&directory).await?;
+    out.write_all(&end).await?;
+    out.shutdown().await
+}
+
+/// Gzip compression of a stream, written to *out* as it is compressed.
+struct GzipWriter<'a, W> {
+    encoder: GzEncoder<Vec<u8>>,
+    out: &'a mut W,
+}
+
+impl<'a, W: AsyncWrite + Unpin> GzipWriter<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Self {
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+            out,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.encoder.write_all(data)?;
+        let compressed = std::mem::take(self.encoder.get_mut());
+        self.out.write_all(&compressed).await
+    }
+
+    async fn finish(self) -> io::Result<()> {
+        let compressed = self.encoder.finish()?;
+        self.out.write_all(&compressed).await?;
+        self.out.shutdown().await
+    }
+}
+
+/// Write *value* in octal in a field of a tar header, ended by a NUL.
+fn tar_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// Build a ustar header block of the *kind* of entry.
+fn tar_block(name: &str, size: u64, modified: i64, kind: u8) -> [u8; TAR_BLOCK] {
+    let mut block = [0u8; TAR_BLOCK];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    tar_octal(&mut block[100..108], 0o644);
+    tar_octal(&mut block[108..116], 0);
+    tar_octal(&mut block[116..124], 0);
+    tar_octal(&mut block[124..136], size);
+    tar_octal(&mut block[136..148], modified.max(0) as u64);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    block[148..156].copy_from_slice(b"        ");
+    let checksum = block.iter().map(|&byte| byte as u64).sum::<u64>();
+    tar_octal(&mut block[148..155], checksum);
+    block
+}
+
+/// Build a record of a pax header, `<length> <key>=<value>\n`, the length counting itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let content = format!(" {}={}\n", key, value);
+    let mut length = content.len() + 1;
+    while length != content.len() + length.to_string().len() {
+        length = content.len() + length.to_string().len();
+    }
+    format!("{}{}", length, content)
+}
+
+/// Build the header of a file in a tar archive.
+///
+/// The ustar header only holds short ASCII names and sizes below 8 GiB, a pax header carries the
+/// other names and sizes.
+fn tar_header(entry: &ArchiveEntry) -> Vec<u8> {
+    let mut header = Vec::new();
+    let large = entry.size > TAR_MAX_SIZE;
+    let mut name = entry
+        .name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
+    if large || name != entry.name || name.len() > 100 {
+        let mut records = pax_record("path", &entry.name);
+        if large {
+            records.push_str(&pax_record("size", &entry.size.to_string()));
+        }
+        header.extend_from_slice(&tar_block(
+            "PaxHeader",
+            records.len() as u64,
+            entry.modified,
+            b'x',
+        ));
+        header.extend_from_slice(records.as_bytes());
+        header.resize(header.len() + tar_padding(records.len() as u64), 0);
+        name.truncate(100);
+    }
+    let size = if large { 0 } else { entry.size };
+    header.extend_from_slice(&tar_block(&name, size, entry.modified, b'0'));
+    header
+}
+
+/// Number of bytes that fill the last block of *size* bytes.
+fn tar_padding(size: u64) -> usize {
+    (TAR_BLOCK - (size % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+}
+
+/// Write a tar archive of *entries* compressed with gzip into *out*.
+async fn write_tar_gz<W: AsyncWrite + Unpin>(
+    storage: &dyn StorageBackend,
+    entries: &[ArchiveEntry],
+    out: &mut W,
+) -> io::Result<()> {
+    let mut gzip = GzipWriter::new(out);
+    let mut buffer = vec![0u8; PIPE_SIZE];
+    for entry in entries {
+        gzip.write_all(&tar_header(entry)).await?;
+
+        let mut reader = storage.stream(&entry.key, None).await?;
+        let mut written = 0u64;
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            gzip.write_all(&buffer[..read]).await?;
+            written += read as u64;
+        }
+        if written != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} changed while it was archived", entry.key),
+            ));
+        }
+        gzip.write_all(&[0; TAR_BLOCK][..tar_padding(written)])
+            .await?;
+    }
+    // The end of the archive is two empty blocks
+    gzip.write_all(&[0; 2 * TAR_BLOCK]).await?;
+    gzip.finish().await
+}
+
+/// Response with an archive, downloaded as *name*.
+pub struct Archive {
+    name: String,
+    content_type: ContentType,
+    reader: ObjectReader,
+}
+
+impl Archive {
+    pub fn new(name: &str, format: ArchiveFormat, reader: ObjectReader) -> Self {
+        Self {
+            name: format!("{}.{}", name, format.extension()),
+            content_type: format.content_type(),
+            reader,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Archive {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        // Only the printable ASCII characters are safe in a quoted parameter
+        let name = self
+            .name
+            .chars()
+            .map(|c| match c {
+                '"' | '\\' => '_',
+                c if c.is_ascii_graphic() || c == ' ' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        Response::build()
+            .header(self.content_type)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", name),
+            )
+            .streamed_body(self.reader)
+            .ok()
+    }
+}
+
+/// Function that gathers the files *ids* to put in an archive.
+///
+/// Each file is checked like get: the request fails if one of them cannot be downloaded. A file
+/// whose number of downloads is limited cannot be archived, its last download would delete it.
+async fn file_entries(
+    storage: &Storage,
+    db: &mut AnyConnection,
+    ids: &[String],
+    access: &FileAccess<'_>,
+) -> Result<Vec<ArchiveEntry>, RoxideError> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for id in ids.iter().filter(|id| seen.insert(id.as_str())) {
+        let id = FileId::from_param(id).map_err(|_| RoxideError::NotFound)?;
+        let file = open_file(storage, db, &id, access).await?;
+        if file.max_downloads.is_some() {
+            return Err(RoxideError::InvalidArchive);
+        }
+        files.push((id, file));
+    }
+
+    let mut used = HashSet::new();
+    let entries = files
+        .into_iter()
+        .map(|(id, file)| ArchiveEntry {
+            name: entry_name(&mut used, &file.title, id.get_id()),
+            id: id.get_id().to_string(),
+            key: file.digest,
+            size: file.size as u64,
+            modified: file.upload_date,
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Function that gathers all the files uploaded with *token* to put in an archive.
+///
+/// The files protected by a password and the files whose number of downloads is limited are left
+/// out, like in a collection.
+async fn token_entries(
+    db: &mut AnyConnection,
+    token: &str,
+) -> Result<Vec<ArchiveEntry>, RoxideError> {
+    let rows = sqlx::query("SELECT id, title, blob, size, upload_date FROM files WHERE token_used = $1 AND expiration_date > $2 AND password_hash IS NULL AND max_downloads IS NULL ORDER BY upload_date, id")
+        .bind(token)
+        .bind(Utc::now().timestamp())
+        .fetch_all(&mut *db)
+        .await?;
+    Ok(row_entries(&rows.iter().collect::<Vec<_>>()))
+}
+
+/// Selection of the files of an archive, and its format.
+#[derive(Debug, FromForm)]
+struct ArchiveQuery {
+    id: Vec<String>,
+    collection: Option<String>,
+    mine: Option<bool>,
+    format: Option<ArchiveFormat>,
+}
+
+/// Function that downloads several files in an archive, streamed while it is built
+/// (`?format=<zip|tar.gz>`, ZIP by default).
+///
+/// The files are selected with one of:
+/// - `id`, repeated for each file (`?id=<id>&id=<id>`), up to `MAX_ARCHIVE_FILES` files. Each
+///   file is checked like get, the request fails if one of them cannot be downloaded.
+/// - `collection`, the files of a collection, like `/c/<id>/zip`.
+/// - `mine=true`, all the files uploaded with the token sent as a bearer token, except the files
+///   protected by a password and the files whose number of downloads is limited.
+///
+/// The files are named after their titles, and each file of the archive counts as a download
+/// once the whole archive is sent.
+#[get("/archive?<query..>")]
+async fn archive(
+    storage: &State<Storage>,
+    pool: &Canard,
+    mut db: Connection<Canard>,
+    query: ArchiveQuery,
+    access: FileAccess<'_>,
+) -> Result<Archive, RoxideError> {
+    if query.id.len() > MAX_ARCHIVE_FILES {
+        return Err(RoxideError::InvalidArchive);
+    }
+    let selection = (
+        query.id.is_empty(),
+        query.collection,
+        query.mine.unwrap_or(false),
+    );
+    let (name, entries) = match selection {
+        (false, None, false) => {
+            let entries = file_entries(storage, &mut db, &query.id, &access).await?;
+            ("files".to_string(), entries)
+        }
+        (true, Some(collection), false) => {
+            let collection = FileId::from_param(&collection).map_err(|_| RoxideError::NotFound)?;
+            collection_entries(&mut db, &collection, &access).await?
+        }
+        (true, None, true) => {
+            let token = access.token().ok_or(RoxideError::InvalidToken)?;
+            ("files".to_string(), token_entries(&mut db, token).await?)
+        }
+        _ => return Err(RoxideError::InvalidArchive),
+    };
+
+    let format = query.format.unwrap_or(ArchiveFormat::Zip);
+    let reader = stream(
+        Storage::clone(storage),
+        DbPool::clone(pool),
+        entries,
+        format,
+    );
+    Ok(Archive::new(&name, format, reader))
+}
+
+/// Function that mounts the route that downloads several files in an archive.
+/// - archive (to download files in an archive).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Archive stage", |rocket| async {
+        rocket.mount("/", routes![archive])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::storage::LocalStorage;
+
+    /// Store *content* under *key* in *dir*, and build its entry.
+    fn entry(dir: &Path, id: &str, name: &str, key: &str, content: &[u8]) -> ArchiveEntry {
+        std::fs::write(dir.join(key), content).unwrap();
+        ArchiveEntry {
+            id: id.to_string(),
+            name: name.to_string(),
+            key: key.to_string(),
+            size: content.len() as u64,
+            modified: 1_700_000_000,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn zip_archive_can_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_str().unwrap());
+        let entries = vec![
+            entry(dir.path(), "a", "notes.txt", "k1", b"some notes"),
+            entry(dir.path(), "b", "données é.bin", "k2", &[0, 1, 2, 255]),
+        ];
+        let mut out = Vec::new();
+        write_zip(&storage, &entries, &mut out).await.unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(out)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut content = Vec::new();
+        let mut file = zip.by_name("notes.txt").unwrap();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"some notes");
+        let modified = file.last_modified();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2023, 11, 14)
+        );
+        drop(file);
+        content.clear();
+        zip.by_name("données é.bin")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, [0, 1, 2, 255]);
+    }
+
+    #[rocket::async_test]
+    async fn zip_of_many_files_uses_zip64_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_str().unwrap());
+        std::fs::write(dir.path().join("empty"), b"").unwrap();
+        let entries = (0..0x10000)
+            .map(|n| ArchiveEntry {
+                id: n.to_string(),
+                name: format!("{}.txt", n),
+                key: "empty".to_string(),
+                size: 0,
+                modified: 0,
+            })
+            .collect::<Vec<_>>();
+        let mut out = Vec::new();
+        write_zip(&storage, &entries, &mut out).await.unwrap();
+
+        assert!(out
+            .windows(4)
+            .any(|window| window == 0x0606_4b50u32.to_le_bytes()));
+        let zip = zip::ZipArchive::new(Cursor::new(out)).unwrap();
+        assert_eq!(zip.len(), 0x10000);
+    }
+
+    #[rocket::async_test]
+    async fn tar_gz_archive_can_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_str().unwrap());
+        let long_name = format!("{}.txt", "n".repeat(120));
+        let entries = vec![
+            entry(dir.path(), "a", "notes.txt", "k1", b"some notes"),
+            entry(dir.path(), "b", "données.bin", "k2", &[7; 1000]),
+            entry(dir.path(), "c", &long_name, "k3", b""),
+        ];
+        let mut out = Vec::new();
+        write_tar_gz(&storage, &entries, &mut out).await.unwrap();
+
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(Cursor::new(out)));
+        let mut read = Vec::new();
+        for file in tar.entries().unwrap() {
+            let mut file = file.unwrap();
+            let name = file.path().unwrap().to_str().unwrap().to_string();
+            assert_eq!(file.header().mtime().unwrap(), 1_700_000_000);
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).unwrap();
+            read.push((name, content));
+        }
+        assert_eq!(
+            read,
+            vec![
+                ("notes.txt".to_string(), b"some notes".to_vec()),
+                ("données.bin".to_string(), vec![7; 1000]),
+                (long_name, Vec::new()),
+            ]
+        );
+    }
+
+    /// Read the number of downloads of the file *id*.
+    async fn download_count(pool: &DbPool, id: &str) -> i64 {
+        sqlx::query("SELECT download_count FROM files WHERE id = $1")
+            .bind(id)
+            .fetch_one(&**pool)
+            .await
+            .unwrap()
+            .get("download_count")
+    }
+
+    #[rocket::async_test]
+    async fn downloads_are_counted_once_the_archive_is_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        sqlx::query("INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, visibility, title, blob, require_signature) VALUES ('sent', $1, 0, 'token', 'text/plain', 4, 0, 'public', '', 'k1', FALSE), ('interrupted', $1, 0, 'token', 'text/plain', 4, 0, 'public', '', 'k2', FALSE)")
+            .bind(Utc::now().timestamp() + 3600)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        let storage: Storage = Arc::new(LocalStorage::new(dir.path().to_str().unwrap()));
+
+        let sent = vec![entry(dir.path(), "sent", "sent", "k1", b"data")];
+        let mut reader = stream(storage.clone(), pool.clone(), sent, ArchiveFormat::Zip);
+        let mut archive = Vec::new();
+        reader.read_to_end(&mut archive).await.unwrap();
+
+        // The reader is dropped before the archive is written
+        let interrupted = vec![entry(
+            dir.path(),
+            "interrupted",
+            "interrupted",
+            "k2",
+            &vec![0; 4 * PIPE_SIZE],
+        )];
+        drop(stream(
+            storage,
+            pool.clone(),
+            interrupted,
+            ArchiveFormat::Zip,
+        ));
+
+        for _ in 0..50 {
+            if download_count(&pool, "sent").await == 1 {
+                break;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(download_count(&pool, "sent").await, 1);
+        assert_eq!(download_count(&pool, "interrupted").await, 0);
+    }
+}
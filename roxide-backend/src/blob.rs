@@ -0,0 +1,79 @@
+use std::io;
+
+use sqlx::{AnyConnection, Row};
+
+use crate::storage::Storage;
+use crate::RoxideError;
+
+/// Register a new reference to the blob *digest*, creating the blob if it does not exist.
+///
+/// Return true if the blob has just been created, in which case its content has to be put in
+/// the storage.
+pub async fn acquire(db: &mut AnyConnection, digest: &str, size: i64) -> Result<bool, RoxideError> {
+    let row = sqlx::query(
+        "INSERT INTO blobs (digest, size, ref_count) VALUES ($1, $2, 1) ON CONFLICT (digest) DO UPDATE SET ref_count = blobs.ref_count + 1 RETURNING ref_count",
+    )
+    .bind(digest)
+    .bind(size)
+    .fetch_one(&mut *db)
+    .await?;
+    Ok(row.get::<i64, &str>("ref_count") == 1)
+}
+
+/// Release a reference to the blob *digest*.
+///
+/// When the last reference is released, the blob is deleted from the database and from the
+/// storage, and true is returned. Content already missing from the storage is not an error.
+pub async fn release(
+    db: &mut AnyConnection,
+    storage: &Storage,
+    digest: &str,
+) -> Result<bool, RoxideError> {
+    sqlx::query("UPDATE blobs SET ref_count = ref_count - 1 WHERE digest = $1")
+        .bind(digest)
+        .execute(&mut *db)
+        .await?;
+
+    // Only the caller that removes the row deletes the content, a concurrent upload of the same
+    // content will then create a new blob.
+    let deleted = sqlx::query("DELETE FROM blobs WHERE digest = $1 AND ref_count <= 0")
+        .bind(digest)
+        .execute(&mut *db)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(false);
+    }
+    match storage.delete(digest).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(true),
+    }
+}
+
+/// Delete the blobs that no file references anymore, from the database and from the storage.
+///
+/// Return the digests of the deleted blobs. Content already missing from the storage is not an
+/// error.
+pub async fn delete_unreferenced(
+    db: &mut AnyConnection,
+    storage: &Storage,
+) -> Result<Vec<String>, RoxideError> {
+    let digests = sqlx::query("SELECT digest FROM blobs WHERE ref_count <= 0")
+        .fetch_all(&mut *db)
+        .await?;
+    let mut deleted = Vec::new();
+    for digest in digests.iter().map(|row| row.get::<&str, &str>("digest")) {
+        // A file may have referenced it again in the meantime
+        let removed = sqlx::query("DELETE FROM blobs WHERE digest = $1 AND ref_count <= 0")
+            .bind(digest)
+            .execute(&mut *db)
+            .await?;
+        if removed.rows_affected() == 0 {
+            continue;
+        }
+        match storage.delete(digest).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => deleted.push(digest.to_string()),
+        }
+    }
+    Ok(deleted)
+}
@@ -0,0 +1,489 @@
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::response::content::RawHtml;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+use rocket_db_pools::Connection;
+
+use sqlx::any::AnyRow;
+use sqlx::{AnyConnection, Row};
+
+use crate::access::{FileAccess, Visibility};
+use crate::archive::{self, row_entries, Archive, ArchiveEntry, ArchiveFormat};
+use crate::db::DbPool;
+use crate::html::{escape, format_date, format_size, page, PrefersHtml};
+use crate::storage::Storage;
+use crate::user::{add_tags, expiration_date, new_file_id, FileData};
+use crate::{is_token_valid, AppConfig, Canard, FileId, RoxideError};
+
+//Structure use to receive the form that creates a collection.
+#[derive(Debug, FromForm)]
+struct NewCollection {
+    title: Option<String>,
+    duration: Option<i64>,
+    visibility: Option<String>,
+}
+
+/// Function that checks that files can be added to the collection *id* by *token*: the
+/// collection was created by the token and has not expired.
+///
+/// Return the expiration date of the collection, the files added to it must not outlive it.
+pub async fn check_collection(
+    db: &mut AnyConnection,
+    id: &str,
+    token: &str,
+    now: i64,
+) -> Result<i64, RoxideError> {
+    let row = sqlx::query(
+        "SELECT expiration_date FROM collections WHERE id = $1 AND token_used = $2 AND expiration_date > $3",
+    )
+    .bind(id)
+    .bind(token)
+    .bind(now)
+    .fetch_optional(&mut *db)
+    .await?
+    .ok_or(RoxideError::InvalidCollection)?;
+    Ok(row.get::<i64, &str>("expiration_date"))
+}
+
+/// Function that deletes the collections that expired, their files expired with them.
+pub async fn clean_collections(db: &mut AnyConnection) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM collections WHERE expiration_date <= $1")
+        .bind(Utc::now().timestamp())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that creates an empty collection, and returns its id.
+///
+/// The files are added with the field `collection` of `/post/<token>`, by the same token. The
+/// duration and the visibility of the collection follow the rules of the files.
+#[post("/collection/<token>", data = "<form>")]
+async fn create(
+    app_config: &State<AppConfig>,
+    mut db: Connection<Canard>,
+    token: &str,
+    form: Form<NewCollection>,
+) -> Result<String, RoxideError> {
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    let now = Utc::now().timestamp();
+    let expiration = expiration_date(app_config, now, form.duration)?;
+    let visibility = match form.visibility.as_deref() {
+        Some(visibility) => Visibility::parse(visibility)?,
+        None => Visibility::Public,
+    };
+
+    let id = new_file_id(app_config, &mut db).await?;
+    sqlx::query(
+        "INSERT INTO collections (id, title, token_used, creation_date, expiration_date, visibility) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(id.get_id())
+    .bind(form.title.as_deref().unwrap_or(""))
+    .bind(token)
+    .bind(now)
+    .bind(expiration)
+    .bind(visibility.as_str())
+    .execute(&mut *db)
+    .await?;
+    Ok(id.get_id().to_string())
+}
+
+/// A collection, with the files the requester can see.
+struct Collection {
+    title: String,
+    creation_date: i64,
+    expiration_date: i64,
+    visibility: Visibility,
+    files: Vec<AnyRow>,
+}
+
+impl Collection {
+    /// Title of the collection, or its id if it has none.
+    fn name<'a>(&'a self, id: &'a FileId) -> &'a str {
+        if self.title.is_empty() {
+            id.get_id()
+        } else {
+            &self.title
+        }
+    }
+}
+
+/// Function that opens the collection *id* for the requester.
+///
+/// The collection follows the rules of the visibility of the files. It only lists the files that
+/// have not expired, and hides from the other requesters the files of its uploader that are
+/// private or that need a signed link.
+async fn open(
+    db: &mut AnyConnection,
+    id: &FileId,
+    access: &FileAccess<'_>,
+) -> Result<Collection, RoxideError> {
+    let row = sqlx::query(
+        "SELECT title, token_used, creation_date, expiration_date, visibility FROM collections WHERE id = $1",
+    )
+    .bind(id.get_id())
+    .fetch_one(&mut *db)
+    .await?;
+    let now = Utc::now().timestamp();
+    let expiration_date = row.get::<i64, &str>("expiration_date");
+    if expiration_date <= now {
+        return Err(RoxideError::Expired);
+    }
+    let visibility = Visibility::from_column(row.get::<&str, &str>("visibility"));
+    let token_used = row.get::<&str, &str>("token_used");
+    access.check(db, id, visibility, token_used, false).await?;
+
+    let files = sqlx::query("SELECT id, upload_date, content_type, download_count, size, title, blob, max_downloads, password_hash IS NOT NULL AS protected FROM files WHERE collection_id = $1 AND expiration_date > $2 AND (max_downloads IS NULL OR download_count < max_downloads) AND (token_used = $3 OR (visibility <> 'private' AND NOT require_signature)) ORDER BY upload_date, id")
+        .bind(id.get_id())
+        .bind(now)
+        .bind(access.token())
+        .fetch_all(&mut *db)
+        .await?;
+    Ok(Collection {
+        title: row.get::<String, &str>("title"),
+        creation_date: row.get::<i64, &str>("creation_date"),
+        expiration_date,
+        visibility,
+        files,
+    })
+}
+
+/// A collection and its files, as listed by `/c/<id>`.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CollectionData {
+    id: String,
+    title: String,
+    creation_date: i64,
+    expiration_date: i64,
+    visibility: Visibility,
+    files: Vec<FileData>,
+}
+
+/// Listing of a collection, a page for the browsers and JSON for the other clients.
+#[derive(Responder)]
+enum Listing {
+    Html(RawHtml<String>),
+    Json(Json<CollectionData>),
+}
+
+/// Build the page that lists the files of a collection.
+fn listing_page(id: &FileId, collection: &Collection) -> RawHtml<String> {
+    let count = match collection.files.len() {
+        1 => "1 file".to_string(),
+        count => format!("{} files", count),
+    };
+    let until = match format_date(collection.expiration_date) {
+        date if date.is_empty() => String::new(),
+        date => format!(", available until {}", date),
+    };
+    let mut body = format!(
+        "<h1>{}</h1>\n<p>{}{}.</p>\n<p><a href=\"/c/{}/zip\">Download all as ZIP</a></p>\n<ul>\n",
+        escape(collection.name(id)),
+        count,
+        until,
+        id.get_id()
+    );
+    for file in &collection.files {
+        let file_id = file.get::<&str, &str>("id");
+        let title = match file.get::<&str, &str>("title") {
+            "" => file_id,
+            title => title,
+        };
+        body.push_str(&format!(
+            "<li><a href=\"/get/{}\">{}</a> ({}, {})</li>\n",
+            file_id,
+            escape(title),
+            escape(file.get::<&str, &str>("content_type")),
+            format_size(file.get::<i64, &str>("size"))
+        ));
+    }
+    body.push_str("</ul>\n");
+    page(collection.name(id), "", &body)
+}
+
+/// Function that lists the files of a collection, as a page for the browsers (that prefer HTML)
+/// and as JSON otherwise.
+///
+/// A private collection is only listed to its uploader, sent as a bearer token. An expired
+/// collection gets 410.
+#[get("/c/<id>")]
+async fn collection(
+    mut db: Connection<Canard>,
+    id: FileId,
+    access: FileAccess<'_>,
+    html: PrefersHtml,
+) -> Result<Listing, RoxideError> {
+    let collection = open(&mut db, &id, &access).await?;
+    if html.0 {
+        return Ok(Listing::Html(listing_page(&id, &collection)));
+    }
+    let mut files = collection
+        .files
+        .iter()
+        .map(FileData::from_row)
+        .collect::<Vec<_>>();
+    add_tags(&mut db, &mut files).await?;
+    Ok(Listing::Json(Json(CollectionData {
+        id: id.get_id().to_string(),
+        files,
+        title: collection.title,
+        creation_date: collection.creation_date,
+        expiration_date: collection.expiration_date,
+        visibility: collection.visibility,
+    })))
+}
+
+/// Function that gathers the files of the collection *id* to put in an archive. Return the name of the archive with its files.
+///
+/// The archive holds the files listed by `/c/<id>`, except the files protected by a password and
+/// the files whose number of downloads is limited: they have to be downloaded one by one.
+pub async fn collection_entries(
+    db: &mut AnyConnection,
+    id: &FileId,
+    access: &FileAccess<'_>,
+) -> Result<(String, Vec<ArchiveEntry>), RoxideError> {
+    let collection = open(db, id, access).await?;
+    let files = collection
+        .files
+        .iter()
+        .filter(|file| {
+            !file.get::<bool, &str>("protected")
+                && file.get::<Option<i64>, &str>("max_downloads").is_none()
+        })
+        .collect::<Vec<_>>();
+    Ok((collection.name(id).to_string(), row_entries(&files)))
+}
+
+/// Function that downloads the files of a collection in a ZIP archive, streamed while it is built
+/// (see `/archive`).
+#[get("/c/<id>/zip")]
+async fn zip(
+    storage: &State<Storage>,
+    pool: &Canard,
+    mut db: Connection<Canard>,
+    id: FileId,
+    access: FileAccess<'_>,
+) -> Result<Archive, RoxideError> {
+    let (name, entries) = collection_entries(&mut db, &id, &access).await?;
+    let reader = archive::stream(
+        Storage::clone(storage),
+        DbPool::clone(pool),
+        entries,
+        ArchiveFormat::Zip,
+    );
+    Ok(Archive::new(&name, ArchiveFormat::Zip, reader))
+}
+
+/// Function that mounts the routes of the collections.
+/// - create (to create a collection).
+/// - collection (to list the files of a collection).
+/// - zip (to download the files of a collection in an archive).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Collection stage", |rocket| async {
+        rocket.mount("/", routes![create, collection, zip])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use rocket::http::{Accept, ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::Value;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::test_client;
+    use crate::user::test_file;
+
+    /// Create a collection on behalf of `token` with the fields *form*.
+    async fn create_collection(client: &Client, form: &str) -> (Status, String) {
+        let response = client
+            .post("/collection/token")
+            .header(ContentType::Form)
+            .body(form)
+            .dispatch()
+            .await;
+        (response.status(), response.into_string().await.unwrap())
+    }
+
+    /// List the collection *id* as JSON, on behalf of *token* if any.
+    async fn listing(client: &Client, id: &str, token: Option<&str>) -> (Status, Value) {
+        let mut request = client.get(format!("/c/{}", id)).header(Accept::JSON);
+        if let Some(token) = token {
+            request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+        }
+        let response = request.dispatch().await;
+        (
+            response.status(),
+            response.into_json::<Value>().await.unwrap_or_default(),
+        )
+    }
+
+    fn ids(collection: &Value) -> Vec<&str> {
+        collection["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["id"].as_str().unwrap())
+            .collect()
+    }
+
+    /// Create the database in *dir*, and build a client of *routes*.
+    async fn client(dir: &std::path::Path, routes: Vec<rocket::Route>) -> (DbPool, Client) {
+        let pool = test_pool(dir).await;
+        (pool, test_client(dir, routes).await)
+    }
+
+    /// Register in the collection *id* the files *a*, *b* (whose content is `hello`), *private*,
+    /// *limited*, *protected*, *expired* and *downloaded*, and the file *outside* out of it.
+    async fn add_files(dir: &std::path::Path, pool: &DbPool, id: &str) {
+        let mut db = pool.acquire().await.unwrap();
+        for (file, changes) in [
+            ("a", "upload_date = 1"),
+            ("b", "upload_date = 2"),
+            ("private", "upload_date = 3, visibility = 'private'"),
+            ("limited", "upload_date = 4, max_downloads = 3"),
+            ("protected", "upload_date = 5, password_hash = 'hash'"),
+            ("expired", "expiration_date = 1"),
+            ("downloaded", "max_downloads = 1, download_count = 1"),
+        ] {
+            test_file(
+                &mut db,
+                file,
+                &format!("{}, size = 5, collection_id = '{}'", changes, id),
+            )
+            .await;
+        }
+        test_file(&mut db, "outside", "size = 5").await;
+        std::fs::write(dir.join("upload").join("digest"), b"hello").unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn collections_are_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, client) = client(dir.path(), routes![create, collection]).await;
+
+        let (status, id) = create_collection(&client, "title=Holidays").await;
+        assert_eq!(status, Status::Ok);
+        let (status, collection) = listing(&client, &id, None).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(collection["id"], id);
+        assert_eq!(collection["title"], "Holidays");
+        assert_eq!(collection["visibility"], "public");
+        assert_eq!(collection["files"], Value::Array(Vec::new()));
+
+        let (status, _) = create_collection(&client, "visibility=hidden").await;
+        assert_eq!(status, Status::BadRequest);
+        let (status, _) = create_collection(&client, "duration=-5").await;
+        assert_eq!(status, Status::BadRequest);
+        let (status, _) = listing(&client, "absent", None).await;
+        assert_eq!(status, Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn collections_list_the_available_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, client) = client(dir.path(), routes![create, collection]).await;
+        let (_, id) = create_collection(&client, "title=Holidays").await;
+        add_files(dir.path(), &pool, &id).await;
+
+        let (_, collection) = listing(&client, &id, None).await;
+        assert_eq!(ids(&collection), ["a", "b", "limited", "protected"]);
+        assert_eq!(collection["files"][3]["protected"], true);
+        let (_, collection) = listing(&client, &id, Some("token")).await;
+        assert_eq!(
+            ids(&collection),
+            ["a", "b", "private", "limited", "protected"]
+        );
+
+        let response = client
+            .get(format!("/c/{}", id))
+            .header(Accept::HTML)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let page = response.into_string().await.unwrap();
+        assert!(page.contains("<h1>Holidays</h1>"));
+        assert!(page.contains("4 files"));
+        assert!(page.contains("<a href=\"/get/a\">a</a>"));
+        assert!(!page.contains("/get/private"));
+        assert!(page.contains(&format!("/c/{}/zip", id)));
+    }
+
+    #[rocket::async_test]
+    async fn private_collections_are_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, client) = client(dir.path(), routes![create, collection, zip]).await;
+        let (_, id) = create_collection(&client, "visibility=private").await;
+
+        let (status, _) = listing(&client, &id, None).await;
+        assert_eq!(status, Status::NotFound);
+        let (status, _) = listing(&client, &id, Some("other")).await;
+        assert_eq!(status, Status::NotFound);
+        let (status, collection) = listing(&client, &id, Some("token")).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(collection["visibility"], "private");
+        let response = client.get(format!("/c/{}/zip", id)).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn collections_are_downloaded_as_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, client) = client(dir.path(), routes![create, zip]).await;
+        let (_, id) = create_collection(&client, "title=Holidays").await;
+        add_files(dir.path(), &pool, &id).await;
+
+        let response = client.get(format!("/c/{}/zip", id)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let disposition = response.headers().get_one("Content-Disposition").unwrap();
+        assert!(disposition.contains("Holidays.zip"));
+        let body = response.into_bytes().await.unwrap();
+
+        // Without the files protected by a password or limited in downloads
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(body)).unwrap();
+        let mut names = archive.file_names().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["a", "b"]);
+        let mut content = Vec::new();
+        archive
+            .by_name("b")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"hello");
+    }
+
+    #[rocket::async_test]
+    async fn expired_collections_are_cleaned() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pool, client) = client(dir.path(), routes![create, collection]).await;
+        let (_, expired) = create_collection(&client, "").await;
+        let (_, kept) = create_collection(&client, "").await;
+        let mut db = pool.acquire().await.unwrap();
+        sqlx::query("UPDATE collections SET expiration_date = 1 WHERE id = $1")
+            .bind(&expired)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+
+        let (status, _) = listing(&client, &expired, None).await;
+        assert_eq!(status, Status::Gone);
+        clean_collections(&mut db).await.unwrap();
+        let (status, _) = listing(&client, &expired, None).await;
+        assert_eq!(status, Status::NotFound);
+        let (status, _) = listing(&client, &kept, None).await;
+        assert_eq!(status, Status::Ok);
+    }
+}
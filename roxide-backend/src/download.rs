@@ -0,0 +1,351 @@
+use std::io;
+use std::ops::Range;
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+
+use crate::storage::{ObjectReader, StorageBackend};
+
+/// Format of the dates in HTTP headers.
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Format a timestamp as an HTTP date.
+fn format_http_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map_or_else(String::new, |date| date.format(HTTP_DATE).to_string())
+}
+
+/// Parse an HTTP date into a timestamp.
+fn parse_http_date(date: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(date.trim(), HTTP_DATE)
+        .ok()
+        .map(|date| Utc.from_utc_datetime(&date).timestamp())
+}
+
+/// Validators of a stored file, used to answer conditional requests.
+///
+/// The entity tag is the digest of the content and the modification date is the upload date.
+pub struct Validators {
+    etag: String,
+    last_modified: i64,
+}
+
+impl Validators {
+    pub fn new(digest: &str, upload_date: i64) -> Self {
+        Self {
+            etag: format!("\"{}\"", digest),
+            last_modified: upload_date,
+        }
+    }
+
+    /// Indicate if an entity tag of a request designates the file, ignoring the weak marker.
+    fn matches(&self, etag: &str) -> bool {
+        etag == "*" || etag.trim_start_matches("W/") == self.etag
+    }
+}
+
+/// Part of a file requested by the client.
+#[derive(Debug, PartialEq)]
+enum RequestedRange {
+    /// The whole file.
+    Full,
+    /// The bytes of the range.
+    Partial(Range<u64>),
+    /// A range that does not overlap the file.
+    NotSatisfiable,
+}
+
+/// Headers that make a download partial or conditional.
+#[derive(Default)]
+pub struct DownloadHeaders<'r> {
+    range: Option<&'r str>,
+    if_range: Option<&'r str>,
+    if_none_match: Option<&'r str>,
+    if_modified_since: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DownloadHeaders<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let headers = req.headers();
+        request::Outcome::Success(DownloadHeaders {
+            range: headers.get_one("Range"),
+            if_range: headers.get_one("If-Range"),
+            if_none_match: headers.get_one("If-None-Match"),
+            if_modified_since: headers.get_one("If-Modified-Since"),
+        })
+    }
+}
+
+impl DownloadHeaders<'_> {
+    /// Indicate if the copy cached by the client is still valid.
+    ///
+    /// `If-Modified-Since` is only considered without `If-None-Match`.
+    pub fn is_not_modified(&self, validators: &Validators) -> bool {
+        if let Some(if_none_match) = self.if_none_match {
+            return if_none_match
+                .split(',')
+                .any(|etag| validators.matches(etag.trim()));
+        }
+        self.if_modified_since
+            .and_then(parse_http_date)
+            .map_or(false, |since| validators.last_modified <= since)
+    }
+
+    /// Compute the part of a file of *size* bytes requested by the client.
+    ///
+    /// The whole file is sent if `If-Range` does not designate the file anymore, or if the range
+    /// is invalid or made of several ranges.
+    fn range(&self, validators: &Validators, size: u64) -> RequestedRange {
+        let range = match self.range {
+            Some(range) => range,
+            None => return RequestedRange::Full,
+        };
+        if let Some(if_range) = self.if_range {
+            let if_range = if_range.trim();
+            let valid = if if_range.starts_with('"') {
+                if_range == validators.etag
+            } else {
+                parse_http_date(if_range) == Some(validators.last_modified)
+            };
+            if !valid {
+                return RequestedRange::Full;
+            }
+        }
+        parse_range(range, size)
+    }
+}
+
+/// Parse a `Range` header for a file of *size* bytes.
+fn parse_range(header: &str, size: u64) -> RequestedRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return RequestedRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return RequestedRange::Full,
+    };
+
+    let range = if start.is_empty() {
+        // Suffix range, the last bytes of the file
+        match end.parse::<u64>() {
+            Ok(length) => size.saturating_sub(length)..size,
+            Err(_) => return RequestedRange::Full,
+        }
+    } else {
+        let start = match start.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return RequestedRange::Full,
+        };
+        let end = if end.is_empty() {
+            size
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(size),
+                _ => return RequestedRange::Full,
+            }
+        };
+        start..end
+    };
+
+    if range.start >= range.end {
+        RequestedRange::NotSatisfiable
+    } else {
+        RequestedRange::Partial(range)
+    }
+}
+
+/// Response to a download, the content of a stored file or a notice for a conditional or
+/// partial request.
+pub enum Download {
+    /// The whole file, or the part of the file in *range*.
+    Content {
+        validators: Validators,
+        content_type: ContentType,
+        size: u64,
+        range: Option<Range<u64>>,
+        reader: ObjectReader,
+    },
+    /// The copy cached by the client is still valid.
+    NotModified { validators: Validators },
+    /// The requested range does not overlap the file.
+    NotSatisfiable { validators: Validators, size: u64 },
+}
+
+impl Download {
+    /// Send the object *key* of *storage*, or the part of it requested by the client.
+    pub async fn from_storage(
+        storage: &dyn StorageBackend,
+        key: &str,
+        content_type: ContentType,
+        validators: Validators,
+        headers: &DownloadHeaders<'_>,
+    ) -> io::Result<Self> {
+        let size = storage.stat(key).await?.size;
+        let range = match headers.range(&validators, size) {
+            RequestedRange::Full => None,
+            RequestedRange::Partial(range) => Some(range),
+            RequestedRange::NotSatisfiable => {
+                return Ok(Download::NotSatisfiable { validators, size })
+            }
+        };
+        let reader = storage.stream(key, range.clone()).await?;
+        Ok(Download::Content {
+            validators,
+            content_type,
+            size,
+            range,
+            reader,
+        })
+    }
+
+    /// Indicate if the beginning of the file is sent: the whole file or a range starting at the
+    /// first byte.
+    pub fn is_from_start(&self) -> bool {
+        match self {
+            Download::Content { range, .. } => {
+                range.as_ref().map_or(true, |range| range.start == 0)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes");
+        match self {
+            Download::Content {
+                validators,
+                content_type,
+                size,
+                range,
+                reader,
+            } => {
+                add_validators(&mut response, validators);
+                response.header(content_type);
+                match range {
+                    Some(range) => response
+                        .status(Status::PartialContent)
+                        .raw_header(
+                            "Content-Range",
+                            format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                        )
+                        .raw_header("Content-Length", (range.end - range.start).to_string()),
+                    None => response.raw_header("Content-Length", size.to_string()),
+                };
+                response.streamed_body(reader);
+            }
+            Download::NotModified { validators } => {
+                add_validators(&mut response, validators);
+                response.status(Status::NotModified);
+            }
+            Download::NotSatisfiable { validators, size } => {
+                add_validators(&mut response, validators);
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", size));
+            }
+        }
+        response.ok()
+    }
+}
+
+/// Add the `ETag` and `Last-Modified` headers to a response.
+fn add_validators(response: &mut response::Builder<'_>, validators: Validators) {
+    response
+        .raw_header("ETag", validators.etag)
+        .raw_header("Last-Modified", format_http_date(validators.last_modified));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(
+            parse_range("bytes=0-99", 1000),
+            RequestedRange::Partial(0..100)
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            RequestedRange::Partial(500..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=900-5000", 1000),
+            RequestedRange::Partial(900..1000)
+        );
+    }
+
+    #[test]
+    fn suffix_ranges_are_the_last_bytes() {
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            RequestedRange::Partial(900..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            RequestedRange::Partial(0..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=-0", 1000),
+            RequestedRange::NotSatisfiable
+        );
+        assert_eq!(parse_range("bytes=-10", 0), RequestedRange::NotSatisfiable);
+    }
+
+    #[test]
+    fn ranges_out_of_the_file_are_not_satisfiable() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RequestedRange::NotSatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=2000-3000", 1000),
+            RequestedRange::NotSatisfiable
+        );
+        assert_eq!(parse_range("bytes=0-0", 0), RequestedRange::NotSatisfiable);
+    }
+
+    #[test]
+    fn multiple_and_invalid_ranges_send_the_whole_file() {
+        assert_eq!(parse_range("bytes=0-10, 20-30", 1000), RequestedRange::Full);
+        assert_eq!(parse_range("bytes=10-5", 1000), RequestedRange::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), RequestedRange::Full);
+        assert_eq!(parse_range("bytes=10", 1000), RequestedRange::Full);
+        assert_eq!(parse_range("items=0-10", 1000), RequestedRange::Full);
+    }
+
+    #[test]
+    fn outdated_if_range_sends_the_whole_file() {
+        let validators = Validators::new("digest", 0);
+        let last_modified = format_http_date(0);
+        let headers = |if_range| DownloadHeaders {
+            range: Some("bytes=0-9"),
+            if_range,
+            ..Default::default()
+        };
+        assert_eq!(
+            headers(Some("\"digest\"")).range(&validators, 100),
+            RequestedRange::Partial(0..10)
+        );
+        assert_eq!(
+            headers(Some(&last_modified)).range(&validators, 100),
+            RequestedRange::Partial(0..10)
+        );
+        assert_eq!(
+            headers(Some("\"other\"")).range(&validators, 100),
+            RequestedRange::Full
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Request, Response};
+
+/// Error for roxide, returned as much as possible
+#[derive(Debug, thiserror::Error)]
+pub enum RoxideError {
+    #[error("token not valid")]
+    InvalidToken,
+    #[error("token not allowed to change this file")]
+    Forbidden,
+    #[error("file not found")]
+    NotFound,
+    #[error("file expired")]
+    Expired,
+    #[error("duration not valid")]
+    InvalidDuration,
+    #[error("visibility not valid")]
+    InvalidVisibility,
+    #[error("maximum number of downloads not valid")]
+    InvalidMaxDownloads,
+    #[error("collection not valid")]
+    InvalidCollection,
+    #[error("selection of the files of the archive not valid")]
+    InvalidArchive,
+    #[error("text of the paste not valid UTF-8")]
+    InvalidText,
+    #[error("language not known")]
+    InvalidLanguage,
+    #[error("tags not valid")]
+    InvalidTags,
+    #[error("content too large")]
+    TooLarge,
+    #[error("file protected by a password")]
+    PasswordRequired,
+    #[error("password not valid")]
+    InvalidPassword,
+    #[error("too many attempts to unlock the file")]
+    TooManyAttempts,
+    #[error("signature of the link not valid")]
+    InvalidSignature,
+    #[error("link expired")]
+    ExpiredLink,
+    #[error("file only available through a signed link")]
+    SignatureRequired,
+    #[error("too much upload")]
+    TooManyUploads,
+    #[error("file is not an image")]
+    NotAnImage,
+    #[error("image is too large to be resized")]
+    ImageTooLarge,
+    #[error("size of the image not valid")]
+    InvalidSize,
+    #[error("too many versions of the image")]
+    TooManyRenditions,
+    #[error("format not supported")]
+    UnsupportedFormat,
+    #[error("cursor of the list not valid")]
+    InvalidCursor,
+    #[error("rocket : {0}")]
+    Rocket(#[from] rocket::Error),
+    #[error("database : {0}")]
+    Database(sqlx::error::Error),
+    #[error("IO : {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// A missing row is a file that does not exist.
+impl From<sqlx::error::Error> for RoxideError {
+    fn from(error: sqlx::error::Error) -> Self {
+        match error {
+            sqlx::error::Error::RowNotFound => RoxideError::NotFound,
+            error => RoxideError::Database(error),
+        }
+    }
+}
+
+impl RoxideError {
+    /// Return the HTTP status of the error.
+    pub fn status(&self) -> Status {
+        match self {
+            RoxideError::InvalidToken => Status::Unauthorized,
+            RoxideError::Forbidden => Status::Forbidden,
+            RoxideError::NotFound => Status::NotFound,
+            RoxideError::Expired => Status::Gone,
+            RoxideError::InvalidDuration => Status::BadRequest,
+            RoxideError::InvalidMaxDownloads => Status::BadRequest,
+            RoxideError::InvalidCollection => Status::BadRequest,
+            RoxideError::InvalidArchive => Status::BadRequest,
+            RoxideError::InvalidText => Status::BadRequest,
+            RoxideError::InvalidLanguage => Status::BadRequest,
+            RoxideError::InvalidTags => Status::BadRequest,
+            RoxideError::TooLarge => Status::PayloadTooLarge,
+            RoxideError::InvalidVisibility => Status::BadRequest,
+            RoxideError::PasswordRequired => Status::Unauthorized,
+            RoxideError::InvalidPassword => Status::Forbidden,
+            RoxideError::TooManyAttempts => Status::TooManyRequests,
+            RoxideError::InvalidSignature => Status::Forbidden,
+            RoxideError::ExpiredLink => Status::Gone,
+            RoxideError::SignatureRequired => Status::Forbidden,
+            RoxideError::TooManyUploads => Status::TooManyRequests,
+            RoxideError::NotAnImage => Status::UnsupportedMediaType,
+            RoxideError::ImageTooLarge => Status::PayloadTooLarge,
+            RoxideError::InvalidSize => Status::BadRequest,
+            RoxideError::TooManyRenditions => Status::BadRequest,
+            RoxideError::UnsupportedFormat => Status::NotImplemented,
+            RoxideError::InvalidCursor => Status::BadRequest,
+            RoxideError::Rocket(_) | RoxideError::Database(_) | RoxideError::IO(_) => {
+                Status::InternalServerError
+            }
+        }
+    }
+
+    /// Return the machine-readable code of the error.
+    ///
+    /// The codes are stable, clients can rely on them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RoxideError::InvalidToken => "invalid_token",
+            RoxideError::Forbidden => "forbidden",
+            RoxideError::NotFound => "not_found",
+            RoxideError::Expired => "expired",
+            RoxideError::InvalidDuration => "invalid_duration",
+            RoxideError::InvalidMaxDownloads => "invalid_max_downloads",
+            RoxideError::InvalidCollection => "invalid_collection",
+            RoxideError::InvalidArchive => "invalid_archive",
+            RoxideError::InvalidText => "invalid_text",
+            RoxideError::InvalidLanguage => "invalid_language",
+            RoxideError::InvalidTags => "invalid_tags",
+            RoxideError::TooLarge => "payload_too_large",
+            RoxideError::InvalidVisibility => "invalid_visibility",
+            RoxideError::PasswordRequired => "password_required",
+            RoxideError::InvalidPassword => "invalid_password",
+            RoxideError::TooManyAttempts => "too_many_attempts",
+            RoxideError::InvalidSignature => "invalid_signature",
+            RoxideError::ExpiredLink => "expired_link",
+            RoxideError::SignatureRequired => "signature_required",
+            RoxideError::TooManyUploads => "too_many_uploads",
+            RoxideError::NotAnImage => "not_an_image",
+            RoxideError::ImageTooLarge => "image_too_large",
+            RoxideError::InvalidSize => "invalid_size",
+            RoxideError::TooManyRenditions => "too_many_renditions",
+            RoxideError::UnsupportedFormat => "unsupported_format",
+            RoxideError::InvalidCursor => "invalid_cursor",
+            RoxideError::Rocket(_) | RoxideError::Database(_) | RoxideError::IO(_) => "internal",
+        }
+    }
+}
+
+/// Body of an error response, following RFC 7807 (problem details for HTTP APIs).
+///
+/// Besides the standard members, `code` is the machine-readable code of the error.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+}
+
+impl Problem {
+    pub fn new(status: Status, code: &'static str, detail: String) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.reason_lossy(),
+            status: status.code,
+            detail,
+            code,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        Response::build_from(Json(self).respond_to(req)?)
+            .status(status)
+            .header(ContentType::new("application", "problem+json"))
+            .ok()
+    }
+}
+
+/// Implement Responder for RoxideError so it can be returned by Rocket.
+///
+/// The error is sent as a problem with the status and the code of the error. The details of
+/// internal errors are only logged.
+impl<'r> Responder<'r, 'static> for RoxideError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let detail = if status == Status::InternalServerError {
+            eprintln!("Internal error: {}", self);
+            status.reason_lossy().to_string()
+        } else {
+            self.to_string()
+        };
+        Problem::new(status, self.code(), detail).respond_to(req)
+    }
+}
+
+/// Catcher that turns the errors raised by Rocket (unknown route, invalid form, too large
+/// upload, ...) into problems.
+#[catch(default)]
+pub fn default_catcher(status: Status, _req: &Request<'_>) -> Problem {
+    let code = match status.code {
+        400 => "bad_request",
+        401 => "invalid_token",
+        403 => "forbidden",
+        404 => "not_found",
+        413 => "payload_too_large",
+        422 => "invalid_form",
+        _ => "error",
+    };
+    Problem::new(status, code, status.reason_lossy().to_string())
+}
@@ -1,6 +1,5 @@
 use rand::seq::SliceRandom;
 use rocket::request::FromParam;
-use std::path::{Path, PathBuf};
 
 ///The structure to manage ids of images.
 #[derive(Debug)]
@@ -31,11 +30,6 @@ impl FileId {
         Self { id: id.to_string() }
     }
 
-    ///Compute the path of the file.
-    pub fn file_path(&self, root: &str) -> PathBuf {
-        Path::new(root).join(&self.id)
-    }
-
     ///Return a reference to the id.
     pub fn get_id(&self) -> &str {
         &self.id
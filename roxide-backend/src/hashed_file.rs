@@ -0,0 +1,193 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use sha2::{Digest, Sha256};
+
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::form::{self, DataField, FromFormField};
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+use crate::file_id::FileId;
+
+///An uploaded file, stored in a temporary file and hashed with SHA-256 while it is received.
+///
+///The temporary file is deleted when the structure is dropped, unless it comes from `from_kept_path`.
+#[derive(Debug)]
+pub struct HashedFile {
+    path: PathBuf,
+    digest: String,
+    size: u64,
+    owned: bool,
+}
+
+impl HashedFile {
+    ///Hash an existing file and take ownership of it, the file is deleted when the structure is
+    ///dropped.
+    pub async fn from_path(path: PathBuf) -> io::Result<Self> {
+        let mut hashed_file = HashedFile {
+            path,
+            digest: String::new(),
+            size: 0,
+            owned: true,
+        };
+        hashed_file.hash().await?;
+        Ok(hashed_file)
+    }
+
+    ///Hash an existing file without taking ownership of it, the file is kept when the structure
+    ///is dropped.
+    pub async fn from_kept_path(path: PathBuf) -> io::Result<Self> {
+        let mut hashed_file = HashedFile {
+            path,
+            digest: String::new(),
+            size: 0,
+            owned: false,
+        };
+        hashed_file.hash().await?;
+        Ok(hashed_file)
+    }
+
+    ///Compute the digest and the size of the file.
+    async fn hash(&mut self) -> io::Result<()> {
+        let mut file = File::open(&self.path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            self.size += read as u64;
+        }
+        self.digest = hex::encode(hasher.finalize());
+        Ok(())
+    }
+
+    ///Receive *data* in a temporary file of *temp_dir*, hashing it on the way.
+    ///
+    ///Return None if the data is longer than *limit*.
+    pub async fn receive(
+        data: Data<'_>,
+        limit: ByteUnit,
+        temp_dir: &Path,
+    ) -> io::Result<Option<Self>> {
+        let path = temp_dir.join(format!("roxide-{}", FileId::new(16).get_id()));
+
+        // Build the structure first so the temporary file is deleted on error.
+        let mut hashed_file = HashedFile {
+            path,
+            digest: String::new(),
+            size: 0,
+            owned: true,
+        };
+        let file = File::create(&hashed_file.path).await?;
+        let mut writer = HashingWriter {
+            inner: BufWriter::new(file),
+            hasher: Sha256::new(),
+        };
+        let written = data.open(limit).stream_to(&mut writer).await?;
+        writer.flush().await?;
+        if !written.complete {
+            return Ok(None);
+        }
+
+        hashed_file.digest = hex::encode(writer.hasher.finalize());
+        hashed_file.size = written.written;
+        Ok(Some(hashed_file))
+    }
+
+    ///Indicate if the content is text: valid UTF-8, without NUL bytes.
+    pub async fn is_text(&self) -> io::Result<bool> {
+        let mut file = File::open(&self.path).await?;
+        let mut buffer = vec![0; 64 * 1024];
+        // Bytes of a character cut at the end of the previous read
+        let mut pending = 0;
+        loop {
+            let read = file.read(&mut buffer[pending..]).await?;
+            if read == 0 {
+                return Ok(pending == 0);
+            }
+            let end = pending + read;
+            if buffer[pending..end].contains(&0) {
+                return Ok(false);
+            }
+            match std::str::from_utf8(&buffer[..end]) {
+                Ok(_) => pending = 0,
+                Err(err) if err.error_len().is_none() => {
+                    buffer.copy_within(err.valid_up_to()..end, 0);
+                    pending = end - err.valid_up_to();
+                }
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+
+    ///Return the path of the temporary file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    ///Return the hexadecimal SHA-256 digest of the content.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    ///Return the size of the content in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for HashedFile {
+    fn drop(&mut self) {
+        if self.owned {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+///Writer that feeds everything it writes to a SHA-256 hasher.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.hasher.update(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+///Allows to receive a HashedFile from a multipart form, with the same limit as a TempFile.
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for HashedFile {
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        let limit = field.request.limits().get("file").unwrap_or(Limits::FILE);
+        let temp_dir = field.request.rocket().config().temp_dir.relative();
+        match HashedFile::receive(field.data, limit, &temp_dir).await? {
+            Some(hashed_file) => Ok(hashed_file),
+            None => Err((None, Some(limit)))?,
+        }
+    }
+}
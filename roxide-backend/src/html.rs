@@ -0,0 +1,125 @@
+use chrono::{TimeZone, Utc};
+
+use rocket::request::{self, FromRequest};
+use rocket::response::content::RawHtml;
+use rocket::Request;
+
+use crate::AppConfig;
+
+/// Request guard that tells if the client prefers an HTML page to JSON, like a browser.
+pub struct PrefersHtml(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PrefersHtml {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let html = req
+            .accept()
+            .map_or(false, |accept| accept.preferred().media_type().is_html());
+        request::Outcome::Success(PrefersHtml(html))
+    }
+}
+
+/// Escape *text* to insert it in an HTML page, in a text node or in a quoted attribute.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode *value* to insert it in the query of a URL.
+pub fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Format a size in bytes for humans.
+pub fn format_size(size: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format a timestamp for humans, in UTC.
+pub fn format_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map_or_else(String::new, |date| {
+            date.format("%Y-%m-%d %H:%M UTC").to_string()
+        })
+}
+
+/// Format a duration in seconds for humans, in its largest unit.
+pub fn format_duration(seconds: i64) -> String {
+    const UNITS: [(i64, &str); 4] = [
+        (86400, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+    for (length, unit) in UNITS {
+        match seconds / length {
+            0 => continue,
+            1 => return format!("1 {}", unit),
+            count => return format!("{} {}s", count, unit),
+        }
+    }
+    "0 seconds".to_string()
+}
+
+/// Request guard that gives the absolute URL of the server, to build the links read outside of
+/// the pages (like the previews of the links): `public_url`. The request is forwarded if it is
+/// not configured, the `Host` of the request cannot be trusted.
+pub struct BaseUrl(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BaseUrl {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let public_url = req
+            .rocket()
+            .state::<AppConfig>()
+            .and_then(|app_config| app_config.public_url.as_deref());
+        match public_url {
+            Some(url) => request::Outcome::Success(BaseUrl(url.trim_end_matches('/').to_string())),
+            None => request::Outcome::Forward(()),
+        }
+    }
+}
+
+/// Build an HTML page of *title*, with the already escaped *head* and *body*.
+pub fn page(title: &str, head: &str, body: &str) -> RawHtml<String> {
+    RawHtml(format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n{}</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title),
+        head,
+        body
+    ))
+}
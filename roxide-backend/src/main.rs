@@ -1,31 +1,53 @@
 #[macro_use]
 extern crate rocket;
+mod access;
+mod admin;
+mod archive;
+mod blob;
+mod collection;
+mod db;
+mod download;
+mod error;
 mod file_id;
+mod hashed_file;
+mod html;
+mod maintenance;
+mod oembed;
+mod password;
+mod paste;
+mod preview;
+mod rendition;
+mod s3;
+mod sanitize;
+mod search;
+mod share;
+mod signature;
+mod storage;
+mod tags;
+mod tus;
 mod user;
 
 use std::fs;
 use std::path::Path;
-use std::time::Duration;
-
-use chrono::Utc;
 
 use rocket::config::Config;
 use rocket::fairing::AdHoc;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Header;
-use rocket::response::Responder;
 use rocket::serde::Deserialize;
 use rocket::{Request, Response};
 
-use rocket_db_pools::sqlx;
 use rocket_db_pools::Database;
 
-use sqlx::Row;
-use sqlx::SqlitePool;
-
 use redis::Commands;
 
+use crate::db::DbPool;
+use crate::error::RoxideError;
 use crate::file_id::FileId;
+use crate::rendition::RenditionCache;
+use crate::sanitize::SanitizeConfig;
+use crate::signature::UrlSigner;
+use crate::storage::StorageConfig;
 
 pub struct CORS;
 
@@ -42,38 +64,17 @@ impl Fairing for CORS {
         response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
-            "POST, GET, PATCH, OPTIONS",
+            "POST, GET, HEAD, PATCH, DELETE, OPTIONS",
         ));
         response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
+        response.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            "Location, Tus-Resumable, Tus-Version, Tus-Extension, Tus-Max-Size, Upload-Offset, Upload-Length",
+        ));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
     }
 }
 
-/// Error for roxide, returned as much as possible
-#[derive(Debug, thiserror::Error)]
-enum RoxideError {
-    #[error("roxide : {0}")]
-    Roxide(String),
-    #[error("rocket : {0}")]
-    Rocket(#[from] rocket::Error),
-    #[error("database : {0}")]
-    Database(#[from] sqlx::error::Error),
-    #[error("IO : {0}")]
-    IO(#[from] std::io::Error),
-}
-
-/// Implement Responder for RoxideError so it can be returned by Rocket.
-///
-/// The function simply return the to_string of the error.
-impl<'r> Responder<'r, 'static> for RoxideError {
-    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let string = self.to_string();
-        rocket::Response::build_from(string.respond_to(req)?)
-            .status(rocket::http::Status::InternalServerError)
-            .ok()
-    }
-}
-
 /// Structure that contains the configuration of Roxide.
 ///
 /// This configuration is extracted from Rocket.toml.
@@ -84,16 +85,83 @@ struct AppConfig {
     id_length: usize,
     max_upload: usize,
     cleaning_frequency: usize,
-    url: String,
     check_token: bool,
     front_sources: std::path::PathBuf,
     default_duration: i64,
+    #[serde(default)]
+    storage: StorageConfig,
+    #[serde(default = "unlimited_duration")]
+    max_duration: i64,
+    #[serde(default)]
+    admin_tokens: Vec<String>,
+    #[serde(default)]
+    sanitize: SanitizeConfig,
+    #[serde(default)]
+    url_signing_key: Option<String>,
+    #[serde(default)]
+    public_url: Option<String>,
+}
+
+/// Default of `max_duration`, files can be kept forever.
+fn unlimited_duration() -> i64 {
+    i64::MAX
+}
+
+/// Function that builds the configuration of the tests, without token checks, storing the
+/// uploads in *upload_directory*.
+#[cfg(test)]
+fn test_config(upload_directory: &Path) -> AppConfig {
+    AppConfig {
+        upload_directory: upload_directory.to_str().unwrap().to_string(),
+        id_length: 6,
+        max_upload: 100,
+        cleaning_frequency: 60,
+        check_token: false,
+        front_sources: std::path::PathBuf::new(),
+        default_duration: 3600,
+        storage: Default::default(),
+        max_duration: i64::MAX,
+        admin_tokens: vec!["admin".to_string()],
+        sanitize: Default::default(),
+        url_signing_key: None,
+        public_url: None,
+    }
+}
+
+/// Function that builds a client of the tests that mounts *routes*, with the configuration of
+/// `test_config`, the database of `db::test_pool` in *dir* and the storage and the renditions in
+/// its directory `upload`.
+#[cfg(test)]
+async fn test_client(
+    dir: &Path,
+    routes: Vec<rocket::Route>,
+) -> rocket::local::asynchronous::Client {
+    let figment = rocket::figment::Figment::from(Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge((
+            "databases.sqlite_logs.url",
+            format!("sqlite://{}", dir.join("roxide.db").display()),
+        ));
+    let upload = dir.join("upload");
+    fs::create_dir_all(&upload).unwrap();
+    let storage: storage::Storage =
+        std::sync::Arc::new(storage::LocalStorage::new(upload.to_str().unwrap()));
+    let rocket = rocket::custom(figment)
+        .attach(Canard::init())
+        .manage(test_config(dir))
+        .manage(storage)
+        .manage(RenditionCache::new(upload.to_str().unwrap()))
+        .manage(UrlSigner::new(Some("test")))
+        .mount("/", routes);
+    rocket::local::asynchronous::Client::tracked(rocket)
+        .await
+        .unwrap()
 }
 
 /// Type that encapsulate a connection to the database
 #[derive(Database)]
 #[database("sqlite_logs")]
-struct Canard(sqlx::SqlitePool);
+struct Canard(DbPool);
 
 fn get_redis_connection() -> redis::RedisResult<redis::Connection> {
     let client = redis::Client::open("redis://127.0.0.1/")?;
@@ -111,32 +179,35 @@ fn is_token_valid(token: &str, app_config: &AppConfig) -> bool {
     }
 }
 
+/// Function that returns the bearer token of a request (`Authorization: Bearer <token>`).
+fn bearer_token<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.headers()
+        .get_one("Authorization")
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+}
+
 #[rocket::main]
 async fn main() -> Result<(), RoxideError> {
     let app_config = Config::figment().extract::<AppConfig>().unwrap();
+    let storage = app_config.storage.build(&app_config.upload_directory);
+    let renditions = RenditionCache::new(&app_config.upload_directory);
+    let signer = UrlSigner::new(app_config.url_signing_key.as_deref());
     let mut r = rocket::build();
 
     r = r.attach(Canard::init())
+        .manage(storage)
+        .manage(renditions)
+        .manage(signer)
         .attach(AdHoc::config::<AppConfig>())
-		.attach(AdHoc::try_on_ignite("Database Initialization", |rocket| async {
+		.attach(AdHoc::try_on_ignite("Database Migrations", |rocket| async {
 			let conn = match Canard::fetch(&rocket) {
 				Some(pool) => pool.clone(), // clone the wrapped pool
 				None => return Err(rocket),
 			};
 
-            let expired_rows = sqlx::query("SELECT id, title, expiration_date, upload_date, token_used, content_type, download_count, public, size FROM files")
-                .fetch_all(&**conn)
-                .await;
-            if expired_rows.is_err() {
-                eprintln!("Initializing Database");
-                let create = sqlx::query(
-                    "CREATE TABLE files (id TEXT, title TEXT, expiration_date UNSIGNED BIG INT, upload_date UNSIGNED BIG INT, token_used TEXT, content_type TEXT, download_count UNSIGNED BIG INT, public BOOL, size UNSIGNED BIG INT);",
-                )
-                .execute(&**conn)
-                .await;
-                if create.is_err() {
-                    return Err(rocket);
-                }
+            if let Err(err) = db::migrate(conn).await {
+                eprintln!("Cannot migrate the database: {}", err);
+                return Err(rocket);
             }
 			Ok(rocket)
 		}))
@@ -151,68 +222,31 @@ async fn main() -> Result<(), RoxideError> {
             }
 			Ok(rocket)
 		}))
-        .attach(AdHoc::on_liftoff("Database Cleanning", |rocket| {
-            Box::pin(async move {
-                let conn = match Canard::fetch(rocket) {
-                    Some(pool) => pool.clone(), // clone the wrapped pool
-                    None => panic!("Cannot fetch database"),
-                };
-                let now = Utc::now().timestamp();
-                let expired_rows = sqlx::query("SELECT id FROM files WHERE expiration_date < $1")
-                    .bind(&now)
-                    .fetch_all(&**conn)
-                    .await;
-                if let Ok(expired_rows) = expired_rows {
-                    for id in expired_rows.iter().map(|row| row.get::<&str, &str>("id")) {
-                        let id = FileId::from(id);
-                        let deleted = fs::remove_file(id.file_path("./upload"));
-                        if let Err(err) = deleted {
-                            eprintln!("Cannot delete {:?}", err);
-                        }
-                    }
-                    sqlx::query("DELETE FROM files WHERE expiration_date < $1")
-                        .bind(&now)
-                        .execute(&**conn)
-                        .await
-                        .unwrap();
-                }
-            })
-        }))
+        .attach(maintenance::stage())
         .attach(CORS)
+        .register("/", catchers![error::default_catcher])
         .attach(user::stage())
-        .mount("/", rocket::fs::FileServer::from(app_config.front_sources));
+        .attach(password::stage())
+        .attach(access::stage())
+        .attach(collection::stage())
+        .attach(archive::stage())
+        .attach(paste::stage())
+        .attach(preview::stage())
+        .attach(oembed::stage())
+        .attach(search::stage())
+        .attach(tags::stage())
+        .attach(share::stage())
+        .attach(signature::stage())
+        .attach(tus::stage());
+
+    // The admin area only exists if admin tokens are configured
+    if !app_config.admin_tokens.is_empty() {
+        r = r.attach(admin::stage());
+    }
 
-    let r = r.ignite().await?;
+    r = r.mount("/", rocket::fs::FileServer::from(app_config.front_sources));
 
-    let cleaning_frequency = app_config.cleaning_frequency as u64;
-    let upload_directory = app_config.upload_directory.to_string();
-    let database_url = app_config.url.to_string();
-
-    rocket::tokio::task::spawn(async move {
-        let conn = SqlitePool::connect(&database_url).await.unwrap();
-        loop {
-            rocket::tokio::time::sleep(Duration::from_secs(cleaning_frequency)).await;
-            let now = Utc::now().timestamp();
-            let expired_rows = sqlx::query("SELECT id FROM files WHERE expiration_date < $1")
-                .bind(&now)
-                .fetch_all(&conn)
-                .await;
-            if let Ok(expired_rows) = expired_rows {
-                for id in expired_rows.iter().map(|row| row.get::<&str, &str>("id")) {
-                    let id = FileId::from(id);
-                    let deleted = fs::remove_file(id.file_path(&upload_directory));
-                    if let Err(err) = deleted {
-                        eprintln!("Cannot delete {:?}", err);
-                    }
-                }
-                sqlx::query("DELETE FROM files WHERE expiration_date < $1")
-                    .bind(&now)
-                    .execute(&conn)
-                    .await
-                    .unwrap();
-            }
-        }
-    });
+    let r = r.ignite().await?;
 
     let _ = r.launch().await?;
 
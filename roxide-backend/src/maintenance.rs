@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+use rocket_db_pools::Database;
+
+use sqlx::Row;
+
+use crate::admin::AdminToken;
+use crate::blob::delete_unreferenced;
+use crate::collection::clean_collections;
+use crate::db::DbPool;
+use crate::password::clean_attempts;
+use crate::rendition::RenditionCache;
+use crate::share::clean_links;
+use crate::storage::Storage;
+use crate::tags::clean_tags;
+use crate::tus::clean_abandoned_uploads;
+use crate::user::{clean_tombstones, expire_file};
+use crate::{AppConfig, Canard, FileId, RoxideError};
+
+/// What a maintenance run deleted.
+#[derive(Debug, Default, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MaintenanceReport {
+    /// Ids of the expired files deleted, and of the files that reached their maximum number of
+    /// downloads.
+    expired_files: Vec<String>,
+    /// Digests of the contents deleted from the storage, because no file references them
+    /// anymore.
+    deleted_blobs: Vec<String>,
+    /// Digests of the contents whose renditions were deleted.
+    deleted_renditions: Vec<String>,
+    /// Ids of the resumable uploads abandoned before their end.
+    abandoned_uploads: Vec<String>,
+    /// Ids of the expired files that could not be deleted, they are retried on the next run.
+    failed_files: Vec<String>,
+}
+
+impl MaintenanceReport {
+    fn is_empty(&self) -> bool {
+        self.expired_files.is_empty()
+            && self.deleted_blobs.is_empty()
+            && self.deleted_renditions.is_empty()
+            && self.abandoned_uploads.is_empty()
+            && self.failed_files.is_empty()
+    }
+}
+
+/// Service that deletes the expired files, the contents that no file references, the renditions
+/// of the deleted contents and the abandoned uploads.
+///
+/// It runs every `cleaning_frequency` seconds, and on demand from `/clean`. A run can be
+/// repeated safely: a file deleted in the meantime, or whose content is already missing, is not
+/// an error, and a file that cannot be deleted does not stop the others from being deleted.
+#[derive(Clone)]
+pub struct Maintenance {
+    pool: DbPool,
+    storage: Storage,
+    renditions: RenditionCache,
+    upload_directory: String,
+}
+
+impl Maintenance {
+    pub fn new(
+        pool: DbPool,
+        storage: Storage,
+        renditions: RenditionCache,
+        upload_directory: String,
+    ) -> Self {
+        Self {
+            pool,
+            storage,
+            renditions,
+            upload_directory,
+        }
+    }
+
+    /// Delete the expired files, the renditions of the deleted contents and the abandoned
+    /// uploads. The failed attempts to unlock the files are forgotten once they are too old to
+    /// count, and the tombstones of the expired files after 30 days. The tags that no file has
+    /// anymore are deleted.
+    ///
+    /// The renditions are deleted with their content, whether it expired or was deleted by its
+    /// uploader or an admin.
+    pub async fn run(&self) -> Result<MaintenanceReport, RoxideError> {
+        let mut report = MaintenanceReport::default();
+        let mut conn = self.pool.acquire().await?;
+
+        let now = Utc::now().timestamp();
+        let expired_rows = sqlx::query(
+            "SELECT id FROM files WHERE expiration_date <= $1 OR download_count >= max_downloads",
+        )
+        .bind(now)
+        .fetch_all(&mut *conn)
+        .await?;
+        for row in expired_rows {
+            let id = FileId::from(row.get::<&str, &str>("id"));
+            match expire_file(&mut conn, &self.storage, &id).await {
+                Ok(blob) => {
+                    report.expired_files.push(id.get_id().to_string());
+                    report.deleted_blobs.extend(blob);
+                }
+                // Deleted in the meantime
+                Err(RoxideError::NotFound) => {}
+                Err(err) => {
+                    eprintln!("Cannot delete {}: {:?}", id.get_id(), err);
+                    report.failed_files.push(id.get_id().to_string());
+                }
+            }
+        }
+
+        // Blobs left without a reference, e.g. by the migration of an old database
+        report
+            .deleted_blobs
+            .extend(delete_unreferenced(&mut conn, &self.storage).await?);
+
+        report.deleted_renditions = self.renditions.remove_orphans(&mut conn).await?;
+        clean_attempts(&mut conn).await?;
+        clean_links(&mut conn).await?;
+        clean_collections(&mut conn).await?;
+        clean_tags(&mut conn).await?;
+        clean_tombstones(&mut conn).await?;
+        report.abandoned_uploads =
+            clean_abandoned_uploads(&mut conn, &self.upload_directory).await?;
+
+        if !report.is_empty() {
+            eprintln!(
+                "Maintenance: {} expired files deleted, {} contents deleted, renditions of {} contents deleted, {} abandoned uploads deleted, {} failures",
+                report.expired_files.len(),
+                report.deleted_blobs.len(),
+                report.deleted_renditions.len(),
+                report.abandoned_uploads.len(),
+                report.failed_files.len()
+            );
+        }
+        Ok(report)
+    }
+
+    /// Run the maintenance every *frequency*, starting now.
+    pub fn spawn(self, frequency: Duration) {
+        rocket::tokio::task::spawn(async move {
+            loop {
+                if let Err(err) = self.run().await {
+                    eprintln!("Maintenance failed: {:?}", err);
+                }
+                rocket::tokio::time::sleep(frequency).await;
+            }
+        });
+    }
+}
+
+/// Function that triggers a maintenance run and reports what it deleted, reserved to admins.
+#[get("/clean")]
+async fn clean(
+    _admin: AdminToken,
+    maintenance: &State<Maintenance>,
+) -> Result<Json<MaintenanceReport>, RoxideError> {
+    Ok(Json(maintenance.run().await?))
+}
+
+/// Function that starts the maintenance service once the database is ready, and mounts the route
+/// that triggers it.
+/// - clean (to trigger a maintenance run).
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Maintenance stage", |rocket| async {
+        let pool = match Canard::fetch(&rocket) {
+            Some(pool) => DbPool::clone(pool),
+            None => return Err(rocket),
+        };
+        let (storage, renditions, app_config) = match (
+            rocket.state::<Storage>(),
+            rocket.state::<RenditionCache>(),
+            rocket.state::<AppConfig>(),
+        ) {
+            (Some(storage), Some(renditions), Some(app_config)) => {
+                (storage.clone(), renditions.clone(), app_config)
+            }
+            _ => return Err(rocket),
+        };
+        let frequency = Duration::from_secs(app_config.cleaning_frequency as u64);
+        let maintenance = Maintenance::new(
+            pool,
+            storage,
+            renditions,
+            app_config.upload_directory.clone(),
+        );
+
+        Ok(rocket
+            .manage(maintenance)
+            .mount("/", routes![clean])
+            .attach(AdHoc::on_liftoff("Maintenance", move |rocket| {
+                Box::pin(async move {
+                    if let Some(maintenance) = rocket.state::<Maintenance>() {
+                        maintenance.clone().spawn(frequency);
+                    }
+                })
+            })))
+    })
+}
@@ -0,0 +1,250 @@
+use rocket::fairing::AdHoc;
+use rocket::request::FromParam;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+use rocket_db_pools::Connection;
+
+use crate::access::FileAccess;
+use crate::html::{escape, BaseUrl};
+use crate::rendition::{
+    contained_dimensions, image_dimensions, size_below, thumbnail_dimensions, MAX_DIMENSION,
+};
+use crate::storage::Storage;
+use crate::user::open_file;
+use crate::{Canard, FileId, RoxideError};
+
+/// Routes whose URL gives a file, `/<route>/<id>`.
+const FILE_ROUTES: [&str; 4] = ["f", "get", "p", "thumbnail"];
+
+/// Largest width of an embedded video, its height follows a 16:9 aspect ratio.
+const VIDEO_WIDTH: u32 = 640;
+
+/// Width and height of an embedded audio player.
+const AUDIO_WIDTH: u32 = 300;
+const AUDIO_HEIGHT: u32 = 54;
+
+/// oEmbed response, see <https://oembed.com>.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    provider_name: &'static str,
+    provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u32>,
+}
+
+/// Remove the scheme of *url*: the server can be behind a proxy that terminates TLS.
+fn strip_scheme(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| rest)
+}
+
+/// Find the file of *url*, a URL of the server *base*. Return its id with the query of the URL.
+fn parse_url<'u>(url: &'u str, base: &str) -> Option<(FileId, Option<&'u str>)> {
+    let url = url.split('#').next().unwrap_or(url);
+    let path = strip_scheme(url).strip_prefix(strip_scheme(base))?;
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let mut segments = path.strip_prefix('/')?.split('/');
+    let (route, id) = (segments.next()?, segments.next()?);
+    if segments.next().is_some() || !FILE_ROUTES.contains(&route) {
+        return None;
+    }
+    Some((FileId::from_param(id).ok()?, query))
+}
+
+/// Scale down *width* x *height* to fit in *max_width* x *max_height*, if it does not fit.
+fn scale_down(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        (width, height)
+    } else {
+        contained_dimensions(width, height, max_width, max_height)
+    }
+}
+
+/// Box of the rendition of an image of *width* x *height* that fits in *max_width* x
+/// *max_height*, with the dimensions of the image in it. None if the image already fits.
+///
+/// The box is made of the sizes of the renditions, so that the rendition can be reused.
+fn scaled_box(
+    width: u32,
+    height: u32,
+    max_width: u32,
+    max_height: u32,
+) -> Option<((u32, u32), (u32, u32))> {
+    if width <= max_width && height <= max_height {
+        return None;
+    }
+    let (box_width, box_height) = (size_below(max_width), size_below(max_height));
+    Some((
+        (box_width, box_height),
+        contained_dimensions(width, height, box_width, box_height),
+    ))
+}
+
+/// Keep the signature of a share link from *query*, for the links to the file.
+fn signature_query(query: Option<&str>) -> String {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| pair.starts_with("exp=") || pair.starts_with("sig="))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Query of an oEmbed request: the URL of the file, the box the embedded file fits in, and the
+/// format of the response.
+#[derive(Debug, FromForm)]
+struct OEmbedQuery<'r> {
+    url: &'r str,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<&'r str>,
+}
+
+/// Function that answers the oEmbed requests for the URLs of the files (`/f/<id>`, `/get/<id>`,
+/// `/p/<id>` and `/thumbnail/<id>`), to embed them in other sites.
+///
+/// The images are `photo` (scaled down to `maxwidth` and `maxheight`, with their thumbnail), the
+/// videos are `video`, the audio files are `rich`, and the other files, like the files whose
+/// number of downloads is limited, are `link`.
+///
+/// The file follows the rules of `/get/<id>`, with the share link of the URL if any, and the
+/// request does not count as a download. A URL that is not a file of the server gets 404, and
+/// another format than `json` gets 501. The URLs of the server are only known with `public_url`,
+/// without it every URL gets 404.
+#[get("/oembed?<query..>")]
+async fn oembed<'r>(
+    storage: &State<Storage>,
+    mut db: Connection<Canard>,
+    query: OEmbedQuery<'r>,
+    access: FileAccess<'r>,
+    base: Option<BaseUrl>,
+) -> Result<Json<OEmbed>, RoxideError> {
+    if query.format.map_or(false, |format| format != "json") {
+        return Err(RoxideError::UnsupportedFormat);
+    }
+    let base = base.ok_or(RoxideError::NotFound)?;
+    let (id, url_query) = parse_url(query.url, &base.0).ok_or(RoxideError::NotFound)?;
+    let access = access.with_link_of(url_query);
+    let file = open_file(storage, &mut db, &id, &access).await?;
+
+    let signature = signature_query(url_query);
+    let link = |route: &str, query: &str| {
+        let query = [query, &signature]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("&");
+        match query.as_str() {
+            "" => format!("{}/{}/{}", base.0, route, id.get_id()),
+            query => format!("{}/{}/{}?{}", base.0, route, id.get_id(), query),
+        }
+    };
+    let raw = link("get", "");
+    let max_width = query.maxwidth.unwrap_or(u32::MAX).max(1);
+    let max_height = query.maxheight.unwrap_or(u32::MAX).max(1);
+    let mut oembed = OEmbed {
+        version: "1.0",
+        kind: "link",
+        title: match file.title.as_str() {
+            "" => id.get_id().to_string(),
+            title => title.to_string(),
+        },
+        provider_name: "Roxide",
+        provider_url: base.0.clone(),
+        url: None,
+        html: None,
+        width: None,
+        height: None,
+        thumbnail_url: None,
+        thumbnail_width: None,
+        thumbnail_height: None,
+    };
+
+    // Embedding the content of a limited file would use its downloads
+    let kind = file.content_type.split('/').next().unwrap_or("");
+    match (kind, file.max_downloads) {
+        (_, Some(_)) => {}
+        ("image", None) => {
+            match image_dimensions(storage, &file.digest, &file.content_type).await {
+                Ok((width, height)) => {
+                    let (max_width, max_height) =
+                        (max_width.min(MAX_DIMENSION), max_height.min(MAX_DIMENSION));
+                    let ((scaled_width, scaled_height), url) =
+                        match scaled_box(width, height, max_width, max_height) {
+                            None => ((width, height), raw),
+                            Some(((box_width, box_height), scaled)) => (
+                                scaled,
+                                link("get", &format!("w={}&h={}", box_width, box_height)),
+                            ),
+                        };
+                    let (thumbnail_width, thumbnail_height) = thumbnail_dimensions(width, height);
+                    oembed.kind = "photo";
+                    oembed.url = Some(url);
+                    oembed.width = Some(scaled_width);
+                    oembed.height = Some(scaled_height);
+                    oembed.thumbnail_url = Some(link("thumbnail", ""));
+                    oembed.thumbnail_width = Some(thumbnail_width);
+                    oembed.thumbnail_height = Some(thumbnail_height);
+                }
+                Err(RoxideError::NotAnImage) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        ("video", None) => {
+            let (width, height) =
+                scale_down(VIDEO_WIDTH, VIDEO_WIDTH * 9 / 16, max_width, max_height);
+            oembed.kind = "video";
+            oembed.html = Some(format!(
+                "<video src=\"{}\" controls preload=\"metadata\" width=\"{}\" height=\"{}\"></video>",
+                escape(&raw),
+                width,
+                height
+            ));
+            oembed.width = Some(width);
+            oembed.height = Some(height);
+        }
+        ("audio", None) => {
+            let width = AUDIO_WIDTH.min(max_width);
+            oembed.kind = "rich";
+            oembed.html = Some(format!(
+                "<audio src=\"{}\" controls preload=\"metadata\" style=\"width: {}px\"></audio>",
+                escape(&raw),
+                width
+            ));
+            oembed.width = Some(width);
+            oembed.height = Some(AUDIO_HEIGHT);
+        }
+        _ => {}
+    }
+    Ok(Json(oembed))
+}
+
+/// Function that mounts the route of the oEmbed provider.
+/// - oembed (to describe a file to embed it).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("oEmbed stage", |rocket| async {
+        rocket.mount("/", routes![oembed])
+    })
+}
@@ -0,0 +1,422 @@
+use std::io;
+use std::net::IpAddr;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::http::uri::Origin;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::request::{self, FromRequest};
+use rocket::response::Redirect;
+use rocket::time::Duration;
+use rocket::tokio::task;
+use rocket::{Request, State};
+
+use rocket_db_pools::Connection;
+
+use sqlx::{AnyConnection, Row};
+
+use crate::access::FileAccess;
+use crate::storage::Storage;
+use crate::user::find_file;
+use crate::{Canard, FileId, RoxideError};
+
+/// Header that carries the password of a file, for the clients that do not keep cookies, and the
+/// password of a new paste.
+pub const PASSWORD_HEADER: &str = "X-File-Password";
+
+/// Time in second a file stays unlocked after its password is sent to `/unlock/<id>`.
+const UNLOCK_DURATION: i64 = 3600;
+
+/// Time in second during which the failed attempts to unlock a file are counted.
+const ATTEMPTS_WINDOW: i64 = 900;
+
+/// Failed attempts allowed to a client during the window, whatever the file.
+const MAX_ATTEMPTS_PER_IP: i64 = 10;
+
+/// Failed attempts allowed on a file during the window, whatever the client. Higher than the
+/// limit per client, so that a few clients cannot lock the legitimate users out of the file.
+const MAX_ATTEMPTS_PER_FILE: i64 = 50;
+
+/// Length of the ids of the attempts.
+const ATTEMPT_ID_LENGTH: usize = 20;
+
+/// Function that hashes the password of a file with Argon2.
+pub async fn hash_password(password: String) -> Result<String, RoxideError> {
+    let hash = task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+    })
+    .await
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+    Ok(hash)
+}
+
+/// Function that checks *password* against the Argon2 *hash* of the password of a file.
+async fn verify_password(hash: String, password: String) -> Result<bool, RoxideError> {
+    let valid = task::spawn_blocking(move || {
+        PasswordHash::new(&hash).map_or(false, |hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    Ok(valid)
+}
+
+/// Name of the cookie that unlocks the file *id*.
+fn cookie_name(id: &FileId) -> String {
+    format!("unlock-{}", id.get_id())
+}
+
+/// Request guard that gathers what can unlock a password-protected file: the password sent in
+/// the `X-File-Password` header, or the cookie set by `/unlock/<id>`.
+pub struct Unlock<'r> {
+    password: Option<&'r str>,
+    cookies: &'r CookieJar<'r>,
+    ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Unlock<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(Unlock {
+            password: req.headers().get_one(PASSWORD_HEADER),
+            cookies: req.cookies(),
+            ip: req.client_ip(),
+        })
+    }
+}
+
+impl Unlock<'_> {
+    /// Indicate if the cookie of the file *id* is still valid.
+    ///
+    /// The cookie is private (encrypted with the `secret_key` of Rocket), so its expiration date
+    /// cannot be forged.
+    fn has_cookie(&self, id: &FileId) -> bool {
+        let now = Utc::now().timestamp();
+        self.cookies
+            .get_private(&cookie_name(id))
+            .and_then(|cookie| cookie.value().parse::<i64>().ok())
+            .map_or(false, |expiration| expiration > now)
+    }
+
+    fn ip(&self) -> String {
+        self.ip
+            .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+    }
+}
+
+/// Function that checks that the file *id*, protected by the password *hash*, is unlocked by the
+/// request.
+pub async fn check_unlocked(
+    db: &mut AnyConnection,
+    id: &FileId,
+    hash: &str,
+    unlock: &Unlock<'_>,
+) -> Result<(), RoxideError> {
+    if unlock.has_cookie(id) {
+        return Ok(());
+    }
+    match unlock.password {
+        Some(password) => attempt(db, id, &unlock.ip(), hash, password).await,
+        None => Err(RoxideError::PasswordRequired),
+    }
+}
+
+/// Function that tries to unlock the file *id* with *password*.
+///
+/// The attempt is recorded before the password is checked, then the attempts of the client and
+/// the attempts on the file are counted: concurrent attempts see each other, so a client cannot
+/// fail more than `MAX_ATTEMPTS_PER_IP` times, and a file cannot be tried more than
+/// `MAX_ATTEMPTS_PER_FILE` times from rotating addresses, until the oldest attempts leave the
+/// window. The attempt is forgotten if the password is right, or if it is refused because of a
+/// limit. The clients that already unlocked the file keep their cookie, they are not limited.
+async fn attempt(
+    db: &mut AnyConnection,
+    id: &FileId,
+    ip: &str,
+    hash: &str,
+    password: &str,
+) -> Result<(), RoxideError> {
+    let now = Utc::now().timestamp();
+    let attempt = FileId::new(ATTEMPT_ID_LENGTH);
+    sqlx::query(
+        "INSERT INTO unlock_attempts (id, file_id, ip, attempt_date) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(attempt.get_id())
+    .bind(id.get_id())
+    .bind(ip)
+    .bind(now)
+    .execute(&mut *db)
+    .await?;
+    let attempts = sqlx::query(
+        "SELECT count(CASE WHEN ip = $1 THEN 1 END) AS ip_attempts, count(CASE WHEN file_id = $2 THEN 1 END) AS file_attempts FROM unlock_attempts WHERE (ip = $1 OR file_id = $2) AND attempt_date > $3",
+    )
+    .bind(ip)
+    .bind(id.get_id())
+    .bind(now - ATTEMPTS_WINDOW)
+    .fetch_one(&mut *db)
+    .await?;
+
+    let result = if attempts.get::<i64, &str>("ip_attempts") > MAX_ATTEMPTS_PER_IP
+        || attempts.get::<i64, &str>("file_attempts") > MAX_ATTEMPTS_PER_FILE
+    {
+        Err(RoxideError::TooManyAttempts)
+    } else if verify_password(hash.to_string(), password.to_string()).await? {
+        Ok(())
+    } else {
+        return Err(RoxideError::InvalidPassword);
+    };
+    sqlx::query("DELETE FROM unlock_attempts WHERE id = $1")
+        .bind(attempt.get_id())
+        .execute(&mut *db)
+        .await?;
+    result
+}
+
+/// Function that deletes the failed attempts that left the window.
+pub async fn clean_attempts(db: &mut AnyConnection) -> Result<(), RoxideError> {
+    let now = Utc::now().timestamp();
+    sqlx::query("DELETE FROM unlock_attempts WHERE attempt_date <= $1")
+        .bind(now - ATTEMPTS_WINDOW)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+//Structure use to receive the form that unlocks a file.
+#[derive(Debug, FromForm)]
+struct UnlockForm {
+    password: String,
+    preview: Option<bool>,
+}
+
+/// Function that unlocks a password-protected file for an hour, then redirects to the file, or
+/// to its preview page (`/f/<id>`) with the `preview` field. The query, like the signature of a
+/// share link, is kept.
+///
+/// The file stays unlocked with a private cookie, for the browsers. Other clients can send the
+/// password with each download in the `X-File-Password` header.
+///
+/// The file follows the rules of get before its password is tried: a file the requester cannot
+/// see, including a file that requires a signature without one, gets 404, and an expired file
+/// 410.
+#[post("/unlock/<id>", data = "<form>")]
+async fn unlock(
+    storage: &State<Storage>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    access: FileAccess<'_>,
+    origin: &Origin<'_>,
+    form: Form<UnlockForm>,
+) -> Result<Redirect, RoxideError> {
+    let row = match find_file(storage, &mut db, &id, &access).await {
+        Err(RoxideError::SignatureRequired) => return Err(RoxideError::NotFound),
+        result => result?,
+    };
+
+    let unlock = access.unlock();
+    if let Some(hash) = row
+        .get::<Option<&str>, &str>("password_hash")
+        .filter(|_| !unlock.has_cookie(&id))
+    {
+        attempt(&mut db, &id, &unlock.ip(), hash, &form.password).await?;
+        let expiration = Utc::now().timestamp() + UNLOCK_DURATION;
+        let cookie = Cookie::build(cookie_name(&id), expiration.to_string())
+            .path("/")
+            .same_site(SameSite::Lax)
+            .http_only(true)
+            .max_age(Duration::seconds(UNLOCK_DURATION))
+            .finish();
+        unlock.cookies.add_private(cookie);
+    }
+    let route = if form.preview.unwrap_or(false) {
+        "f"
+    } else {
+        "get"
+    };
+    Ok(Redirect::to(match origin.query() {
+        Some(query) => format!("/{}/{}?{}", route, id.get_id(), query.as_str()),
+        None => format!("/{}/{}", route, id.get_id()),
+    }))
+}
+
+/// Function that mounts the route that unlocks the password-protected files.
+/// - unlock (to unlock a password-protected file).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Password stage", |rocket| async {
+        rocket.mount("/", routes![unlock])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Params, Version};
+
+    use rocket::http::{ContentType, Status};
+    use rocket::tokio;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::test_client;
+    use crate::user::test_file;
+
+    /// Hash *password* with small Argon2 parameters, the tests check many passwords.
+    fn cheap_hash(password: &str) -> String {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string()
+    }
+
+    #[rocket::async_test]
+    async fn concurrent_wrong_passwords_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let hash = cheap_hash("right");
+
+        let attempts = (0..12)
+            .map(|_| {
+                let (pool, hash) = (pool.clone(), hash.clone());
+                tokio::spawn(async move {
+                    let mut db = pool.acquire().await.unwrap();
+                    attempt(&mut db, &FileId::from("file"), "1.2.3.4", &hash, "wrong").await
+                })
+            })
+            .collect::<Vec<_>>();
+        let (mut refused, mut limited) = (0, 0);
+        for attempt in attempts {
+            match attempt.await.unwrap() {
+                Err(RoxideError::InvalidPassword) => refused += 1,
+                Err(RoxideError::TooManyAttempts) => limited += 1,
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+        assert!(refused <= MAX_ATTEMPTS_PER_IP);
+        assert_eq!(refused + limited, 12);
+
+        // Even the right password waits for the window from this address
+        let mut db = pool.acquire().await.unwrap();
+        let right = attempt(&mut db, &FileId::from("file"), "1.2.3.4", &hash, "right").await;
+        assert!(matches!(right, Err(RoxideError::TooManyAttempts)));
+    }
+
+    #[rocket::async_test]
+    async fn other_addresses_can_still_unlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let (id, hash) = (FileId::from("file"), cheap_hash("right"));
+        for _ in 0..MAX_ATTEMPTS_PER_IP + 5 {
+            let _ = attempt(&mut db, &id, "1.2.3.4", &hash, "wrong").await;
+        }
+        attempt(&mut db, &id, "5.6.7.8", &hash, "right")
+            .await
+            .unwrap();
+
+        // A right password is not counted as a failed attempt
+        let attempts =
+            sqlx::query("SELECT count(1) AS attempts FROM unlock_attempts WHERE ip = '5.6.7.8'")
+                .fetch_one(&mut *db)
+                .await
+                .unwrap()
+                .get::<i64, &str>("attempts");
+        assert_eq!(attempts, 0);
+    }
+
+    #[rocket::async_test]
+    async fn rotating_addresses_are_limited_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let (id, hash) = (FileId::from("file"), cheap_hash("right"));
+        let (mut refused, mut limited) = (0, 0);
+        for n in 0..MAX_ATTEMPTS_PER_FILE + 5 {
+            let ip = format!("10.0.0.{}", n);
+            match attempt(&mut db, &id, &ip, &hash, "wrong").await {
+                Err(RoxideError::InvalidPassword) => refused += 1,
+                Err(RoxideError::TooManyAttempts) => limited += 1,
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+        assert_eq!((refused, limited), (MAX_ATTEMPTS_PER_FILE, 5));
+        let right = attempt(&mut db, &id, "10.0.1.1", &hash, "right").await;
+        assert!(matches!(right, Err(RoxideError::TooManyAttempts)));
+
+        // The other files are not locked
+        attempt(&mut db, &FileId::from("other"), "10.0.1.1", &hash, "right")
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn unlock_follows_the_rules_of_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let password = format!("password_hash = '{}'", cheap_hash("right"));
+        test_file(&mut db, "open", &password).await;
+        test_file(
+            &mut db,
+            "private",
+            &format!("{}, visibility = 'private'", password),
+        )
+        .await;
+        test_file(
+            &mut db,
+            "signed",
+            &format!("{}, require_signature = TRUE", password),
+        )
+        .await;
+        test_file(
+            &mut db,
+            "expired",
+            &format!("{}, expiration_date = 0", password),
+        )
+        .await;
+        let client = test_client(dir.path(), routes![unlock]).await;
+        let unlock = |id: &'static str, password: &'static str| {
+            let client = &client;
+            async move {
+                client
+                    .post(format!("/unlock/{}", id))
+                    .header(ContentType::Form)
+                    .body(format!("password={}", password))
+                    .dispatch()
+                    .await
+            }
+        };
+
+        assert_eq!(unlock("missing", "right").await.status(), Status::NotFound);
+        assert_eq!(unlock("private", "right").await.status(), Status::NotFound);
+        assert_eq!(unlock("signed", "right").await.status(), Status::NotFound);
+        assert_eq!(unlock("expired", "right").await.status(), Status::Gone);
+        assert_eq!(unlock("open", "wrong").await.status(), Status::Forbidden);
+        let response = unlock("open", "right").await;
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/get/open"));
+        assert!(response.cookies().get_private("unlock-open").is_some());
+
+        // Only the wrong password was counted
+        let attempts = sqlx::query("SELECT count(1) AS attempts FROM unlock_attempts")
+            .fetch_one(&mut *db)
+            .await
+            .unwrap()
+            .get::<i64, &str>("attempts");
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,361 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::fairing::AdHoc;
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::content::RawHtml;
+use rocket::response::{self, Redirect, Responder};
+use rocket::tokio::task;
+use rocket::{Request, State};
+
+use rocket_db_pools::Connection;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::access::FileAccess;
+use crate::hashed_file::HashedFile;
+use crate::html::{escape, format_size, page};
+use crate::password::PASSWORD_HEADER;
+use crate::storage::Storage;
+use crate::user::{
+    check_upload_rate, count_download, open_file, upload_file, OpenedFile, UploadSettings,
+};
+use crate::{is_token_valid, AppConfig, Canard, FileId, RoxideError};
+
+/// Theme of the highlighted pastes.
+const THEME: &str = "InspiredGitHub";
+
+/// Largest text shown by the viewer, the larger files are sent as they are.
+const MAX_VIEWED_SIZE: i64 = 4 * 1024 * 1024;
+
+/// Largest text highlighted by the viewer, the larger texts are shown without colors.
+const MAX_HIGHLIGHTED_SIZE: usize = 512 * 1024;
+
+/// Style of the viewer, the colors of the code come from the theme.
+const STYLE: &str = "body { font-family: sans-serif; margin: 1em; }
+.code { border-collapse: collapse; font-family: monospace; width: 100%; }
+.code td { padding: 0 0.5em; vertical-align: top; }
+.number { text-align: right; user-select: none; width: 1%; }
+.number a { color: #999; text-decoration: none; }
+.text { white-space: pre; }
+.selected { background: #fff8c5; }";
+
+/// Script of the viewer: it selects the lines of the anchor (`#L<line>` or `#L<from>-L<to>`),
+/// a click on a number with Shift selects the range from the selected line.
+const SCRIPT: &str = "function select() {
+  document.querySelectorAll('tr.selected').forEach(function (row) { row.classList.remove('selected'); });
+  var match = /^#L(\\d+)(?:-L(\\d+))?$/.exec(location.hash);
+  if (!match) return;
+  var from = +match[1], to = +(match[2] || match[1]);
+  if (from > to) { var first = to; to = from; from = first; }
+  for (var line = from; line <= to; line++) {
+    var row = document.getElementById('L' + line);
+    if (row) row.classList.add('selected');
+  }
+  var first = document.getElementById('L' + from);
+  if (first) first.scrollIntoView();
+}
+document.addEventListener('click', function (event) {
+  var link = event.target.closest('.number a');
+  var match = /^#L(\\d+)/.exec(location.hash);
+  if (!link || !event.shiftKey || !match) return;
+  event.preventDefault();
+  location.hash = '#L' + match[1] + '-' + link.hash.slice(1);
+});
+window.addEventListener('hashchange', select);
+select();";
+
+/// Syntaxes and theme used to highlight the pastes, loaded once.
+#[derive(Clone)]
+pub struct Highlighter {
+    syntaxes: Arc<SyntaxSet>,
+    theme: Arc<Theme>,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        let mut themes = ThemeSet::load_defaults().themes;
+        Self {
+            syntaxes: Arc::new(SyntaxSet::load_defaults_newlines()),
+            theme: Arc::new(themes.remove(THEME).unwrap_or_default()),
+        }
+    }
+
+    /// Check the language sent with a paste, a name or an extension known by the highlighter.
+    fn check_language(&self, language: &str) -> Result<(), RoxideError> {
+        self.syntaxes
+            .find_syntax_by_token(language)
+            .map(|_| ())
+            .ok_or(RoxideError::InvalidLanguage)
+    }
+
+    /// Find the syntax of a text: its language if any, else the extension of its title, else its
+    /// first line.
+    fn syntax(&self, language: Option<&str>, title: &str, text: &str) -> &SyntaxReference {
+        let extension = Path::new(title).extension().and_then(|ext| ext.to_str());
+        language
+            .and_then(|language| self.syntaxes.find_syntax_by_token(language))
+            .or_else(|| extension.and_then(|ext| self.syntaxes.find_syntax_by_extension(ext)))
+            .or_else(|| self.syntaxes.find_syntax_by_first_line(text))
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text())
+    }
+
+    /// Highlight *text* with *syntax*, return each line in HTML.
+    ///
+    /// A text too large, or that the syntax fails to parse, is only escaped.
+    fn lines(&self, syntax: &SyntaxReference, text: &str) -> Vec<String> {
+        if text.len() <= MAX_HIGHLIGHTED_SIZE {
+            let mut highlighter = HighlightLines::new(syntax, &self.theme);
+            let lines = LinesWithEndings::from(text)
+                .map(|line| {
+                    let regions = highlighter.highlight_line(line, &self.syntaxes)?;
+                    styled_line_to_highlighted_html(&regions, IncludeBackground::No)
+                })
+                .collect::<Result<Vec<_>, _>>();
+            match lines {
+                Ok(lines) => return lines,
+                Err(err) => eprintln!("Cannot highlight a paste: {:?}", err),
+            }
+        }
+        LinesWithEndings::from(text).map(escape).collect()
+    }
+
+    /// Colors of the background and of the text of the theme, in CSS.
+    fn colors(&self) -> String {
+        let css = |color: syntect::highlighting::Color| {
+            format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+        };
+        let settings = &self.theme.settings;
+        format!(
+            ".code {{ background: {}; color: {}; }}",
+            settings.background.map_or_else(|| "#fff".to_string(), css),
+            settings.foreground.map_or_else(|| "#000".to_string(), css)
+        )
+    }
+}
+
+//Structure use to receive the settings of a paste, in the query.
+#[derive(Debug, FromForm)]
+struct PasteSettings {
+    title: Option<String>,
+    language: Option<String>,
+    duration: Option<i64>,
+    visibility: Option<String>,
+    max_downloads: Option<i64>,
+    require_signature: Option<bool>,
+    collection: Option<String>,
+    tags: Vec<String>,
+}
+
+/// Request guard that gathers what a new paste needs besides its settings: the state of the
+/// server, where and up to which size the text is received, and the password of the paste, sent
+/// in the `X-File-Password` header to keep it out of the URL.
+struct PasteContext<'r> {
+    app_config: &'r AppConfig,
+    storage: &'r Storage,
+    highlighter: &'r Highlighter,
+    temp_dir: PathBuf,
+    /// The `paste` limit, `file` by default.
+    limit: ByteUnit,
+    password: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PasteContext<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let rocket = req.rocket();
+        match (
+            rocket.state::<AppConfig>(),
+            rocket.state::<Storage>(),
+            rocket.state::<Highlighter>(),
+        ) {
+            (Some(app_config), Some(storage), Some(highlighter)) => {
+                let limits = req.limits();
+                request::Outcome::Success(PasteContext {
+                    app_config,
+                    storage,
+                    highlighter,
+                    temp_dir: rocket.config().temp_dir.relative(),
+                    limit: limits
+                        .get("paste")
+                        .or_else(|| limits.get("file"))
+                        .unwrap_or(Limits::FILE),
+                    password: req.headers().get_one(PASSWORD_HEADER),
+                })
+            }
+            _ => request::Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// Function that uploads a text sent as the raw body of the request, and returns its id.
+///
+/// The text must be UTF-8, it is stored as `text/plain; charset=utf-8`. The settings are the
+/// fields of `/post/<token>`, sent in the query, with the language of the text (a name or an
+/// extension, like `rust` or `rs`). The body is limited by the `paste` limit (`file` by
+/// default), and is only received once the token and its number of uploads are checked.
+#[post("/paste/<token>?<settings..>", data = "<text>")]
+async fn paste(
+    context: PasteContext<'_>,
+    mut db: Connection<Canard>,
+    token: &str,
+    settings: PasteSettings,
+    text: Data<'_>,
+) -> Result<String, RoxideError> {
+    let app_config = context.app_config;
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    check_upload_rate(app_config, &mut db, token, Utc::now().timestamp()).await?;
+    if let Some(language) = &settings.language {
+        context.highlighter.check_language(language)?;
+    }
+    let text = HashedFile::receive(text, context.limit, &context.temp_dir)
+        .await?
+        .ok_or(RoxideError::TooLarge)?;
+    if !text.is_text().await? {
+        return Err(RoxideError::InvalidText);
+    }
+
+    let settings = UploadSettings {
+        title: settings.title.as_deref().unwrap_or(""),
+        duration: settings.duration,
+        unlisted: None,
+        visibility: settings.visibility.as_deref(),
+        max_downloads: settings.max_downloads,
+        password: context.password,
+        require_signature: settings.require_signature,
+        collection: settings.collection.as_deref(),
+        language: settings.language.as_deref(),
+        paste: true,
+        tags: &settings.tags,
+    };
+    let (id, _) = upload_file(
+        app_config,
+        context.storage,
+        &mut db,
+        token,
+        &settings,
+        &text,
+    )
+    .await?;
+    Ok(id.get_id().to_string())
+}
+
+/// View of a file, a page for the texts and the file itself otherwise.
+enum PasteView {
+    Page(RawHtml<String>),
+    File(Box<Redirect>),
+}
+
+impl<'r> Responder<'r, 'static> for PasteView {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            PasteView::Page(page) => page.respond_to(req),
+            PasteView::File(redirect) => redirect.respond_to(req),
+        }
+    }
+}
+
+/// Build the page of the highlighted text of *file*, in *language*.
+fn view_page(
+    id: &FileId,
+    file: &OpenedFile,
+    language: &str,
+    raw: &str,
+    lines: &[String],
+    colors: &str,
+) -> RawHtml<String> {
+    let title = match file.title.as_str() {
+        "" => id.get_id(),
+        title => title,
+    };
+    let mut body = format!(
+        "<h1>{}</h1>\n<p>{}, {}. <a href=\"{}\">Raw</a></p>\n<table class=\"code\"><tbody>\n",
+        escape(title),
+        escape(language),
+        format_size(file.size),
+        escape(raw)
+    );
+    for (number, line) in lines.iter().enumerate() {
+        body.push_str(&format!(
+            "<tr id=\"L{0}\"><td class=\"number\"><a href=\"#L{0}\">{0}</a></td><td class=\"text\">{1}</td></tr>\n",
+            number + 1,
+            line
+        ));
+    }
+    body.push_str(&format!(
+        "</tbody></table>\n<script>\n{}\n</script>\n",
+        SCRIPT
+    ));
+    let head = format!("<style>\n{}\n{}\n</style>\n", STYLE, colors);
+    page(title, &head, &body)
+}
+
+/// Function that shows a text with syntax highlighting, line numbers and anchors to the lines
+/// (`#L<line>`) or to ranges of lines (`#L<from>-L<to>`).
+///
+/// The file follows the rules of `/get/<id>`, and the view counts as a download. The files that
+/// are not text, and the texts too large to be shown, are redirected to `/get/<id>`.
+#[get("/p/<id>")]
+async fn view(
+    storage: &State<Storage>,
+    highlighter: &State<Highlighter>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    access: FileAccess<'_>,
+    origin: &Origin<'_>,
+) -> Result<PasteView, RoxideError> {
+    // Keep the signature of a share link for the raw file
+    let raw = match origin.query() {
+        Some(query) => format!("/get/{}?{}", id.get_id(), query.as_str()),
+        None => format!("/get/{}", id.get_id()),
+    };
+    let file = open_file(storage, &mut db, &id, &access).await?;
+    if !file.content_type.starts_with("text/") || file.size > MAX_VIEWED_SIZE {
+        return Ok(PasteView::File(Box::new(Redirect::to(raw))));
+    }
+    let content = storage.get(&file.digest).await?;
+    count_download(storage, &mut db, &id, &file).await?;
+
+    let highlighter = Highlighter::clone(highlighter);
+    let page = task::spawn_blocking(move || {
+        let text = String::from_utf8_lossy(&content);
+        let syntax = highlighter.syntax(file.language.as_deref(), &file.title, &text);
+        let lines = highlighter.lines(syntax, &text);
+        view_page(
+            &id,
+            &file,
+            &syntax.name,
+            &raw,
+            &lines,
+            &highlighter.colors(),
+        )
+    })
+    .await
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    Ok(PasteView::Page(page))
+}
+
+/// Function that mounts the routes of the pastes.
+/// - paste (to upload a text).
+/// - view (to show a highlighted text).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Paste stage", |rocket| async {
+        rocket
+            .manage(Highlighter::new())
+            .mount("/", routes![paste, view])
+    })
+}
@@ -0,0 +1,188 @@
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::response::content::RawHtml;
+use rocket::State;
+
+use rocket_db_pools::Connection;
+
+use crate::access::FileAccess;
+use crate::html::{encode_query, escape, format_date, format_duration, format_size, page, BaseUrl};
+use crate::rendition::RenditionRequest;
+use crate::storage::Storage;
+use crate::user::{open_file, OpenedFile};
+use crate::{Canard, FileId, RoxideError};
+
+/// Build the page that asks the password of a protected file, it unlocks the file and comes back
+/// to its preview.
+fn locked_page(id: &FileId, query: &str) -> RawHtml<String> {
+    let body = format!(
+        "<h1>Protected file</h1>\n<p>This file is protected by a password.</p>\n<form method=\"post\" action=\"/unlock/{}{}\">\n<input type=\"hidden\" name=\"preview\" value=\"true\">\n<input type=\"password\" name=\"password\" autofocus required>\n<button>Unlock</button>\n</form>\n",
+        id.get_id(),
+        escape(query)
+    );
+    page(
+        "Protected file",
+        "<meta name=\"robots\" content=\"noindex\">\n",
+        &body,
+    )
+}
+
+/// Build the preview page of *file*.
+///
+/// *query* is the query of the request, kept in the links to the file so that a share link
+/// gives access to them too. The tags of the previews need the absolute URL of the server
+/// *base*, they are left out without it.
+fn preview_page(
+    base: Option<&str>,
+    id: &FileId,
+    query: &str,
+    file: &OpenedFile,
+) -> RawHtml<String> {
+    let title = match file.title.as_str() {
+        "" => id.get_id(),
+        title => title,
+    };
+    let raw = escape(&format!("/get/{}{}", id.get_id(), query));
+    let now = Utc::now().timestamp();
+    let expiration = match format_date(file.expiration_date) {
+        date if date.is_empty() => "never".to_string(),
+        date => format!(
+            "in {} ({})",
+            format_duration(file.expiration_date - now),
+            date
+        ),
+    };
+    let downloads = match file.max_downloads {
+        Some(max_downloads) => format!("{} of {}", file.download_count, max_downloads),
+        None => file.download_count.to_string(),
+    };
+    let description = format!(
+        "{}, {}, expires {}.",
+        format_size(file.size),
+        file.content_type,
+        expiration
+    );
+
+    // Showing the content of a limited file would use its downloads
+    let kind = file.content_type.split('/').next().unwrap_or("");
+    let embed = match (kind, file.max_downloads) {
+        (_, Some(_)) => String::new(),
+        ("image", None) => format!("<p><img src=\"{}\" alt=\"{}\"></p>\n", raw, escape(title)),
+        ("video", None) => format!(
+            "<p><video src=\"{}\" controls preload=\"metadata\"></video></p>\n",
+            raw
+        ),
+        ("audio", None) => format!(
+            "<p><audio src=\"{}\" controls preload=\"metadata\"></audio></p>\n",
+            raw
+        ),
+        _ => String::new(),
+    };
+    let text_link = match kind {
+        "text" => format!(
+            " <a href=\"{}\">View as text</a>",
+            escape(&format!("/p/{}{}", id.get_id(), query))
+        ),
+        _ => String::new(),
+    };
+    let body = format!(
+        "<h1>{}</h1>\n{}<ul>\n<li>Size: {}</li>\n<li>Type: {}</li>\n<li>Uploaded: {}</li>\n<li>Expires: {}</li>\n<li>Downloads: {}</li>\n</ul>\n<p><a href=\"{}\" download>Download</a>{}</p>\n",
+        escape(title),
+        embed,
+        format_size(file.size),
+        escape(&file.content_type),
+        format_date(file.upload_date),
+        escape(&expiration),
+        downloads,
+        raw,
+        text_link
+    );
+
+    let mut head = match base {
+        Some(base) => preview_tags(base, id, query, file, title, &description),
+        None => String::new(),
+    };
+    head.push_str("<style>\nimg, video { max-width: 100%; }\n</style>\n");
+    page(title, &head, &body)
+}
+
+/// Build the OpenGraph and Twitter card tags of *file*, and the link to its oEmbed description,
+/// with the absolute URL of the server *base*.
+fn preview_tags(
+    base: &str,
+    id: &FileId,
+    query: &str,
+    file: &OpenedFile,
+    title: &str,
+    description: &str,
+) -> String {
+    // The thumbnail of an image, unless it uses the downloads of the file
+    let thumbnail = RenditionRequest::thumbnail()
+        .resolve(&file.content_type)
+        .ok()
+        .filter(|_| file.max_downloads.is_none())
+        .map(|_| escape(&format!("{}/thumbnail/{}{}", base, id.get_id(), query)));
+    let url = format!("{}/f/{}{}", base, id.get_id(), query);
+    let mut head = format!(
+        "<link rel=\"alternate\" type=\"application/json+oembed\" href=\"{3}\" title=\"{0}\">\n<meta property=\"og:title\" content=\"{0}\">\n<meta property=\"og:type\" content=\"website\">\n<meta property=\"og:url\" content=\"{1}\">\n<meta property=\"og:description\" content=\"{2}\">\n<meta name=\"twitter:title\" content=\"{0}\">\n<meta name=\"twitter:description\" content=\"{2}\">\n",
+        escape(title),
+        escape(&url),
+        escape(description),
+        escape(&format!("{}/oembed?url={}", base, encode_query(&url)))
+    );
+    match thumbnail {
+        Some(thumbnail) => head.push_str(&format!(
+            "<meta property=\"og:image\" content=\"{0}\">\n<meta name=\"twitter:card\" content=\"summary_large_image\">\n<meta name=\"twitter:image\" content=\"{0}\">\n",
+            thumbnail
+        )),
+        None => head.push_str("<meta name=\"twitter:card\" content=\"summary\">\n"),
+    }
+    head
+}
+
+/// Function that shows the preview page of a file: its title, size, content type, upload date,
+/// expiration and number of downloads, with the image, video or audio embedded. When `public_url`
+/// is configured, the page has OpenGraph and Twitter card tags, so that the links show a preview
+/// in chats, with the thumbnail of the images, and links to its oEmbed description (`/oembed`).
+///
+/// The file follows the rules of `/get/<id>`, except that the page does not count as a download.
+/// For a password-protected file, the page asks the password. The content of a file whose number
+/// of downloads is limited is not embedded. `/get/<id>` stays the raw file.
+#[get("/f/<id>")]
+async fn preview(
+    storage: &State<Storage>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    access: FileAccess<'_>,
+    origin: &Origin<'_>,
+    base: Option<BaseUrl>,
+) -> Result<(Status, RawHtml<String>), RoxideError> {
+    // Keep the signature of a share link in the links to the file
+    let query = origin
+        .query()
+        .map_or_else(String::new, |query| format!("?{}", query.as_str()));
+    match open_file(storage, &mut db, &id, &access).await {
+        Ok(file) => Ok((
+            Status::Ok,
+            preview_page(
+                base.as_ref().map(|base| base.0.as_str()),
+                &id,
+                &query,
+                &file,
+            ),
+        )),
+        Err(RoxideError::PasswordRequired) => Ok((Status::Unauthorized, locked_page(&id, &query))),
+        Err(err) => Err(err),
+    }
+}
+
+/// Function that mounts the route of the preview pages.
+/// - preview (to show the preview page of a file).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Preview stage", |rocket| async {
+        rocket.mount("/", routes![preview])
+    })
+}
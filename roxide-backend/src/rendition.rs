@@ -0,0 +1,468 @@
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, ImageError, ImageFormat, ImageOutputFormat};
+
+use rocket::http::ContentType;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::sync::Semaphore;
+use rocket::tokio::{fs, task};
+
+use sqlx::AnyConnection;
+
+use crate::storage::{LocalStorage, Storage, StorageBackend};
+use crate::{FileId, RoxideError};
+
+/// Largest width or height of a rendition.
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// Widths and heights of the renditions, the requested sizes are rounded up to one of them.
+const SIZES: [u32; 16] = [
+    16,
+    32,
+    48,
+    64,
+    96,
+    128,
+    256,
+    320,
+    480,
+    640,
+    800,
+    1024,
+    1280,
+    1920,
+    2560,
+    MAX_DIMENSION,
+];
+
+/// Largest number of renditions of a blob.
+const MAX_RENDITIONS: usize = 32;
+
+/// Largest number of images rendered at the same time, the other renders wait.
+const MAX_CONCURRENT_RENDERS: usize = 4;
+
+/// Largest width or height of an image that can be resized.
+const MAX_SOURCE_DIMENSION: u32 = 16384;
+
+/// Largest size of an image that can be resized, larger files are not loaded in memory.
+const MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Width and height of the box the thumbnails fit in.
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Size of the beginning of an image read to find its dimensions.
+const HEADER_SIZE: u64 = 1024 * 1024;
+
+/// Quality of the JPEG renditions.
+const JPEG_QUALITY: u8 = 85;
+
+/// How an image is resized to a box of a different aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum Fit {
+    /// Scale the image to fit in the box, keeping its aspect ratio.
+    Contain,
+    /// Scale and crop the image to fill the box, keeping its aspect ratio.
+    Cover,
+    /// Stretch the image to the box.
+    Fill,
+}
+
+/// Formats in which the renditions can be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum Format {
+    Png,
+    #[field(value = "jpeg")]
+    #[field(value = "jpg")]
+    Jpeg,
+    Webp,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpeg",
+            Format::Webp => "webp",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            Format::Png => ContentType::PNG,
+            Format::Jpeg => ContentType::JPEG,
+            Format::Webp => ContentType::WEBP,
+        }
+    }
+}
+
+/// Query of a download that asks for a resized or re-encoded image
+/// (`?w=<width>&h=<height>&fit=<fit>&format=<format>`).
+#[derive(Debug, Default, FromForm)]
+pub struct RenditionRequest {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<Fit>,
+    format: Option<Format>,
+}
+
+impl RenditionRequest {
+    /// The rendition used as thumbnail.
+    pub fn thumbnail() -> Self {
+        Self {
+            w: Some(THUMBNAIL_SIZE),
+            h: Some(THUMBNAIL_SIZE),
+            fit: Some(Fit::Contain),
+            format: None,
+        }
+    }
+
+    /// Indicate if the query asks for the original file.
+    pub fn is_original(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.fit.is_none() && self.format.is_none()
+    }
+
+    /// Check the query for a file of *content_type* and apply the defaults: the image keeps its
+    /// size, its aspect ratio and its format (PNG if it cannot be encoded). The width and the
+    /// height are rounded up to one of `SIZES`.
+    pub fn resolve(&self, content_type: &str) -> Result<Rendition, RoxideError> {
+        let source = ImageFormat::from_mime_type(content_type)
+            .filter(|format| format.can_read())
+            .ok_or(RoxideError::NotAnImage)?;
+        let dimensions = [self.w, self.h];
+        if dimensions
+            .iter()
+            .flatten()
+            .any(|&dimension| dimension == 0 || dimension > MAX_DIMENSION)
+        {
+            return Err(RoxideError::InvalidSize);
+        }
+        let format = self.format.unwrap_or(match source {
+            ImageFormat::Jpeg => Format::Jpeg,
+            ImageFormat::WebP => Format::Webp,
+            _ => Format::Png,
+        });
+        Ok(Rendition {
+            source,
+            width: self.w.map(size_above),
+            height: self.h.map(size_above),
+            fit: self.fit.unwrap_or(Fit::Contain),
+            format,
+        })
+    }
+}
+
+/// A resized or re-encoded version of an image.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    source: ImageFormat,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    format: Format,
+}
+
+impl Rendition {
+    /// Name of the rendition, unique for each set of parameters.
+    pub fn name(&self) -> String {
+        let dimension = |dimension: Option<u32>| {
+            dimension.map_or_else(|| "auto".to_string(), |dimension| dimension.to_string())
+        };
+        format!(
+            "{}x{}-{:?}.{}",
+            dimension(self.width),
+            dimension(self.height),
+            self.fit,
+            self.format.extension()
+        )
+        .to_lowercase()
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.format.content_type()
+    }
+
+    /// Decode the image *content*, resize it and encode it.
+    fn render(&self, content: &[u8]) -> Result<Vec<u8>, RoxideError> {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+        limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+        let mut reader = Reader::with_format(Cursor::new(content), self.source);
+        reader.limits(limits);
+        let image = reader.decode().map_err(|err| match err {
+            ImageError::Limits(_) => RoxideError::ImageTooLarge,
+            _ => RoxideError::NotAnImage,
+        })?;
+
+        // A missing dimension follows the aspect ratio of the image
+        let (width, height) = match (self.width, self.height) {
+            (None, None) => (image.width(), image.height()),
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scale(image.height(), width, image.width())),
+            (None, Some(height)) => (scale(image.width(), height, image.height()), height),
+        };
+        let image = if (width, height) == (image.width(), image.height()) {
+            image
+        } else {
+            match self.fit {
+                Fit::Contain => image.resize(width, height, FilterType::CatmullRom),
+                Fit::Cover => image.resize_to_fill(width, height, FilterType::CatmullRom),
+                Fit::Fill => image.resize_exact(width, height, FilterType::CatmullRom),
+            }
+        };
+
+        let (image, output) = match self.format {
+            Format::Png => (image, ImageOutputFormat::Png),
+            Format::Jpeg => (
+                DynamicImage::ImageRgb8(image.to_rgb8()),
+                ImageOutputFormat::Jpeg(JPEG_QUALITY),
+            ),
+            Format::Webp => (
+                DynamicImage::ImageRgba8(image.to_rgba8()),
+                ImageOutputFormat::WebP,
+            ),
+        };
+        let mut encoded = Cursor::new(Vec::new());
+        image
+            .write_to(&mut encoded, output)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(encoded.into_inner())
+    }
+}
+
+/// Smallest of `SIZES` that is at least *dimension*, which is at most `MAX_DIMENSION`.
+fn size_above(dimension: u32) -> u32 {
+    SIZES
+        .into_iter()
+        .find(|&size| size >= dimension)
+        .unwrap_or(MAX_DIMENSION)
+}
+
+/// Largest of `SIZES` that is at most *dimension*, the smallest size if there is none.
+pub fn size_below(dimension: u32) -> u32 {
+    SIZES
+        .into_iter()
+        .rev()
+        .find(|&size| size <= dimension)
+        .unwrap_or(SIZES[0])
+}
+
+/// Scale *side* by *to* / *from*.
+fn scale(side: u32, to: u32, from: u32) -> u32 {
+    let scaled = (side as u64 * to as u64 + from as u64 / 2) / from.max(1) as u64;
+    scaled.clamp(1, MAX_DIMENSION as u64) as u32
+}
+
+/// Dimensions of an image of *width* x *height* scaled to fit in a box of *box_width* x
+/// *box_height*, like the `contain` fit.
+pub fn contained_dimensions(
+    width: u32,
+    height: u32,
+    box_width: u32,
+    box_height: u32,
+) -> (u32, u32) {
+    if width as u64 * box_height as u64 > height as u64 * box_width as u64 {
+        (box_width, scale(height, box_width, width))
+    } else {
+        (scale(width, box_height, height), box_height)
+    }
+}
+
+/// Dimensions of the thumbnail of an image of *width* x *height*.
+pub fn thumbnail_dimensions(width: u32, height: u32) -> (u32, u32) {
+    contained_dimensions(width, height, THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+}
+
+/// Function that reads the width and the height of the image *digest* of *content_type*, from
+/// the beginning of its content.
+pub async fn image_dimensions(
+    storage: &Storage,
+    digest: &str,
+    content_type: &str,
+) -> Result<(u32, u32), RoxideError> {
+    let format = ImageFormat::from_mime_type(content_type)
+        .filter(|format| format.can_read())
+        .ok_or(RoxideError::NotAnImage)?;
+    let mut header = Vec::new();
+    storage
+        .stream(digest, None)
+        .await?
+        .take(HEADER_SIZE)
+        .read_to_end(&mut header)
+        .await?;
+    Reader::with_format(Cursor::new(header), format)
+        .into_dimensions()
+        .map_err(|_| RoxideError::NotAnImage)
+}
+
+/// Cache of the renditions, on the disk.
+///
+/// The renditions of a blob are stored in the directory named after its digest, so they can be
+/// deleted with the blob. A blob has at most `MAX_RENDITIONS` renditions, and at most
+/// `MAX_CONCURRENT_RENDERS` images are rendered at the same time.
+#[derive(Clone)]
+pub struct RenditionCache {
+    root: PathBuf,
+    storage: LocalStorage,
+    renders: Arc<Semaphore>,
+}
+
+impl RenditionCache {
+    pub fn new(upload_directory: &str) -> Self {
+        let root = Path::new(upload_directory).join("renditions");
+        Self {
+            storage: LocalStorage::new(&root.to_string_lossy()),
+            root,
+            renders: Arc::new(Semaphore::new(MAX_CONCURRENT_RENDERS)),
+        }
+    }
+
+    /// Storage of the renditions, to read them.
+    pub fn storage(&self) -> &LocalStorage {
+        &self.storage
+    }
+
+    /// Count the renditions of the blob *digest* in the cache.
+    async fn count(&self, digest: &str) -> io::Result<usize> {
+        let mut entries = match fs::read_dir(self.root.join(digest)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut count = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_name().to_string_lossy().ends_with(".part") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Return the key of the *rendition* of the blob *digest* in the storage of the renditions,
+    /// rendering it if it is not in the cache.
+    pub async fn render(
+        &self,
+        storage: &Storage,
+        digest: &str,
+        rendition: &Rendition,
+    ) -> Result<String, RoxideError> {
+        let key = format!("{}/{}", digest, rendition.name());
+        if self.storage.exists(&key).await? {
+            return Ok(key);
+        }
+
+        // The rendition may have been rendered while the permit was awaited
+        let _permit = self
+            .renders
+            .acquire()
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        if self.storage.exists(&key).await? {
+            return Ok(key);
+        }
+        if self.count(digest).await? >= MAX_RENDITIONS {
+            return Err(RoxideError::TooManyRenditions);
+        }
+
+        if storage.stat(digest).await?.size > MAX_SOURCE_SIZE {
+            return Err(RoxideError::ImageTooLarge);
+        }
+        let content = storage.get(digest).await?;
+        let task_rendition = rendition.clone();
+        let rendered = task::spawn_blocking(move || task_rendition.render(&content))
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+
+        // Written aside then renamed, so that a concurrent request never reads a partial rendition
+        let directory = self.root.join(digest);
+        fs::create_dir_all(&directory).await?;
+        let partial = directory.join(format!(
+            "{}.{}.part",
+            rendition.name(),
+            FileId::new(8).get_id()
+        ));
+        fs::write(&partial, rendered).await?;
+        fs::rename(&partial, self.root.join(&key)).await?;
+        Ok(key)
+    }
+
+    /// Delete the renditions of the blobs that do not exist anymore.
+    ///
+    /// Return the digests of these blobs.
+    pub async fn remove_orphans(&self, db: &mut AnyConnection) -> Result<Vec<String>, RoxideError> {
+        let mut entries = match fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut removed = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let digest = entry.file_name().to_string_lossy().into_owned();
+            let exists = sqlx::query("SELECT digest FROM blobs WHERE digest = $1")
+                .bind(&digest)
+                .fetch_optional(&mut *db)
+                .await?
+                .is_some();
+            if exists {
+                continue;
+            }
+            match fs::remove_dir_all(entry.path()).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    eprintln!("Cannot delete the renditions of {}: {:?}", digest, err)
+                }
+                _ => removed.push(digest),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_rounded_to_the_rendition_sizes() {
+        let request = RenditionRequest {
+            w: Some(700),
+            h: Some(4000),
+            ..Default::default()
+        };
+        let rendition = request.resolve("image/png").unwrap();
+        assert_eq!((rendition.width, rendition.height), (Some(800), Some(4096)));
+        assert_eq!(size_above(256), 256);
+        assert_eq!(size_below(700), 640);
+        assert_eq!(size_below(5), 16);
+    }
+
+    #[rocket::async_test]
+    async fn renditions_of_a_blob_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Storage = Arc::new(LocalStorage::new(dir.path().to_str().unwrap()));
+        let cache = RenditionCache::new(dir.path().to_str().unwrap());
+        let directory = dir.path().join("renditions").join("digest");
+        std::fs::create_dir_all(&directory).unwrap();
+        for n in 0..MAX_RENDITIONS {
+            std::fs::write(directory.join(format!("{}x16-contain.png", n)), b"").unwrap();
+        }
+
+        let cached = Rendition {
+            width: Some(0),
+            height: Some(16),
+            ..RenditionRequest::thumbnail().resolve("image/png").unwrap()
+        };
+        assert_eq!(
+            cache.render(&storage, "digest", &cached).await.unwrap(),
+            "digest/0x16-contain.png"
+        );
+        let new = RenditionRequest::thumbnail().resolve("image/png").unwrap();
+        assert!(matches!(
+            cache.render(&storage, "digest", &new).await,
+            Err(RoxideError::TooManyRenditions)
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::{Digest, Sha256};
+
+use rocket::futures::TryStreamExt;
+use rocket::serde::Deserialize;
+use rocket::tokio::fs::File;
+
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::storage::{ObjectReader, ObjectStat, StorageBackend};
+
+/// Payload hash sent to the object store, the content of the objects is not signed.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Configuration of an S3-compatible object store (AWS S3, MinIO, ...).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct S3Config {
+    /// Base url of the object store, e.g. `http://127.0.0.1:9000` or
+    /// `https://s3.eu-west-3.amazonaws.com`.
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+/// Storage that keeps files in a bucket of an S3-compatible object store.
+///
+/// Requests are addressed path-style (`<endpoint>/<bucket>/<key>`) and signed with AWS
+/// Signature Version 4.
+pub struct S3Storage {
+    config: S3Config,
+    host: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Self {
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint, |(_, host)| host)
+            .to_string();
+        Self {
+            config: S3Config {
+                endpoint: endpoint.to_string(),
+                ..config.clone()
+            },
+            host,
+            // Both plain HTTP and HTTPS, with the Mozilla root certificates
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+        }
+    }
+
+    /// Build, sign and send a request on the object *key*.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Body,
+        headers: Vec<(&str, String)>,
+    ) -> io::Result<Response<Body>> {
+        let path = format!("/{}/{}", self.config.bucket, key);
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, UNSIGNED_PAYLOAD, amz_date, signed_headers, UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let secret = format!("AWS4{}", self.config.secret_key);
+        let key_date = hmac_sha256(secret.as_bytes(), date.as_bytes());
+        let key_region = hmac_sha256(&key_date, self.config.region.as_bytes());
+        let key_service = hmac_sha256(&key_region, b"s3");
+        let key_signing = hmac_sha256(&key_service, b"aws4_request");
+        let signature = hex::encode(hmac_sha256(&key_signing, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.config.endpoint, path))
+            .header("host", &self.host)
+            .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let request = request
+            .body(body)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        self.client
+            .request(request)
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+/// Compute the HMAC-SHA256 of *data* with *key*.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Turn an unexpected answer of the object store into an error.
+fn status_error(key: &str, status: StatusCode) -> io::Error {
+    if status == StatusCode::NOT_FOUND {
+        io::Error::new(io::ErrorKind::NotFound, format!("object {} not found", key))
+    } else {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("object store answered {} for {}", status, key),
+        )
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Storage {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let file = File::open(source).await?;
+        let size = file.metadata().await?.len();
+        let body = Body::wrap_stream(ReaderStream::new(file));
+        let response = self
+            .send(
+                Method::PUT,
+                key,
+                body,
+                vec![("content-length", size.to_string())],
+            )
+            .await?;
+        if !response.status().is_success() {
+            return Err(status_error(key, response.status()));
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let response = self.send(Method::GET, key, Body::empty(), vec![]).await?;
+        if !response.status().is_success() {
+            return Err(status_error(key, response.status()));
+        }
+        let bytes = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let response = self
+            .send(Method::DELETE, key, Body::empty(), vec![])
+            .await?;
+        if !response.status().is_success() {
+            return Err(status_error(key, response.status()));
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.stat(key).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<ObjectStat> {
+        let response = self.send(Method::HEAD, key, Body::empty(), vec![]).await?;
+        if !response.status().is_success() {
+            return Err(status_error(key, response.status()));
+        }
+        let size = response
+            .headers()
+            .get("content-length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        Ok(ObjectStat { size })
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ObjectReader> {
+        let headers = match range {
+            Some(range) => vec![("range", format!("bytes={}-{}", range.start, range.end - 1))],
+            None => vec![],
+        };
+        let response = self.send(Method::GET, key, Body::empty(), headers).await?;
+        if !response.status().is_success() {
+            return Err(status_error(key, response.status()));
+        }
+        let body = response
+            .into_body()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        Ok(Box::pin(StreamReader::new(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::io::AsyncReadExt;
+    use rocket::tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn first_byte(scheme: &str) -> u8 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let storage = S3Storage::new(&S3Config {
+            endpoint: format!("{}://{}", scheme, listener.local_addr().unwrap()),
+            bucket: "roxide".into(),
+            region: "us-east-1".into(),
+            access_key: "access".into(),
+            secret_key: "secret".into(),
+        });
+        let request = rocket::tokio::spawn(async move { storage.exists("key").await });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let byte = stream.read_u8().await.unwrap();
+        request.abort();
+        byte
+    }
+
+    #[rocket::async_test]
+    async fn https_endpoints_use_tls() {
+        // A TLS handshake record, then the method of a plain HTTP request
+        assert_eq!(first_byte("https").await, 0x16);
+        assert_eq!(first_byte("http").await, b'H');
+    }
+}
@@ -0,0 +1,519 @@
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+
+use rocket::serde::Deserialize;
+use rocket::tokio::{fs, task};
+
+use crate::hashed_file::HashedFile;
+
+/// Largest image that is sanitized, larger files are not loaded in memory.
+const MAX_SANITIZED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Quality of the JPEG images re-encoded after a rotation.
+const JPEG_QUALITY: u8 = 90;
+
+/// Configuration of the removal of the metadata of the uploaded images (EXIF, XMP, IPTC, text
+/// chunks, ...), which can leak the location of a photo or the serial number of a camera.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SanitizeConfig {
+    /// Remove the metadata of the images uploaded with any token.
+    #[serde(default)]
+    enabled: bool,
+    /// Tokens whose images have their metadata removed, even if it is not enabled for all tokens.
+    #[serde(default)]
+    tokens: Vec<String>,
+    /// Rotate the images according to their EXIF orientation before removing it. The image is
+    /// then re-encoded.
+    #[serde(default)]
+    auto_rotate: bool,
+}
+
+impl SanitizeConfig {
+    /// Indicate if the images uploaded with *token* have their metadata removed.
+    pub fn applies_to(&self, token: &str) -> bool {
+        self.enabled || self.tokens.iter().any(|t| t == token)
+    }
+}
+
+/// Remove the metadata of the image *upload* of *content_type*.
+///
+/// Return the sanitized image, with its new digest and size, or None if the file is not a
+/// supported image (JPEG, PNG or WebP) or has no metadata.
+pub async fn sanitize(
+    config: &SanitizeConfig,
+    upload: &HashedFile,
+    content_type: &str,
+) -> io::Result<Option<HashedFile>> {
+    let format = match ImageFormat::from_mime_type(content_type) {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => format,
+        _ => return Ok(None),
+    };
+    if upload.size() > MAX_SANITIZED_SIZE {
+        return Ok(None);
+    }
+
+    let content = fs::read(upload.path()).await?;
+    let auto_rotate = config.auto_rotate;
+    let sanitized = task::spawn_blocking(move || strip(&content, format, auto_rotate))
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+    let sanitized = match sanitized {
+        Some(sanitized) => sanitized,
+        None => return Ok(None),
+    };
+
+    let mut path = PathBuf::from(upload.path());
+    path.set_extension("sanitized");
+    fs::write(&path, sanitized).await?;
+    HashedFile::from_path(path).await.map(Some)
+}
+
+/// Remove the metadata of *content*, and apply its orientation if *auto_rotate*.
+///
+/// Return None if there is nothing to remove.
+fn strip(content: &[u8], format: ImageFormat, auto_rotate: bool) -> io::Result<Option<Vec<u8>>> {
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(content),
+        ImageFormat::Png => strip_png(content),
+        _ => strip_webp(content),
+    };
+    let Stripped { content, exif } = match stripped {
+        Some(stripped) => stripped,
+        None => return Ok(None),
+    };
+
+    let orientation = exif.as_deref().and_then(orientation).unwrap_or(1);
+    if !auto_rotate || orientation == 1 {
+        return Ok(Some(content));
+    }
+    rotate(&content, format, orientation).map(Some)
+}
+
+/// An image without its metadata.
+struct Stripped {
+    content: Vec<u8>,
+    /// The EXIF data of the image (a TIFF structure), to read the orientation.
+    exif: Option<Vec<u8>>,
+}
+
+/// Remove the metadata segments of a JPEG image: EXIF and XMP (APP1), IPTC (APP13), the
+/// segments of the camera makers (APP3 to APP12, APP15) and the comments. JFIF (APP0), the color
+/// profile (APP2) and the Adobe segment (APP14) are kept.
+///
+/// Return None if the image is malformed or has no metadata.
+fn strip_jpeg(content: &[u8]) -> Option<Stripped> {
+    if !content.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut output = content[..2].to_vec();
+    let mut exif = None;
+    let mut removed = false;
+    let mut position = 2;
+    loop {
+        let marker = *content.get(position + 1)?;
+        if content[position] != 0xFF {
+            return None;
+        }
+        // Fill byte before a marker
+        if marker == 0xFF {
+            output.push(0xFF);
+            position += 1;
+            continue;
+        }
+        // Markers without payload
+        if marker == 0x01 || (0xD0..=0xD8).contains(&marker) {
+            output.extend_from_slice(&content[position..position + 2]);
+            position += 2;
+            continue;
+        }
+        // End of image, or start of the compressed data, which is kept as is
+        if marker == 0xD9 || marker == 0xDA {
+            output.extend_from_slice(&content[position..]);
+            break;
+        }
+        let length = u16::from_be_bytes([*content.get(position + 2)?, *content.get(position + 3)?]);
+        if length < 2 {
+            return None;
+        }
+        let end = position + 2 + length as usize;
+        let segment = content.get(position..end)?;
+        let payload = &segment[4..];
+        let metadata = matches!(marker, 0xE1 | 0xE3..=0xEC | 0xED | 0xEF | 0xFE);
+        if metadata {
+            if marker == 0xE1 && payload.starts_with(b"Exif\0\0") {
+                exif = Some(payload[6..].to_vec());
+            }
+            removed = true;
+        } else {
+            output.extend_from_slice(segment);
+        }
+        position = end;
+    }
+    removed.then_some(Stripped {
+        content: output,
+        exif,
+    })
+}
+
+/// Remove the metadata chunks of a PNG image: EXIF (eXIf), texts (tEXt, zTXt, iTXt) and the
+/// modification time (tIME).
+///
+/// Return None if the image is malformed or has no metadata.
+fn strip_png(content: &[u8]) -> Option<Stripped> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !content.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut output = SIGNATURE.to_vec();
+    let mut exif = None;
+    let mut removed = false;
+    let mut position = SIGNATURE.len();
+    while position < content.len() {
+        let length = u32::from_be_bytes(content.get(position..position + 4)?.try_into().ok()?);
+        // Length, type, data and CRC
+        let end = position.checked_add(12 + length as usize)?;
+        let chunk = content.get(position..end)?;
+        let kind = &chunk[4..8];
+        if matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            if kind == b"eXIf" {
+                exif = Some(chunk[8..8 + length as usize].to_vec());
+            }
+            removed = true;
+        } else {
+            output.extend_from_slice(chunk);
+        }
+        position = end;
+    }
+    removed.then_some(Stripped {
+        content: output,
+        exif,
+    })
+}
+
+/// Remove the metadata chunks of a WebP image: EXIF and XMP.
+///
+/// Return None if the image is malformed or has no metadata.
+fn strip_webp(content: &[u8]) -> Option<Stripped> {
+    if content.len() < 12 || &content[..4] != b"RIFF" || &content[8..12] != b"WEBP" {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut exif = None;
+    let mut removed = false;
+    let mut position = 12;
+    while position < content.len() {
+        let size = u32::from_le_bytes(content.get(position + 4..position + 8)?.try_into().ok()?);
+        let data = content.get(position + 8..position.checked_add(8 + size as usize)?)?;
+        // Chunks are padded to an even size, the padding of the last chunk may be missing
+        let end = (position + 8 + size as usize + (size as usize & 1)).min(content.len());
+        let chunk = &content[position..end];
+        match &chunk[..4] {
+            b"EXIF" => {
+                exif = Some(data.strip_prefix(b"Exif\0\0").unwrap_or(data).to_vec());
+                removed = true;
+            }
+            b"XMP " => removed = true,
+            _ => chunks.extend_from_slice(chunk),
+        }
+        position = end;
+    }
+    if !removed {
+        return None;
+    }
+
+    // The extended header announces the metadata, its flags are cleared
+    if chunks.starts_with(b"VP8X") && chunks.len() > 8 {
+        chunks[8] &= !(0x08 | 0x04);
+    }
+    let mut output = b"RIFF".to_vec();
+    output.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend_from_slice(&chunks);
+    Some(Stripped {
+        content: output,
+        exif,
+    })
+}
+
+/// Read the orientation (tag 0x0112 of the first IFD) of EXIF data.
+fn orientation(exif: &[u8]) -> Option<u16> {
+    let big_endian = match exif.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |position: usize| {
+        let bytes = [*exif.get(position)?, *exif.get(position + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |position: usize| {
+        let bytes: [u8; 4] = exif.get(position..position + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|entry| ifd + 2 + entry * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+/// Rotate and flip an image according to its EXIF *orientation*, then encode it again.
+fn rotate(content: &[u8], format: ImageFormat, orientation: u16) -> io::Result<Vec<u8>> {
+    let to_io = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    let image = image::load_from_memory_with_format(content, format).map_err(to_io)?;
+    let image = match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    };
+    let (image, output) = match format {
+        ImageFormat::Jpeg => (
+            DynamicImage::ImageRgb8(image.to_rgb8()),
+            ImageOutputFormat::Jpeg(JPEG_QUALITY),
+        ),
+        ImageFormat::Png => (image, ImageOutputFormat::Png),
+        _ => (
+            DynamicImage::ImageRgba8(image.to_rgba8()),
+            ImageOutputFormat::WebP,
+        ),
+    };
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, output).map_err(to_io)?;
+    Ok(encoded.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    use super::*;
+
+    /// Text of the metadata of the test images, that must not be left in the sanitized images.
+    const SECRET: &[u8] = b"SECRET";
+
+    fn contains(content: &[u8], part: &[u8]) -> bool {
+        content.windows(part.len()).any(|window| window == part)
+    }
+
+    /// A 4×2 image encoded with *format*.
+    fn encoded(format: ImageOutputFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(4, 2, |x, y| Rgb([x as u8 * 60, y as u8 * 120, 90]));
+        let mut encoded = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut encoded, format)
+            .unwrap();
+        encoded.into_inner()
+    }
+
+    fn dimensions(content: &[u8], format: ImageFormat) -> (u32, u32) {
+        image::load_from_memory_with_format(content, format)
+            .unwrap()
+            .dimensions()
+    }
+
+    /// EXIF data with *orientation*, in big endian (`MM`) or little endian (`II`), followed by
+    /// the location of the photo. Its size is odd.
+    fn exif(big_endian: bool, orientation: u16) -> Vec<u8> {
+        let u16_bytes = |value: u16| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let u32_bytes = |value: u32| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let mut exif = if big_endian { b"MM" } else { b"II" }.to_vec();
+        exif.extend(u16_bytes(42));
+        exif.extend(u32_bytes(8));
+        // One entry, the orientation (SHORT), then no other IFD
+        exif.extend(u16_bytes(1));
+        exif.extend(u16_bytes(0x0112));
+        exif.extend(u16_bytes(3));
+        exif.extend(u32_bytes(1));
+        exif.extend(u16_bytes(orientation));
+        exif.extend([0, 0]);
+        exif.extend(u32_bytes(0));
+        exif.extend(b"SECRET GPS 48.8584N 2.2945E");
+        exif
+    }
+
+    /// A JPEG image with EXIF, XMP and a comment.
+    fn jpeg(exif: &[u8]) -> Vec<u8> {
+        let image = encoded(ImageOutputFormat::Jpeg(90));
+        let segment = |marker: u8, payload: &[u8]| {
+            let mut segment = vec![0xFF, marker];
+            segment.extend((payload.len() as u16 + 2).to_be_bytes());
+            segment.extend(payload);
+            segment
+        };
+        let mut content = image[..2].to_vec();
+        content.extend(segment(0xE1, &[b"Exif\0\0", exif].concat()));
+        content.extend(segment(
+            0xE1,
+            b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta>SECRET XMP</x:xmpmeta>",
+        ));
+        content.extend(segment(0xFE, b"SECRET comment"));
+        content.extend(&image[2..]);
+        content
+    }
+
+    /// A PNG image with EXIF, a text and a modification time.
+    fn png(exif: &[u8]) -> Vec<u8> {
+        let image = encoded(ImageOutputFormat::Png);
+        let chunk = |kind: &[u8], data: &[u8]| {
+            let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+            chunk.extend(kind);
+            chunk.extend(data);
+            // The CRC of the removed chunks is not checked
+            chunk.extend([0; 4]);
+            chunk
+        };
+        // After the signature and the header
+        let header = 8 + 25;
+        let mut content = image[..header].to_vec();
+        content.extend(chunk(b"eXIf", exif));
+        content.extend(chunk(b"tEXt", b"Comment\0SECRET text"));
+        content.extend(chunk(b"tIME", &[7, 234, 10, 18, 12, 0, 0]));
+        content.extend(&image[header..]);
+        content
+    }
+
+    /// A WebP image with the extended header, EXIF and XMP.
+    fn webp(exif: &[u8]) -> Vec<u8> {
+        let image = encoded(ImageOutputFormat::WebP);
+        let chunk = |kind: &[u8], data: &[u8]| {
+            let mut chunk = kind.to_vec();
+            chunk.extend((data.len() as u32).to_le_bytes());
+            chunk.extend(data);
+            if data.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        };
+        // Flags of EXIF and XMP, then the canvas size minus one on 24 bits
+        let mut header = vec![0x08 | 0x04, 0, 0, 0];
+        header.extend(&3u32.to_le_bytes()[..3]);
+        header.extend(&1u32.to_le_bytes()[..3]);
+        let mut chunks = chunk(b"VP8X", &header);
+        chunks.extend(&image[12..]);
+        chunks.extend(chunk(b"EXIF", &[b"Exif\0\0", exif].concat()));
+        chunks.extend(chunk(b"XMP ", b"<x:xmpmeta>SECRET XMP</x:xmpmeta>"));
+        let mut content = b"RIFF".to_vec();
+        content.extend((chunks.len() as u32 + 4).to_le_bytes());
+        content.extend(b"WEBP");
+        content.extend(chunks);
+        content
+    }
+
+    type Parser = fn(&[u8]) -> Option<Stripped>;
+
+    /// The test images in each format, with their parser.
+    fn images(exif: &[u8]) -> [(ImageFormat, Vec<u8>, Parser); 3] {
+        [
+            (ImageFormat::Jpeg, jpeg(exif), strip_jpeg),
+            (ImageFormat::Png, png(exif), strip_png),
+            (ImageFormat::WebP, webp(exif), strip_webp),
+        ]
+    }
+
+    #[test]
+    fn metadata_is_removed() {
+        for big_endian in [false, true] {
+            let exif = exif(big_endian, 1);
+            for (format, content, strip) in images(&exif) {
+                assert!(contains(&content, SECRET));
+                let stripped = strip(&content).unwrap();
+                assert!(!contains(&stripped.content, SECRET), "{:?}", format);
+                assert_eq!(stripped.exif.as_deref(), Some(&exif[..]), "{:?}", format);
+                assert_eq!(dimensions(&stripped.content, format), (4, 2));
+                // Nothing is left to remove
+                assert!(strip(&stripped.content).is_none(), "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn webp_header_is_updated() {
+        let stripped = strip_webp(&webp(&exif(false, 1))).unwrap().content;
+        let size = u32::from_le_bytes(stripped[4..8].try_into().unwrap());
+        assert_eq!(size as usize, stripped.len() - 8);
+        assert_eq!(&stripped[12..16], b"VP8X");
+        assert_eq!(stripped[20] & (0x08 | 0x04), 0);
+    }
+
+    #[test]
+    fn orientation_is_read_in_both_byte_orders() {
+        for big_endian in [false, true] {
+            for value in 1..=8 {
+                assert_eq!(orientation(&exif(big_endian, value)), Some(value));
+            }
+            assert_eq!(orientation(&exif(big_endian, 6)[..16]), None);
+        }
+        assert_eq!(orientation(b"XX\0\x2a\0\0\0\x08"), None);
+        assert_eq!(orientation(b""), None);
+    }
+
+    #[test]
+    fn images_are_rotated_with_their_orientation() {
+        for (orientation, rotated) in [(1, (4, 2)), (3, (4, 2)), (6, (2, 4)), (8, (2, 4))] {
+            for (format, content, _) in images(&exif(true, orientation)) {
+                let sanitized = strip(&content, format, true).unwrap().unwrap();
+                assert_eq!(dimensions(&sanitized, format), rotated, "{:?}", format);
+                assert!(!contains(&sanitized, SECRET), "{:?}", format);
+            }
+        }
+        // Without auto_rotate, the orientation is only removed
+        for (format, content, _) in images(&exif(false, 6)) {
+            let sanitized = strip(&content, format, false).unwrap().unwrap();
+            assert_eq!(dimensions(&sanitized, format), (4, 2), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn malformed_images_are_refused() {
+        for (format, content, strip) in images(&exif(false, 6)) {
+            // Cut anywhere, even in the compressed data that is kept as is
+            for length in 0..content.len() {
+                strip(&content[..length]);
+            }
+            // Cut in the EXIF data
+            let exif = content
+                .windows(SECRET.len())
+                .position(|window| window == SECRET)
+                .unwrap();
+            assert!(strip(&content[..exif]).is_none(), "{:?}", format);
+            assert!(strip(b"not an image").is_none(), "{:?}", format);
+        }
+
+        // A JPEG segment shorter than its length field
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01, 0xFF, 0xD9]).is_none());
+        // A byte that is not a marker
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0x00, 0xE1, 0x00, 0x02, 0xFF, 0xD9]).is_none());
+        // A PNG chunk longer than the file
+        let mut png = b"\x89PNG\r\n\x1a\n\xff\xff\xff\xfftEXt".to_vec();
+        png.extend([0; 8]);
+        assert!(strip_png(&png).is_none());
+        // A WebP chunk longer than the file
+        let mut webp = b"RIFF\0\0\0\0WEBPEXIF\xff\xff\xff\xff".to_vec();
+        webp.extend([0; 8]);
+        assert!(strip_webp(&webp).is_none());
+    }
+}
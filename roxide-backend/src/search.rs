@@ -0,0 +1,299 @@
+use std::path::Path;
+
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs::File;
+use rocket::tokio::io::AsyncReadExt;
+
+use rocket_db_pools::Connection;
+
+use sqlx::any::AnyKind;
+use sqlx::{AnyConnection, Row};
+
+use crate::html::escape;
+use crate::user::{add_tags, FileData, NewFile, OwnerToken};
+use crate::{Canard, FileId, RoxideError};
+
+/// Largest part of a text file that is indexed, the rest of the file is not searched.
+const MAX_INDEXED_SIZE: u64 = 256 * 1024;
+
+/// Number of results of a search, by default.
+const SEARCH_LIMIT: i64 = 20;
+
+/// Largest number of results of a search.
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// Characters around the matches in the highlighted texts, replaced by `<mark>` once the text is
+/// escaped. They are removed from the indexed texts.
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+/// Content types, besides `text/*`, whose content is indexed.
+const TEXT_APPLICATION_TYPES: [&str; 8] = [
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/x-sh",
+    "application/x-shellscript",
+    "application/sql",
+    "application/toml",
+    "application/yaml",
+];
+
+/// Conditions on `files` of the results of a search: the files that are neither expired nor out
+/// of downloads, that are public or that the token `$2` uploaded or can see. `$3` is now.
+const VISIBLE_FILES: &str = "files.expiration_date > $3 AND (files.max_downloads IS NULL OR files.download_count < files.max_downloads) AND (files.token_used = $2 OR (NOT files.require_signature AND (files.visibility = 'public' OR (files.visibility = 'private' AND EXISTS (SELECT 1 FROM file_grants WHERE file_grants.file_id = files.id AND file_grants.token = $2)))))";
+
+/// Columns of `FileData`.
+const FILE_COLUMNS: &str = "files.id, files.upload_date, files.content_type, files.download_count, files.size, files.title, files.password_hash IS NOT NULL AS protected";
+
+/// Tell whether the content of a file of *content_type* is indexed: text, JSON, XML and source
+/// code.
+fn is_text_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || TEXT_APPLICATION_TYPES.contains(&essence)
+}
+
+/// Read the text of the file at *path* to index it, the beginning of the file if it is large.
+async fn read_text(path: &Path) -> Result<String, RoxideError> {
+    let mut content = Vec::new();
+    File::open(path)
+        .await?
+        .take(MAX_INDEXED_SIZE)
+        .read_to_end(&mut content)
+        .await?;
+    Ok(String::from_utf8_lossy(&content).replace(['\0', MARK_START, MARK_END], ""))
+}
+
+/// Function that indexes a new file for the search: its title, and the content at *path* if it
+/// is a text.
+///
+/// The content of a file protected by a password, or whose number of downloads is limited, is
+/// not indexed: the snippets of the results would give it away.
+pub async fn index_file(
+    db: &mut AnyConnection,
+    file: &NewFile<'_>,
+    content_type: &str,
+    path: &Path,
+) -> Result<(), RoxideError> {
+    let content = if is_text_type(content_type)
+        && file.password_hash.is_none()
+        && file.max_downloads.is_none()
+    {
+        read_text(path).await?
+    } else {
+        String::new()
+    };
+    sqlx::query("INSERT INTO file_search (file_id, title, content) VALUES ($1, $2, $3)")
+        .bind(file.id.get_id())
+        .bind(file.title)
+        .bind(content)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that changes the title of the file *id* in the index.
+pub async fn rename_file(
+    db: &mut AnyConnection,
+    id: &FileId,
+    title: &str,
+) -> Result<(), RoxideError> {
+    sqlx::query("UPDATE file_search SET title = $1 WHERE file_id = $2")
+        .bind(title)
+        .bind(id.get_id())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that removes the file *id* from the index, with the file.
+pub async fn delete_index(db: &mut AnyConnection, id: &FileId) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM file_search WHERE file_id = $1")
+        .bind(id.get_id())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Build the full-text query of SQLite from the words of *q*: every word must be found, the
+/// operators of FTS5 are taken as words.
+fn sqlite_query(q: &str) -> String {
+    q.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escape a highlighted text for HTML, its matches being in `<mark>`.
+fn highlight(text: &str) -> String {
+    escape(text)
+        .replace(MARK_START, "<mark>")
+        .replace(MARK_END, "</mark>")
+}
+
+/// A file found by a search.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SearchResult {
+    #[serde(flatten)]
+    file: FileData,
+    /// Title of the file in HTML, its matches in `<mark>`.
+    highlighted_title: String,
+    /// Parts of the content of the file that match, in HTML, the matches in `<mark>`.
+    snippet: String,
+}
+
+/// Function that searches the files by their title and the content of the text files, for the
+/// token sent as a bearer token.
+///
+/// Every word of `q` must be found. The results are the public files, and the files that the
+/// token uploaded or that are shared with it, the best matches first. There are `limit` results
+/// (20 by default, up to 100).
+#[get("/search?<q>&<limit>")]
+async fn search(
+    mut db: Connection<Canard>,
+    token: OwnerToken<'_>,
+    q: &str,
+    limit: Option<i64>,
+) -> Result<Json<Vec<SearchResult>>, RoxideError> {
+    let limit = limit.unwrap_or(SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    let (sql, q) = match db.kind() {
+        AnyKind::Postgres => (
+            format!(
+                "SELECT {0}, ts_headline('simple', file_search.title, query, 'HighlightAll=true, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS highlighted_title, CASE WHEN file_search.content = '' THEN '' ELSE ts_headline('simple', file_search.content, query, 'MaxFragments=2, MaxWords=24, MinWords=8, FragmentDelimiter=\" … \", StartSel=' || chr(2) || ', StopSel=' || chr(3)) END AS snippet FROM file_search JOIN files ON files.id = file_search.file_id, plainto_tsquery('simple', $1) AS query WHERE file_search.document @@ query AND {1} ORDER BY ts_rank(file_search.document, query) DESC, files.id LIMIT {2}",
+                FILE_COLUMNS, VISIBLE_FILES, limit
+            ),
+            q.to_string(),
+        ),
+        _ => (
+            format!(
+                "SELECT {0}, highlight(file_search_index, 0, char(2), char(3)) AS highlighted_title, snippet(file_search_index, 1, char(2), char(3), '…', 24) AS snippet FROM file_search_index JOIN file_search ON file_search.id = file_search_index.rowid JOIN files ON files.id = file_search.file_id WHERE file_search_index MATCH $1 AND {1} ORDER BY bm25(file_search_index, 10.0, 1.0), files.id LIMIT {2}",
+                FILE_COLUMNS, VISIBLE_FILES, limit
+            ),
+            sqlite_query(q),
+        ),
+    };
+    if q.trim().is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let rows = sqlx::query(&sql)
+        .bind(q)
+        .bind(token.0)
+        .bind(Utc::now().timestamp())
+        .fetch_all(&mut *db)
+        .await?;
+    let mut files = rows.iter().map(FileData::from_row).collect::<Vec<_>>();
+    add_tags(&mut db, &mut files).await?;
+    let results = files
+        .into_iter()
+        .zip(&rows)
+        .map(|(file, row)| SearchResult {
+            file,
+            highlighted_title: highlight(&row.get::<String, &str>("highlighted_title")),
+            snippet: highlight(&row.get::<String, &str>("snippet")),
+        })
+        .collect();
+    Ok(Json(results))
+}
+
+/// Function that mounts the route of the full-text search.
+/// - search (to find files by their title and their content).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Search stage", |rocket| async {
+        rocket.mount("/", routes![search])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::serde::json::Value;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::test_client;
+
+    #[test]
+    fn sqlite_queries_are_quoted_words() {
+        assert_eq!(sqlite_query("  rust   notes "), r#""rust" "notes""#);
+        assert_eq!(
+            sqlite_query(r#"a OR b* NEAR(c) title:d "e"#),
+            r#""a" "OR" "b*" "NEAR(c)" "title:d" """e""#
+        );
+        assert_eq!(sqlite_query(" \t"), "");
+    }
+
+    #[rocket::async_test]
+    async fn operators_are_searched_as_words() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        for (id, title) in [("a", "rust OR go"), ("b", "rust notes"), ("c", "<b>go</b>")] {
+            sqlx::query("INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, visibility, title, blob, require_signature) VALUES ($1, $2, 0, 'token', 'text/plain', 0, 0, 'public', $3, 'digest', FALSE)")
+                .bind(id)
+                .bind(Utc::now().timestamp() + 3600)
+                .bind(title)
+                .execute(&mut *db)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO file_search (file_id, title) VALUES ($1, $2)")
+                .bind(id)
+                .bind(title)
+                .execute(&mut *db)
+                .await
+                .unwrap();
+        }
+        let client = test_client(dir.path(), routes![search]).await;
+        let search = |q: &'static str| {
+            let client = &client;
+            async move {
+                let response = client
+                    .get(format!("/search?q={}", q))
+                    .header(Header::new("Authorization", "Bearer token"))
+                    .dispatch()
+                    .await;
+                let results = response.into_json::<Value>().await.unwrap();
+                results
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|result| result["id"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(search("rust").await.len(), 2);
+        assert_eq!(search("rust%20go").await, ["a"]);
+        assert_eq!(search("OR").await, ["a"]);
+        // A syntax error of FTS5 would fail the request instead of returning a list
+        for q in [
+            "go%20NOT%20rust",
+            "%22rust",
+            "rust*",
+            "NEAR(rust%20go)",
+            "title:go",
+            "-go",
+        ] {
+            search(q).await;
+        }
+
+        let response = client
+            .get("/search?q=go%20b")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        let results = response.into_json::<Value>().await.unwrap();
+        assert_eq!(
+            results[0]["highlighted_title"],
+            "&lt;<mark>b</mark>&gt;<mark>go</mark>&lt;/<mark>b</mark>&gt;"
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::State;
+
+use rocket_db_pools::Connection;
+
+use sqlx::any::AnyRow;
+use sqlx::{Acquire, AnyConnection, Row};
+
+use crate::access::FileAccess;
+use crate::download::{Download, DownloadHeaders};
+use crate::rendition::RenditionCache;
+use crate::storage::Storage;
+use crate::user::{check_max_downloads, check_owner, download, OwnerToken};
+use crate::{Canard, FileId, RoxideError};
+
+/// Size of the slugs of the share links.
+const SLUG_SIZE: usize = 16;
+
+/// Settings of a share link to create.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LinkRequest {
+    /// Name of the link, to tell the links apart.
+    #[serde(default)]
+    name: String,
+    /// Time in second the link is valid, the link lives as long as the file without it.
+    duration: Option<i64>,
+    /// Number of downloads after which the link stops working.
+    max_downloads: Option<i64>,
+}
+
+/// A named share link to a file.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ShareLink {
+    slug: String,
+    name: String,
+    url: String,
+    creation_date: i64,
+    expiration_date: Option<i64>,
+    max_downloads: Option<i64>,
+    download_count: i64,
+}
+
+impl ShareLink {
+    fn from_row(row: &AnyRow) -> Self {
+        let slug = row.get::<String, &str>("slug");
+        Self {
+            url: format!("/s/{}", slug),
+            slug,
+            name: row.get::<String, &str>("name"),
+            creation_date: row.get::<i64, &str>("creation_date"),
+            expiration_date: row.get::<Option<i64>, &str>("expiration_date"),
+            max_downloads: row.get::<Option<i64>, &str>("max_downloads"),
+            download_count: row.get::<i64, &str>("download_count"),
+        }
+    }
+}
+
+/// Function that deletes the share links of the file *id*, with the file.
+pub async fn delete_links(db: &mut AnyConnection, id: &FileId) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM share_links WHERE file_id = $1")
+        .bind(id.get_id())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that deletes the share links that expired.
+///
+/// The links that reached their number of downloads are kept, so that they answer 410 until the
+/// file is deleted or the link revoked.
+pub async fn clean_links(db: &mut AnyConnection) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM share_links WHERE expiration_date <= $1")
+        .bind(Utc::now().timestamp())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that creates a named share link to a file, on behalf of its uploader.
+///
+/// Each link has its own expiration date and number of downloads, and can be revoked without
+/// touching the other links.
+#[post("/file/<id>/links", data = "<request>")]
+async fn create_link(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+    request: Json<LinkRequest>,
+) -> Result<Json<ShareLink>, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    if matches!(request.duration, Some(duration) if duration <= 0) {
+        return Err(RoxideError::InvalidDuration);
+    }
+    let max_downloads = check_max_downloads(request.max_downloads)?;
+
+    let now = Utc::now().timestamp();
+    let expiration = request
+        .duration
+        .map(|duration| now.saturating_add(duration));
+    let slug = FileId::new(SLUG_SIZE);
+    sqlx::query(
+        "INSERT INTO share_links (slug, file_id, name, creation_date, expiration_date, max_downloads) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(slug.get_id())
+    .bind(id.get_id())
+    .bind(&request.name)
+    .bind(now)
+    .bind(expiration)
+    .bind(max_downloads)
+    .execute(&mut *db)
+    .await?;
+    Ok(Json(ShareLink {
+        url: format!("/s/{}", slug.get_id()),
+        slug: slug.get_id().to_string(),
+        name: request.into_inner().name,
+        creation_date: now,
+        expiration_date: expiration,
+        max_downloads,
+        download_count: 0,
+    }))
+}
+
+/// Function that lists the share links of a file, on behalf of its uploader.
+#[get("/file/<id>/links")]
+async fn links(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+) -> Result<Json<Vec<ShareLink>>, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    let rows = sqlx::query("SELECT * FROM share_links WHERE file_id = $1 ORDER BY creation_date")
+        .bind(id.get_id())
+        .fetch_all(&mut *db)
+        .await?;
+    Ok(Json(rows.iter().map(ShareLink::from_row).collect()))
+}
+
+/// Function that revokes a share link of a file, on behalf of its uploader.
+#[delete("/file/<id>/links/<slug>")]
+async fn revoke_link(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+    slug: FileId,
+) -> Result<Status, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    let deleted = sqlx::query("DELETE FROM share_links WHERE slug = $1 AND file_id = $2")
+        .bind(slug.get_id())
+        .bind(id.get_id())
+        .execute(&mut *db)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(RoxideError::NotFound);
+    }
+    Ok(Status::NoContent)
+}
+
+/// Function that sends the file of a share link.
+///
+/// The link gives access to the file whatever its visibility, but a password-protected file still
+/// needs its password. A link that expired, or that reached its number of downloads, gets 410.
+/// Like for the file, a download is counted when the beginning of the file is sent, and ranges
+/// and conditional requests are ignored by a link whose number of downloads is limited.
+#[get("/s/<slug>")]
+async fn shared(
+    storage: &State<Storage>,
+    renditions: &State<RenditionCache>,
+    mut db: Connection<Canard>,
+    slug: FileId,
+    headers: DownloadHeaders<'_>,
+    access: FileAccess<'_>,
+) -> Result<Download, RoxideError> {
+    let row = sqlx::query(
+        "SELECT file_id, expiration_date, max_downloads, download_count FROM share_links WHERE slug = $1",
+    )
+    .bind(slug.get_id())
+    .fetch_one(&mut *db)
+    .await?;
+    let id = FileId::from(row.get::<&str, &str>("file_id"));
+    let expiration_date = row.get::<Option<i64>, &str>("expiration_date");
+    let max_downloads = row.get::<Option<i64>, &str>("max_downloads");
+    if expiration_date.map_or(false, |expiration| expiration <= Utc::now().timestamp())
+        || max_downloads.map_or(false, |max| row.get::<i64, &str>("download_count") >= max)
+    {
+        return Err(RoxideError::ExpiredLink);
+    }
+    let headers = match max_downloads {
+        Some(_) => DownloadHeaders::default(),
+        None => headers,
+    };
+
+    //The downloads of the link and of the file are counted together: a request that loses the
+    //race for the last download of the link does not count a download of the file
+    let mut tx = db.begin().await?;
+    if max_downloads.is_some() {
+        count_link_download(&mut tx, &slug).await?;
+    }
+    let download = download(
+        storage,
+        renditions,
+        &mut tx,
+        id,
+        headers,
+        access.through_share_link(),
+        None,
+    )
+    .await?;
+    if max_downloads.is_none() && download.is_from_start() {
+        count_link_download(&mut tx, &slug).await?;
+    }
+    tx.commit().await?;
+    Ok(download)
+}
+
+/// Function that counts a download of the share link *slug*, only while the link has downloads
+/// left: concurrent requests cannot download more than allowed, the requests that come too late
+/// get 410.
+async fn count_link_download(db: &mut AnyConnection, slug: &FileId) -> Result<(), RoxideError> {
+    sqlx::query(
+        "UPDATE share_links SET download_count = download_count+1 WHERE slug = $1 AND (max_downloads IS NULL OR download_count < max_downloads) RETURNING slug",
+    )
+    .bind(slug.get_id())
+    .fetch_optional(&mut *db)
+    .await?
+    .ok_or(RoxideError::ExpiredLink)?;
+    Ok(())
+}
+
+/// Function that mounts the routes that manage the named share links.
+/// - create_link (to create a share link to a file).
+/// - links (to list the share links of a file).
+/// - revoke_link (to revoke a share link).
+/// - shared (to download the file of a share link).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Share stage", |rocket| async {
+        rocket.mount("/", routes![create_link, links, revoke_link, shared])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::futures::future::join_all;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::Value;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::test_client;
+    use crate::user::test_file;
+
+    /// Register the file *id* whose content is `hello`, changed by *changes*, and build a client
+    /// of the routes of the links.
+    async fn client_with_file(dir: &std::path::Path, id: &str, changes: &str) -> Client {
+        let pool = test_pool(dir).await;
+        let mut db = pool.acquire().await.unwrap();
+        let changes = match changes {
+            "" => "size = 5".to_string(),
+            changes => format!("size = 5, {}", changes),
+        };
+        test_file(&mut db, id, &changes).await;
+        let client = test_client(dir, routes![create_link, links, revoke_link, shared]).await;
+        std::fs::write(dir.join("upload").join("digest"), b"hello").unwrap();
+        client
+    }
+
+    /// Create a link to the file *id* with the settings *request*, on behalf of *token*.
+    async fn create(client: &Client, id: &str, token: &str, request: &str) -> (Status, Value) {
+        let response = client
+            .post(format!("/file/{}/links", id))
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .header(ContentType::JSON)
+            .body(request)
+            .dispatch()
+            .await;
+        let status = response.status();
+        (
+            status,
+            response.into_json::<Value>().await.unwrap_or_default(),
+        )
+    }
+
+    #[rocket::async_test]
+    async fn links_are_created_listed_and_revoked() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_file(dir.path(), "a", "visibility = 'private'").await;
+
+        let (status, _) = create(&client, "a", "other", r#"{"name": "stolen"}"#).await;
+        assert_eq!(status, Status::Forbidden);
+        let (status, _) = create(&client, "a", "token", r#"{"duration": 0}"#).await;
+        assert_eq!(status, Status::BadRequest);
+        let (status, link) = create(&client, "a", "token", r#"{"name": "press"}"#).await;
+        assert_eq!(status, Status::Ok);
+        let slug = link["slug"].as_str().unwrap();
+        assert_eq!(link["url"], format!("/s/{}", slug));
+
+        let list = client
+            .get("/file/a/links")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await
+            .into_json::<Value>()
+            .await
+            .unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(list[0]["name"], "press");
+
+        // The link gives access to the private file
+        let response = client.get(format!("/s/{}", slug)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().await.unwrap(), "hello");
+
+        let revoke = || {
+            client
+                .delete(format!("/file/a/links/{}", slug))
+                .header(Header::new("Authorization", "Bearer token"))
+                .dispatch()
+        };
+        assert_eq!(revoke().await.status(), Status::NoContent);
+        assert_eq!(revoke().await.status(), Status::NotFound);
+        let response = client.get(format!("/s/{}", slug)).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn limited_links_stop_at_their_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_file(dir.path(), "a", "max_downloads = 10").await;
+        let (_, link) = create(&client, "a", "token", r#"{"max_downloads": 2}"#).await;
+        let url = format!("/s/{}", link["slug"].as_str().unwrap());
+
+        let statuses = join_all((0..6).map(|_| client.get(url.as_str()).dispatch())).await;
+        let sent = statuses
+            .iter()
+            .filter(|response| response.status() == Status::Ok)
+            .count();
+        assert_eq!(sent, 2);
+        assert!(statuses
+            .iter()
+            .all(|response| [Status::Ok, Status::Gone].contains(&response.status())));
+
+        // The requests refused by the link did not count a download of the file
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let count = sqlx::query("SELECT download_count FROM files WHERE id = 'a'")
+            .fetch_one(&mut *db)
+            .await
+            .unwrap()
+            .get::<i64, &str>("download_count");
+        assert_eq!(count, 2);
+    }
+
+    #[rocket::async_test]
+    async fn expired_links_are_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client_with_file(dir.path(), "a", "").await;
+        let (_, link) = create(&client, "a", "token", r#"{"duration": 60}"#).await;
+        let slug = link["slug"].as_str().unwrap();
+        let url = format!("/s/{}", slug);
+        assert_eq!(
+            client.get(url.as_str()).dispatch().await.status(),
+            Status::Ok
+        );
+
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        sqlx::query("UPDATE share_links SET expiration_date = 0")
+            .execute(&mut *db)
+            .await
+            .unwrap();
+        let response = client.get(url.as_str()).dispatch().await;
+        assert_eq!(response.status(), Status::Gone);
+        let error = response.into_json::<Value>().await.unwrap();
+        assert_eq!(error["code"], "expired_link");
+
+        clean_links(&mut db).await.unwrap();
+        assert_eq!(
+            client.get(url.as_str()).dispatch().await.status(),
+            Status::NotFound
+        );
+    }
+}
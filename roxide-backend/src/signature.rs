@@ -0,0 +1,160 @@
+use chrono::Utc;
+
+use hmac::{Hmac, Mac};
+
+use rand::Rng;
+
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::State;
+
+use rocket_db_pools::Connection;
+
+use sha2::Sha256;
+
+use crate::user::{check_owner, OwnerToken};
+use crate::{Canard, FileId, RoxideError};
+
+/// Signer of the share links of the files (`/get/<id>?exp=<expiration>&sig=<signature>`).
+///
+/// The signature is an HMAC-SHA256 of the id of the file and of the expiration date of the link,
+/// with the `url_signing_key` of the configuration.
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    /// Build the signer with *key*. Without key, a random key is used: the links do not survive
+    /// a restart, and are not valid on the other instances.
+    pub fn new(key: Option<&str>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                eprintln!("No url_signing_key configured, signed links will not survive a restart");
+                rand::thread_rng().gen::<[u8; 32]>().to_vec()
+            }
+        };
+        Self { key }
+    }
+
+    fn mac(&self, id: &FileId, expiration: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}:{}", id.get_id(), expiration).as_bytes());
+        mac
+    }
+
+    /// Sign the link to the file *id* valid until *expiration*.
+    pub fn sign(&self, id: &FileId, expiration: i64) -> String {
+        hex::encode(self.mac(id, expiration).finalize().into_bytes())
+    }
+
+    /// Check the *signature* of the link to the file *id* valid until *expiration*.
+    pub fn verify(&self, id: &FileId, expiration: i64, signature: &str) -> Result<(), RoxideError> {
+        let signature = hex::decode(signature).map_err(|_| RoxideError::InvalidSignature)?;
+        self.mac(id, expiration)
+            .verify_slice(&signature)
+            .map_err(|_| RoxideError::InvalidSignature)?;
+        if expiration <= Utc::now().timestamp() {
+            return Err(RoxideError::ExpiredLink);
+        }
+        Ok(())
+    }
+}
+
+/// Lifetime of a link to create.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LinkRequest {
+    /// Time in second the link is valid.
+    duration: i64,
+}
+
+/// A signed link to a file.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SignedLink {
+    url: String,
+    expiration_date: i64,
+}
+
+/// Function that creates a signed link to a file, valid for `duration` seconds, on behalf of its
+/// uploader.
+///
+/// The link stops working after its duration, or with the file. It gives access to the file even
+/// if it is private, but not to a password-protected file without its password.
+#[post("/file/<id>/sign", data = "<request>")]
+async fn sign(
+    signer: &State<UrlSigner>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+    request: Json<LinkRequest>,
+) -> Result<Json<SignedLink>, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    if request.duration <= 0 {
+        return Err(RoxideError::InvalidDuration);
+    }
+
+    let expiration = Utc::now().timestamp().saturating_add(request.duration);
+    let signature = signer.sign(&id, expiration);
+    Ok(Json(SignedLink {
+        url: format!("/get/{}?exp={}&sig={}", id.get_id(), expiration, signature),
+        expiration_date: expiration,
+    }))
+}
+
+/// Function that mounts the route that creates the signed links.
+/// - sign (to create a signed link to a file).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Signature stage", |rocket| async {
+        rocket.mount("/", routes![sign])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_links_are_verified() {
+        let signer = UrlSigner::new(Some("key"));
+        let (id, expiration) = (FileId::from("abc"), Utc::now().timestamp() + 60);
+        let signature = signer.sign(&id, expiration);
+        assert!(signer.verify(&id, expiration, &signature).is_ok());
+        assert!(UrlSigner::new(Some("key"))
+            .verify(&id, expiration, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn altered_links_are_rejected() {
+        let signer = UrlSigner::new(Some("key"));
+        let (id, expiration) = (FileId::from("abc"), Utc::now().timestamp() + 60);
+        let signature = signer.sign(&id, expiration);
+        let rejected = |result| matches!(result, Err(RoxideError::InvalidSignature));
+        assert!(rejected(signer.verify(
+            &FileId::from("abd"),
+            expiration,
+            &signature
+        )));
+        assert!(rejected(signer.verify(&id, expiration + 1, &signature)));
+        assert!(rejected(signer.verify(&id, expiration, "not hex")));
+        assert!(rejected(signer.verify(&id, expiration, &signature[..32])));
+        assert!(rejected(
+            UrlSigner::new(Some("other")).verify(&id, expiration, &signature)
+        ));
+    }
+
+    #[test]
+    fn expired_links_are_rejected() {
+        let signer = UrlSigner::new(Some("key"));
+        let (id, expiration) = (FileId::from("abc"), Utc::now().timestamp() - 1);
+        let signature = signer.sign(&id, expiration);
+        assert!(matches!(
+            signer.verify(&id, expiration, &signature),
+            Err(RoxideError::ExpiredLink)
+        ));
+    }
+}
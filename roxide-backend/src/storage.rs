@@ -0,0 +1,130 @@
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use rocket::serde::Deserialize;
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+use crate::s3::{S3Config, S3Storage};
+
+/// Reader over the content of a stored object.
+pub type ObjectReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Storage shared between the routes and the background tasks.
+pub type Storage = Arc<dyn StorageBackend>;
+
+/// Metadata of a stored object.
+#[derive(Debug)]
+pub struct ObjectStat {
+    pub size: u64,
+}
+
+/// Interface of the places where uploaded files are kept.
+///
+/// Objects are identified by a key (the id of the file). A missing object is reported with an
+/// error of kind `std::io::ErrorKind::NotFound`.
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Store the content of the local file *source* under *key*.
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()>;
+
+    /// Retrieve the whole content of the object *key*.
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Delete the object *key*.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Indicate if the object *key* exists.
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Retrieve the metadata of the object *key*.
+    async fn stat(&self, key: &str) -> io::Result<ObjectStat>;
+
+    /// Open a reader over the content of the object *key*, or over the bytes of *range* only.
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ObjectReader>;
+}
+
+/// Configuration of the storage backend, extracted from the `storage` table of Rocket.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// Files are stored in `upload_directory`.
+    #[default]
+    Local,
+    /// Files are stored in a bucket of an S3-compatible object store.
+    S3(S3Config),
+}
+
+impl StorageConfig {
+    /// Build the storage backend described by the configuration.
+    pub fn build(&self, upload_directory: &str) -> Storage {
+        match self {
+            StorageConfig::Local => Arc::new(LocalStorage::new(upload_directory)),
+            StorageConfig::S3(config) => Arc::new(S3Storage::new(config)),
+        }
+    }
+}
+
+/// Storage that keeps every file in a directory, the name of the file being its key.
+#[derive(Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+        }
+    }
+
+    ///Compute the path of an object.
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        fs::copy(source, self.path(key)).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key)).await
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match fs::metadata(self.path(key)).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<ObjectStat> {
+        let metadata = fs::metadata(self.path(key)).await?;
+        Ok(ObjectStat {
+            size: metadata.len(),
+        })
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ObjectReader> {
+        let mut file = fs::File::open(self.path(key)).await?;
+        match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(Box::pin(file.take(range.end - range.start)))
+            }
+            None => Ok(Box::pin(file)),
+        }
+    }
+}
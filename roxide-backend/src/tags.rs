@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+
+use rocket_db_pools::Connection;
+
+use sqlx::{Acquire, AnyConnection, Row};
+
+use crate::user::{check_owner, OwnerToken};
+use crate::{Canard, FileId, RoxideError};
+
+/// Largest number of tags of a file.
+const MAX_TAGS: usize = 20;
+
+/// Largest length of a tag, in characters.
+const MAX_TAG_LENGTH: usize = 50;
+
+/// Normalize a tag: without the spaces around it, in lower case.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// Function that reads the tags sent for a file, each field holding one tag or several tags
+/// separated by commas.
+///
+/// The tags are normalized and the duplicates removed. A file has at most 20 tags, of at most
+/// 50 characters without control characters.
+pub fn parse_tags<S: AsRef<str>>(fields: &[S]) -> Result<Vec<String>, RoxideError> {
+    let mut tags = Vec::new();
+    for tag in fields.iter().flat_map(|field| field.as_ref().split(',')) {
+        let tag = normalize_tag(tag);
+        if tag.is_empty() || tags.contains(&tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH || tag.chars().any(char::is_control) {
+            return Err(RoxideError::InvalidTags);
+        }
+        tags.push(tag);
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(RoxideError::InvalidTags);
+    }
+    Ok(tags)
+}
+
+/// Function that returns the id of the tag *name*, created if needed.
+///
+/// The tag is read again after its creation, and created again if the maintenance deleted it in
+/// the meantime because no file had it.
+async fn tag_id(db: &mut AnyConnection, name: &str) -> Result<i64, RoxideError> {
+    loop {
+        sqlx::query("INSERT INTO tags (name) VALUES ($1) ON CONFLICT (name) DO NOTHING")
+            .bind(name)
+            .execute(&mut *db)
+            .await?;
+        if let Some(row) = sqlx::query("SELECT id FROM tags WHERE name = $1")
+            .bind(name)
+            .fetch_optional(&mut *db)
+            .await?
+        {
+            return Ok(row.get::<i64, &str>("id"));
+        }
+    }
+}
+
+/// Function that replaces the tags of the file *id* by *tags*, normalized.
+pub async fn set_tags(
+    db: &mut AnyConnection,
+    id: &FileId,
+    tags: &[String],
+) -> Result<(), RoxideError> {
+    let mut tx = db.begin().await?;
+    delete_tags(&mut tx, id).await?;
+    for tag in tags {
+        let tag_id = tag_id(&mut tx, tag).await?;
+        sqlx::query("INSERT INTO file_tags (file_id, tag_id) VALUES ($1, $2)")
+            .bind(id.get_id())
+            .bind(tag_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Function that deletes the tags of the file *id*, with the file.
+pub async fn delete_tags(db: &mut AnyConnection, id: &FileId) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM file_tags WHERE file_id = $1")
+        .bind(id.get_id())
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that deletes the tags that no file has anymore.
+pub async fn clean_tags(db: &mut AnyConnection) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM file_tags)")
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that reads the tags of the files *ids*, in alphabetical order. The files without
+/// tags are missing from the map.
+pub async fn file_tags(
+    db: &mut AnyConnection,
+    ids: &[&str],
+) -> Result<HashMap<String, Vec<String>>, RoxideError> {
+    let mut tags = HashMap::<String, Vec<String>>::new();
+    if ids.is_empty() {
+        return Ok(tags);
+    }
+    let parameters = (1..=ids.len())
+        .map(|n| format!("${}", n))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT file_tags.file_id, tags.name FROM file_tags JOIN tags ON tags.id = file_tags.tag_id WHERE file_tags.file_id IN ({}) ORDER BY tags.name",
+        parameters
+    );
+    let rows = ids
+        .iter()
+        .fold(sqlx::query(&sql), |query, id| query.bind(*id))
+        .fetch_all(&mut *db)
+        .await?;
+    for row in rows {
+        tags.entry(row.get::<String, &str>("file_id"))
+            .or_default()
+            .push(row.get::<String, &str>("name"));
+    }
+    Ok(tags)
+}
+
+/// A tag with the number of public files that have it.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TagCount {
+    name: String,
+    count: i64,
+}
+
+/// Function that lists the tags of the files listed by `/list/<token>`, with the number of files
+/// that have each of them, the most used first. The token is sent as a bearer token.
+#[get("/tags")]
+async fn tags(
+    mut db: Connection<Canard>,
+    _token: OwnerToken<'_>,
+) -> Result<Json<Vec<TagCount>>, RoxideError> {
+    let tags = sqlx::query("SELECT tags.name, count(1) AS count FROM tags JOIN file_tags ON file_tags.tag_id = tags.id JOIN files ON files.id = file_tags.file_id WHERE files.visibility = 'public' AND NOT files.require_signature AND files.expiration_date > $1 AND (files.max_downloads IS NULL OR files.download_count < files.max_downloads) GROUP BY tags.name ORDER BY count DESC, tags.name")
+        .bind(Utc::now().timestamp())
+        .fetch_all(&mut *db)
+        .await?
+        .iter()
+        .map(|row| TagCount {
+            name: row.get::<String, &str>("name"),
+            count: row.get::<i64, &str>("count"),
+        })
+        .collect();
+    Ok(Json(tags))
+}
+
+/// Function that replaces the tags of a file, on behalf of its uploader.
+///
+/// The tags are sent as a JSON array, e.g. `["project-a", "logo"]`, and follow the rules of the
+/// field `tags` of `/post/<token>`. An empty array removes the tags.
+#[put("/file/<id>/tags", data = "<tags>")]
+async fn edit(
+    mut db: Connection<Canard>,
+    id: FileId,
+    owner: OwnerToken<'_>,
+    tags: Json<Vec<String>>,
+) -> Result<Status, RoxideError> {
+    check_owner(&mut db, &id, owner.0).await?;
+    let tags = parse_tags(&tags)?;
+    set_tags(&mut db, &id, &tags).await?;
+    Ok(Status::NoContent)
+}
+
+/// Function that mounts the routes of the tags.
+/// - tags (to list the tags with their number of files).
+/// - edit (to change the tags of a file).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Tags stage", |rocket| async {
+        rocket.mount("/", routes![tags, edit])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Header, Status};
+    use rocket::serde::json::Value;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::test_client;
+    use crate::user::test_file;
+
+    /// Read the tags of the file *id*.
+    async fn read_tags(db: &mut AnyConnection, id: &str) -> Vec<String> {
+        let mut tags = file_tags(db, &[id]).await.unwrap();
+        tags.remove(id).unwrap_or_default()
+    }
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(
+            parse_tags(&["Logo, project-A", " logo ,,", "draft"]).unwrap(),
+            ["logo", "project-a", "draft"]
+        );
+        assert!(parse_tags::<&str>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_tags_are_refused() {
+        let too_many = (0..=MAX_TAGS).map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(matches!(
+            parse_tags(&too_many),
+            Err(RoxideError::InvalidTags)
+        ));
+        assert!(matches!(
+            parse_tags(&["a".repeat(MAX_TAG_LENGTH + 1)]),
+            Err(RoxideError::InvalidTags)
+        ));
+        assert!(matches!(
+            parse_tags(&["new\nline"]),
+            Err(RoxideError::InvalidTags)
+        ));
+        assert!(parse_tags(&["é".repeat(MAX_TAG_LENGTH)]).is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn tags_are_counted_on_the_public_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let files = [
+            ("a", "", vec!["logo", "draft"]),
+            ("b", "", vec!["logo"]),
+            ("c", "visibility = 'private'", vec!["logo", "secret"]),
+            ("d", "expiration_date = 0", vec!["draft"]),
+            ("e", "max_downloads = 1, download_count = 1", vec!["draft"]),
+        ];
+        for (id, changes, tags) in files {
+            test_file(&mut db, id, changes).await;
+            let tags = tags.into_iter().map(String::from).collect::<Vec<_>>();
+            set_tags(&mut db, &FileId::from(id), &tags).await.unwrap();
+        }
+        let client = test_client(dir.path(), routes![tags]).await;
+
+        let response = client.get("/tags").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/tags")
+            .header(Header::new("Authorization", "Bearer other"))
+            .dispatch()
+            .await;
+        let counts = response.into_json::<Value>().await.unwrap();
+        assert_eq!(
+            counts,
+            rocket::serde::json::json!([
+                {"name": "logo", "count": 2},
+                {"name": "draft", "count": 1},
+            ])
+        );
+    }
+
+    #[rocket::async_test]
+    async fn uploaders_replace_the_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        test_file(&mut db, "a", "").await;
+        let client = test_client(dir.path(), routes![edit]).await;
+        let edit = |token: &'static str, tags: &'static str| {
+            let client = &client;
+            async move {
+                client
+                    .put("/file/a/tags")
+                    .header(Header::new("Authorization", format!("Bearer {}", token)))
+                    .body(tags)
+                    .dispatch()
+                    .await
+                    .status()
+            }
+        };
+
+        assert_eq!(
+            edit("token", r#"["Logo", "draft,logo"]"#).await,
+            Status::NoContent
+        );
+        assert_eq!(read_tags(&mut db, "a").await, ["draft", "logo"]);
+        assert_eq!(edit("other", r#"["stolen"]"#).await, Status::Forbidden);
+        assert_eq!(edit("token", r#"["a\u0007"]"#).await, Status::BadRequest);
+        assert_eq!(read_tags(&mut db, "a").await, ["draft", "logo"]);
+        assert_eq!(edit("token", "[]").await, Status::NoContent);
+        assert!(read_tags(&mut db, "a").await.is_empty());
+
+        // The tags that no file has anymore are deleted, and created again when needed
+        clean_tags(&mut db).await.unwrap();
+        let tags = sqlx::query("SELECT name FROM tags")
+            .fetch_all(&mut *db)
+            .await
+            .unwrap();
+        assert!(tags.is_empty());
+        assert_eq!(edit("token", r#"["logo"]"#).await, Status::NoContent);
+        assert_eq!(read_tags(&mut db, "a").await, ["logo"]);
+    }
+}
@@ -0,0 +1,607 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use rocket::data::{ByteUnit, Data, Limits, ToByteUnit};
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder};
+use rocket::tokio::fs::{self, File, OpenOptions};
+use rocket::tokio::io::AsyncWriteExt;
+use rocket::{Request, Response, State};
+
+use rocket_db_pools::Connection;
+
+use sqlx::{Acquire, AnyConnection, Row};
+
+use crate::access::Visibility;
+use crate::collection::check_collection;
+use crate::hashed_file::HashedFile;
+use crate::password::hash_password;
+use crate::storage::Storage;
+use crate::user::{
+    check_max_downloads, check_upload_rate, expiration_date, new_file_id, register_file, NewFile,
+};
+use crate::{is_token_valid, AppConfig, Canard, FileId, RoxideError};
+
+/// Version of the tus protocol implemented by Roxide.
+const TUS_VERSION: &str = "1.0.0";
+
+/// Extensions of the tus protocol implemented by Roxide.
+const TUS_EXTENSIONS: &str = "creation,termination";
+
+/// Time in seconds after which an unfinished upload is abandoned.
+const ABANDONED_AFTER: i64 = 24 * 3600;
+
+/// Response of a tus endpoint, a status with the tus headers.
+struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        Self {
+            status,
+            headers: vec![Header::new("Tus-Resumable", TUS_VERSION)],
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .raw_header("Cache-Control", "no-store");
+        for header in self.headers {
+            response.header(header);
+        }
+        response.ok()
+    }
+}
+
+/// Headers of a tus request.
+struct TusHeaders {
+    resumable: bool,
+    upload_length: Option<u64>,
+    upload_offset: Option<u64>,
+    upload_metadata: HashMap<String, String>,
+    is_offset_stream: bool,
+    max_size: ByteUnit,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let headers = req.headers();
+        request::Outcome::Success(TusHeaders {
+            resumable: headers.get_one("Tus-Resumable") == Some(TUS_VERSION),
+            upload_length: headers
+                .get_one("Upload-Length")
+                .and_then(|length| length.parse().ok()),
+            upload_offset: headers
+                .get_one("Upload-Offset")
+                .and_then(|offset| offset.parse().ok()),
+            upload_metadata: headers
+                .get_one("Upload-Metadata")
+                .map(parse_metadata)
+                .unwrap_or_default(),
+            is_offset_stream: headers.get_one("Content-Type")
+                == Some("application/offset+octet-stream"),
+            max_size: req.limits().get("resumable").unwrap_or(Limits::FILE),
+        })
+    }
+}
+
+/// Parse the `Upload-Metadata` header, a list of keys and base64 encoded values.
+fn parse_metadata(header: &str) -> HashMap<String, String> {
+    header
+        .split(',')
+        .filter_map(|pair| {
+            let mut pair = pair.trim().splitn(2, ' ');
+            let key = pair.next()?.to_string();
+            let value = base64::decode(pair.next().unwrap_or("")).ok()?;
+            Some((key, String::from_utf8(value).ok()?))
+        })
+        .collect()
+}
+
+/// Ids of the uploads currently receiving data, so two requests cannot write the same upload.
+#[derive(Default)]
+struct UploadLocks(Mutex<HashSet<String>>);
+
+/// Lock on an upload, released when dropped.
+struct UploadLock<'a> {
+    locks: &'a UploadLocks,
+    id: String,
+}
+
+impl UploadLocks {
+    fn lock(&self, id: &FileId) -> Option<UploadLock<'_>> {
+        let mut locked = self.0.lock().unwrap();
+        locked.insert(id.get_id().to_string()).then(|| UploadLock {
+            locks: self,
+            id: id.get_id().to_string(),
+        })
+    }
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.locks.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Compute the path of the partial file of an upload.
+fn partial_path(upload_directory: &str, id: &str) -> PathBuf {
+    Path::new(upload_directory).join(format!("{}.part", id))
+}
+
+/// Read the offset of an upload, the size of its partial file at *path*.
+///
+/// The partial file is deleted when the upload is finished or cancelled, the upload is then
+/// not found.
+async fn upload_offset(path: &Path) -> Result<u64, RoxideError> {
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(RoxideError::NotFound),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Function that describes the tus protocol supported by the server.
+#[options("/tus/<_>")]
+fn options(headers: TusHeaders) -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", headers.max_size.as_u64())
+}
+
+/// Function that describes the tus protocol supported by the server, for an upload.
+#[options("/tus/<_>/<_>")]
+fn options_upload(headers: TusHeaders) -> TusResponse {
+    options(headers)
+}
+
+/// Function that creates a new resumable upload.
+///
+/// The metadata `title` (or `filename`), `duration`, `unlisted`, `visibility`, `max_downloads`,
+/// `password`, `require_signature` and `collection` have the same meaning as the fields of the
+/// form of `/post/<token>`. The password is hashed right away, it is never stored. This function
+/// checks the following:
+/// - the token is valid.
+/// - the length is declared and below the `resumable` limit.
+/// - the duration is correct.
+/// - the maximum number of downloads, if any, is positive.
+/// - the collection, if any, was created by the token and has not expired.
+/// - the token did not upload too much.
+///
+/// The id of the upload is the id of the file once the upload is finished.
+#[post("/tus/<token>")]
+async fn create(
+    app_config: &State<AppConfig>,
+    storage: &State<Storage>,
+    mut db: Connection<Canard>,
+    token: &str,
+    headers: TusHeaders,
+) -> Result<TusResponse, RoxideError> {
+    if !headers.resumable {
+        return Ok(TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION));
+    }
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    let upload_length = match headers.upload_length {
+        Some(upload_length) => upload_length,
+        None => return Ok(TusResponse::new(Status::BadRequest)),
+    };
+    if upload_length > headers.max_size.as_u64() {
+        return Ok(TusResponse::new(Status::PayloadTooLarge));
+    }
+
+    let metadata = &headers.upload_metadata;
+    let title = metadata
+        .get("title")
+        .or_else(|| metadata.get("filename"))
+        .map_or("", |title| title.as_str());
+    let duration = match metadata.get("duration").map(|duration| duration.parse()) {
+        Some(Ok(duration)) => Some(duration),
+        Some(Err(_)) => return Ok(TusResponse::new(Status::BadRequest)),
+        None => None,
+    };
+    let visibility = match metadata.get("visibility") {
+        Some(visibility) => Visibility::parse(visibility)?,
+        None => Visibility::from_public(
+            metadata
+                .get("unlisted")
+                .map_or(true, |unlisted| unlisted != "true"),
+        ),
+    };
+    let max_downloads = match metadata.get("max_downloads").map(|max| max.parse()) {
+        Some(Ok(max_downloads)) => Some(max_downloads),
+        Some(Err(_)) => return Ok(TusResponse::new(Status::BadRequest)),
+        None => None,
+    };
+
+    let now = Utc::now().timestamp();
+    expiration_date(app_config, now, duration)?;
+    check_max_downloads(max_downloads)?;
+    let collection = metadata.get("collection");
+    if let Some(collection) = collection {
+        check_collection(&mut db, collection, token, now).await?;
+    }
+    let require_signature = metadata
+        .get("require_signature")
+        .map_or(false, |require| require == "true");
+    let password_hash = match metadata.get("password") {
+        Some(password) if !password.is_empty() => Some(hash_password(password.clone()).await?),
+        _ => None,
+    };
+    check_upload_rate(app_config, &mut db, token, now).await?;
+
+    let id = new_file_id(app_config, &mut db).await?;
+    File::create(partial_path(&app_config.upload_directory, id.get_id())).await?;
+    sqlx::query(
+        "INSERT INTO uploads (id, title, duration, visibility, token_used, upload_length, creation_date, max_downloads, password_hash, require_signature, collection_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+    )
+    .bind(id.get_id())
+    .bind(title)
+    .bind(duration)
+    .bind(visibility.as_str())
+    .bind(token)
+    .bind(upload_length as i64)
+    .bind(now)
+    .bind(max_downloads)
+    .bind(password_hash)
+    .bind(require_signature)
+    .bind(collection)
+    .execute(&mut *db)
+    .await?;
+
+    // An empty file is already complete
+    if upload_length == 0 {
+        finish(app_config, storage, &mut db, token, &id).await?;
+    }
+
+    Ok(TusResponse::new(Status::Created)
+        .header("Location", format!("/tus/{}/{}", token, id.get_id()))
+        .header("Upload-Offset", 0))
+}
+
+/// Function that returns the offset of an upload.
+#[head("/tus/<token>/<id>")]
+async fn status(
+    app_config: &State<AppConfig>,
+    mut db: Connection<Canard>,
+    token: &str,
+    id: FileId,
+    headers: TusHeaders,
+) -> Result<TusResponse, RoxideError> {
+    if !headers.resumable {
+        return Ok(TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION));
+    }
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    let row = sqlx::query("SELECT upload_length FROM uploads WHERE id = $1 AND token_used = $2")
+        .bind(id.get_id())
+        .bind(token)
+        .fetch_optional(&mut *db)
+        .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(TusResponse::new(Status::NotFound)),
+    };
+
+    let path = partial_path(&app_config.upload_directory, id.get_id());
+    let offset = upload_offset(&path).await?;
+    Ok(TusResponse::new(Status::Ok)
+        .header("Upload-Offset", offset)
+        .header("Upload-Length", row.get::<i64, &str>("upload_length")))
+}
+
+/// Function that appends data to an upload.
+///
+/// The offset of the upload is the size of its partial file, so the data received before a
+/// connection drops is kept. Once all the data is received, the upload becomes a file.
+#[patch("/tus/<token>/<id>", data = "<data>")]
+async fn append(
+    app_config: &State<AppConfig>,
+    storage: &State<Storage>,
+    locks: &State<UploadLocks>,
+    mut db: Connection<Canard>,
+    token: &str,
+    id: FileId,
+    headers: TusHeaders,
+    data: Data<'_>,
+) -> Result<TusResponse, RoxideError> {
+    if !headers.resumable {
+        return Ok(TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION));
+    }
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    if !headers.is_offset_stream {
+        return Ok(TusResponse::new(Status::UnsupportedMediaType));
+    }
+    let row = sqlx::query("SELECT upload_length FROM uploads WHERE id = $1 AND token_used = $2")
+        .bind(id.get_id())
+        .bind(token)
+        .fetch_optional(&mut *db)
+        .await?;
+    let upload_length = match row {
+        Some(row) => row.get::<i64, &str>("upload_length") as u64,
+        None => return Ok(TusResponse::new(Status::NotFound)),
+    };
+    let _lock = match locks.lock(&id) {
+        Some(lock) => lock,
+        None => return Ok(TusResponse::new(Status::Conflict)),
+    };
+
+    let path = partial_path(&app_config.upload_directory, id.get_id());
+    let offset = upload_offset(&path).await?;
+    if headers.upload_offset != Some(offset) {
+        return Ok(TusResponse::new(Status::Conflict));
+    }
+
+    let mut file = OpenOptions::new().append(true).open(&path).await?;
+    let written = data
+        .open((upload_length - offset).bytes())
+        .stream_to(&mut file)
+        .await?;
+    file.flush().await?;
+
+    // More data than declared, the chunk is refused
+    if !written.complete {
+        file.set_len(offset).await?;
+        return Ok(TusResponse::new(Status::PayloadTooLarge));
+    }
+
+    let offset = offset + written.written;
+    if offset == upload_length {
+        finish(app_config, storage, &mut db, token, &id).await?;
+    }
+    Ok(TusResponse::new(Status::NoContent).header("Upload-Offset", offset))
+}
+
+/// Function that cancels an upload and deletes the data received.
+#[delete("/tus/<token>/<id>")]
+async fn terminate(
+    app_config: &State<AppConfig>,
+    locks: &State<UploadLocks>,
+    mut db: Connection<Canard>,
+    token: &str,
+    id: FileId,
+    headers: TusHeaders,
+) -> Result<TusResponse, RoxideError> {
+    if !headers.resumable {
+        return Ok(TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION));
+    }
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    let _lock = match locks.lock(&id) {
+        Some(lock) => lock,
+        None => return Ok(TusResponse::new(Status::Conflict)),
+    };
+    let deleted = sqlx::query("DELETE FROM uploads WHERE id = $1 AND token_used = $2")
+        .bind(id.get_id())
+        .bind(token)
+        .execute(&mut *db)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(TusResponse::new(Status::NotFound));
+    }
+    fs::remove_file(partial_path(&app_config.upload_directory, id.get_id())).await?;
+    Ok(TusResponse::new(Status::NoContent))
+}
+
+/// Function that turns a complete upload into a file, with the same rules as `/post/<token>`.
+///
+/// The collection of the upload may have expired in the meantime, the file is not added to it
+/// then. The upload is only deleted in the transaction that registers the file: if the file
+/// cannot be registered, the upload and its data are kept.
+async fn finish(
+    app_config: &AppConfig,
+    storage: &Storage,
+    db: &mut AnyConnection,
+    token: &str,
+    id: &FileId,
+) -> Result<(), RoxideError> {
+    let path = partial_path(&app_config.upload_directory, id.get_id());
+    let mut tx = db.begin().await?;
+    let row = sqlx::query(
+        "SELECT title, duration, visibility, max_downloads, password_hash, require_signature, collection_id FROM uploads WHERE id = $1",
+    )
+    .bind(id.get_id())
+    .fetch_one(&mut *tx)
+    .await?;
+    let upload = HashedFile::from_kept_path(path.clone()).await?;
+
+    let now = Utc::now().timestamp();
+    let mut expiration = expiration_date(app_config, now, row.get("duration"))?;
+    let mut collection = row.get::<Option<&str>, &str>("collection_id");
+    if let Some(id) = collection {
+        match check_collection(&mut tx, id, token, now).await {
+            Ok(collection_expiration) => expiration = expiration.min(collection_expiration),
+            Err(RoxideError::InvalidCollection) => collection = None,
+            Err(err) => return Err(err),
+        }
+    }
+    let file = NewFile {
+        id,
+        title: row.get::<&str, &str>("title"),
+        token,
+        upload_date: now,
+        expiration,
+        visibility: Visibility::from_column(row.get::<&str, &str>("visibility")),
+        max_downloads: row.get::<Option<i64>, &str>("max_downloads"),
+        password_hash: row.get::<Option<&str>, &str>("password_hash"),
+        require_signature: row.get::<bool, &str>("require_signature"),
+        collection,
+        language: None,
+        paste: false,
+    };
+    register_file(&mut tx, storage, &app_config.sanitize, &file, &upload).await?;
+    sqlx::query("DELETE FROM uploads WHERE id = $1")
+        .bind(id.get_id())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    // The file is registered, the data left behind only takes space
+    if let Err(err) = fs::remove_file(&path).await {
+        eprintln!("Cannot delete finished upload {}: {:?}", id.get_id(), err);
+    }
+    Ok(())
+}
+
+/// Function that deletes the uploads that did not receive all their data in time.
+///
+/// Return the ids of the deleted uploads. A partial file already missing is not an error, and a
+/// partial file that cannot be deleted does not stop the others from being deleted.
+pub async fn clean_abandoned_uploads(
+    db: &mut AnyConnection,
+    upload_directory: &str,
+) -> Result<Vec<String>, RoxideError> {
+    let time_limit = Utc::now().timestamp() - ABANDONED_AFTER;
+    let abandoned_rows = sqlx::query("DELETE FROM uploads WHERE creation_date < $1 RETURNING id")
+        .bind(time_limit)
+        .fetch_all(&mut *db)
+        .await?;
+    let mut abandoned = Vec::new();
+    for id in abandoned_rows
+        .iter()
+        .map(|row| row.get::<String, &str>("id"))
+    {
+        match fs::remove_file(partial_path(upload_directory, &id)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                eprintln!("Cannot delete abandoned upload {}: {:?}", id, err)
+            }
+            _ => abandoned.push(id),
+        }
+    }
+    Ok(abandoned)
+}
+
+/// Function that mounts the routes of the tus protocol for resumable uploads.
+/// - options (to describe the protocol).
+/// - create (to start an upload).
+/// - status (to retrieve the offset of an upload).
+/// - append (to send data).
+/// - terminate (to cancel an upload).
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Tus stage", |rocket| async {
+        rocket.manage(UploadLocks::default()).mount(
+            "/",
+            routes![options, options_upload, create, status, append, terminate],
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rocket::http::Header;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::storage::LocalStorage;
+    use crate::{test_client, test_config};
+
+    #[rocket::async_test]
+    async fn status_of_a_missing_upload_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        for id in ["started", "finished"] {
+            sqlx::query("INSERT INTO uploads (id, title, visibility, token_used, upload_length, creation_date, require_signature) VALUES ($1, 'title', 'public', 'token', 13, $2, FALSE)")
+                .bind(id)
+                .bind(Utc::now().timestamp())
+                .execute(&mut *db)
+                .await
+                .unwrap();
+        }
+        // The partial file of the other upload was deleted in the meantime
+        std::fs::write(
+            partial_path(dir.path().to_str().unwrap(), "started"),
+            b"data",
+        )
+        .unwrap();
+        let client = test_client(dir.path(), routes![status]).await;
+        let status = |id: &str| {
+            client
+                .head(format!("/tus/token/{}", id))
+                .header(Header::new("Tus-Resumable", TUS_VERSION))
+                .dispatch()
+        };
+
+        let response = status("started").await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Upload-Offset"), Some("4"));
+        assert_eq!(response.headers().get_one("Upload-Length"), Some("13"));
+        assert_eq!(status("finished").await.status(), Status::NotFound);
+        assert_eq!(status("missing").await.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn failed_finish_keeps_the_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_config = test_config(dir.path());
+        // The storage is missing, so the content cannot be stored
+        let storage_dir = dir.path().join("storage");
+        let storage: Storage = Arc::new(LocalStorage::new(storage_dir.to_str().unwrap()));
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let id = FileId::from("upload");
+        let path = partial_path(&app_config.upload_directory, id.get_id());
+        std::fs::write(&path, b"complete data").unwrap();
+        sqlx::query("INSERT INTO uploads (id, title, visibility, token_used, upload_length, creation_date, require_signature) VALUES ($1, 'title', 'public', 'token', 13, $2, FALSE)")
+            .bind(id.get_id())
+            .bind(Utc::now().timestamp())
+            .execute(&mut *db)
+            .await
+            .unwrap();
+
+        assert!(finish(&app_config, &storage, &mut db, "token", &id)
+            .await
+            .is_err());
+        let uploads = sqlx::query("SELECT id FROM uploads")
+            .fetch_all(&mut *db)
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert!(path.exists());
+
+        // The upload can be finished once the storage is back
+        std::fs::create_dir(&storage_dir).unwrap();
+        finish(&app_config, &storage, &mut db, "token", &id)
+            .await
+            .unwrap();
+        let uploads = sqlx::query("SELECT id FROM uploads")
+            .fetch_all(&mut *db)
+            .await
+            .unwrap();
+        assert!(uploads.is_empty());
+        assert!(!path.exists());
+        let files = sqlx::query("SELECT title FROM files WHERE id = 'upload'")
+            .fetch_all(&mut *db)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+    }
+}
@@ -1,178 +1,684 @@
-use std::fs;
-use std::path::Path;
-
 use chrono::Utc;
 
 use rocket::fairing::AdHoc;
 use rocket::form::Form;
-use rocket::fs::TempFile;
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
 use rocket::serde::json::Json;
-use rocket::serde::Serialize;
-use rocket::tokio::fs::File;
-use rocket::State;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Request, State};
 
 use rocket_db_pools::Connection;
 
-use sqlx::Row;
+use sqlx::any::{Any, AnyArguments, AnyRow};
+use sqlx::query::Query;
+use sqlx::{Acquire, AnyConnection, Row};
 
-use crate::{is_token_valid, AppConfig, Canard, FileId, RoxideError};
+use crate::access::{delete_grants, FileAccess, Visibility};
+use crate::blob;
+use crate::collection::check_collection;
+use crate::download::{Download, DownloadHeaders, Validators};
+use crate::hashed_file::HashedFile;
+use crate::password::{check_unlocked, hash_password};
+use crate::rendition::{RenditionCache, RenditionRequest};
+use crate::sanitize::{sanitize, SanitizeConfig};
+use crate::search::{delete_index, index_file, rename_file};
+use crate::share::delete_links;
+use crate::storage::Storage;
+use crate::tags::{delete_tags, file_tags, normalize_tag, parse_tags, set_tags};
+use crate::{bearer_token, is_token_valid, AppConfig, Canard, FileId, RoxideError};
 
 //Structure use to receive the form that post a file.
 #[derive(Debug, FromForm)]
-struct UploadFile<'f> {
-    upload: TempFile<'f>,
+struct UploadFile {
+    upload: HashedFile,
     title: String,
     duration: Option<i64>,
     unlisted: Option<bool>,
+    visibility: Option<String>,
+    max_downloads: Option<i64>,
+    password: Option<String>,
+    require_signature: Option<bool>,
+    collection: Option<String>,
+    tags: Vec<String>,
 }
 
-/// Function that process a new posted file.
-///
-/// This function checks the following:
-/// - the token is valid.
-/// - the duration is correct.
-///
-#[post("/post/<token>", data = "<upload_form>")]
-async fn post(
-    app_config: &State<AppConfig>,
-    mut db: Connection<Canard>,
-    token: &str,
-    mut upload_form: Form<UploadFile<'_>>,
-) -> Result<String, RoxideError> {
-    if !is_token_valid(token, app_config) {
-        return Err(RoxideError::Roxide("Token not valid".to_string()));
-    }
+/// A file about to be registered in the database.
+pub struct NewFile<'a> {
+    pub id: &'a FileId,
+    pub title: &'a str,
+    pub token: &'a str,
+    pub upload_date: i64,
+    pub expiration: i64,
+    pub visibility: Visibility,
+    pub max_downloads: Option<i64>,
+    pub password_hash: Option<&'a str>,
+    pub require_signature: bool,
+    pub collection: Option<&'a str>,
+    pub language: Option<&'a str>,
+    /// The content is a paste, stored as UTF-8 plain text whatever it looks like.
+    pub paste: bool,
+}
+
+/// Settings of a file sent with its content, by the upload form or by a paste.
+pub struct UploadSettings<'a> {
+    pub title: &'a str,
+    pub duration: Option<i64>,
+    pub unlisted: Option<bool>,
+    pub visibility: Option<&'a str>,
+    pub max_downloads: Option<i64>,
+    pub password: Option<&'a str>,
+    pub require_signature: Option<bool>,
+    pub collection: Option<&'a str>,
+    pub language: Option<&'a str>,
+    pub paste: bool,
+    pub tags: &'a [String],
+}
+
+/// Time during which the tombstone of an expired file is kept, in seconds.
+const TOMBSTONE_DURATION: i64 = 30 * 24 * 3600;
+
+/// Content type of the uploads that are text without a known format, like the pastes.
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Function that generates an id used neither by a file, nor by a resumable upload, nor by a
+/// collection, nor by the tombstone of an expired file.
+pub async fn new_file_id(
+    app_config: &AppConfig,
+    db: &mut AnyConnection,
+) -> Result<FileId, RoxideError> {
     let mut id = FileId::new(app_config.id_length);
-    while Path::new(&id.file_path(&app_config.upload_directory)).exists() {
+    while sqlx::query(
+        "SELECT id FROM files WHERE id = $1 UNION SELECT id FROM uploads WHERE id = $1 UNION SELECT id FROM collections WHERE id = $1 UNION SELECT id FROM tombstones WHERE id = $1",
+    )
+    .bind(id.get_id())
+    .fetch_optional(&mut *db)
+    .await?
+    .is_some()
+    {
         id = FileId::new(app_config.id_length);
     }
+    Ok(id)
+}
 
-    let now = Utc::now().timestamp();
-    let duration = upload_form.duration.unwrap_or(app_config.default_duration);
+/// Function that computes the expiration date of a file uploaded at *now*.
+///
+/// Without *duration*, the default duration of the configuration is used (up to
+/// `max_duration`). A duration longer than `max_duration` is not valid.
+pub fn expiration_date(
+    app_config: &AppConfig,
+    now: i64,
+    duration: Option<i64>,
+) -> Result<i64, RoxideError> {
+    let duration =
+        duration.unwrap_or_else(|| app_config.default_duration.min(app_config.max_duration));
     let expiration = now.saturating_add(duration);
-    if expiration < now {
-        return Err(RoxideError::Roxide("Expired file".to_string()));
+    if expiration < now || duration > app_config.max_duration {
+        return Err(RoxideError::InvalidDuration);
     }
-    let size;
-    let content_type;
-    if let Some(file_path) = upload_form.upload.path() {
-        let kind = infer::get_from_path(file_path).expect("file read successfully");
+    Ok(expiration)
+}
 
-        content_type = kind.map_or("unknown", |s| s.mime_type());
-        let metadata = fs::metadata(file_path)?;
-        size = metadata.len() as i64;
-    } else {
-        return Err(RoxideError::Roxide("No path to the file".to_string()));
+/// Function that checks the number of downloads after which a file is deleted, if any.
+pub fn check_max_downloads(max_downloads: Option<i64>) -> Result<Option<i64>, RoxideError> {
+    match max_downloads {
+        Some(max_downloads) if max_downloads <= 0 => Err(RoxideError::InvalidMaxDownloads),
+        max_downloads => Ok(max_downloads),
     }
+}
 
-    //Retrieve the database entry
+/// Function that checks that a token did not upload more than `max_upload` files in the last
+/// hour.
+///
+/// Resumable uploads still in progress count as uploads.
+pub async fn check_upload_rate(
+    app_config: &AppConfig,
+    db: &mut AnyConnection,
+    token: &str,
+    now: i64,
+) -> Result<(), RoxideError> {
     let time_limit = now - 3600;
     let db_count = sqlx::query(
-        "SELECT count(1) AS count FROM files WHERE token_used = $1 AND upload_date > $2",
+        "SELECT (SELECT count(1) FROM files WHERE token_used = $1 AND upload_date > $2) + (SELECT count(1) FROM uploads WHERE token_used = $1 AND creation_date > $2) AS count",
     )
     .bind(token)
-    .bind(&time_limit)
+    .bind(time_limit)
     .fetch_one(&mut *db)
     .await?;
 
     let count = db_count.get::<i64, &str>("count") as usize;
     if count >= app_config.max_upload {
-        return Err(RoxideError::Roxide("Too much upload".to_string()));
+        return Err(RoxideError::TooManyUploads);
     }
+    Ok(())
+}
+
+/// Content of a registered file.
+pub struct StoredContent {
+    pub content_type: &'static str,
+    pub digest: String,
+}
 
-    // Set if the the file is public from the unlisted parameter
-    let public = !upload_form.unlisted.unwrap_or(false);
+/// Function that stores the content of an upload and registers it in the files table.
+///
+/// The metadata of the images are removed first if the configuration asks for it, the sanitized
+/// image is stored instead of the upload. An upload whose format is not recognized but that is
+/// text is stored as UTF-8 plain text.
+pub async fn register_file(
+    db: &mut AnyConnection,
+    storage: &Storage,
+    sanitize_config: &SanitizeConfig,
+    file: &NewFile<'_>,
+    upload: &HashedFile,
+) -> Result<StoredContent, RoxideError> {
+    let content_type = if file.paste {
+        TEXT_CONTENT_TYPE
+    } else {
+        match infer::get_from_path(upload.path())? {
+            Some(kind) => kind.mime_type(),
+            None if upload.is_text().await? => TEXT_CONTENT_TYPE,
+            None => "unknown",
+        }
+    };
+
+    let sanitized = if sanitize_config.applies_to(file.token) {
+        sanitize(sanitize_config, upload, content_type).await?
+    } else {
+        None
+    };
+    let upload = sanitized.as_ref().unwrap_or(upload);
+
+    let file_path = upload.path();
+    let size = upload.size() as i64;
+
+    // Reference the blob of the content, it is only stored if no other file has the same content
+    let digest = upload.digest();
+    let created = blob::acquire(&mut *db, digest, size).await?;
+    if created || !storage.exists(digest).await? {
+        let copy = storage.put(digest, file_path).await;
+
+        // Failed to copy, so we release the blob then we propagate the error
+        if let Err(copy) = copy {
+            let _ = blob::release(&mut *db, storage, digest).await;
+            return Err(RoxideError::IO(copy));
+        }
+    }
 
     // Insert the new entry to the database
-    sqlx::query(
-        "INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, public, title) VALUES ($1, $2, $3, $4, $5, $6, 0, $7, $8) RETURNING *",
+    let insert = sqlx::query(
+        "INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, visibility, title, blob, max_downloads, password_hash, require_signature, collection_id, language) VALUES ($1, $2, $3, $4, $5, $6, 0, $7, $8, $9, $10, $11, $12, $13, $14)",
     )
-    .bind(id.get_id())
-    .bind(&expiration)
-    .bind(&now)
-    .bind(&token)
+    .bind(file.id.get_id())
+    .bind(file.expiration)
+    .bind(file.upload_date)
+    .bind(file.token)
     .bind(content_type)
-    .bind(&size)
-    .bind(&public)
-    .bind(&upload_form.title)
+    .bind(size)
+    .bind(file.visibility.as_str())
+    .bind(file.title)
+    .bind(digest)
+    .bind(file.max_downloads)
+    .bind(file.password_hash)
+    .bind(file.require_signature)
+    .bind(file.collection)
+    .bind(file.language)
     .execute(&mut *db)
-    .await?;
+    .await;
 
-    //If everything went nicely, copy the file
-    let copy = upload_form
-        .upload
-        .copy_to(id.file_path(&app_config.upload_directory))
-        .await;
+    // Failed to insert, so the blob loses the reference we took
+    if let Err(insert) = insert {
+        blob::release(&mut *db, storage, digest).await?;
+        return Err(insert.into());
+    }
 
-    // Failed to copy, so we delete entry from database then we propagate the error
-    if let Err(copy) = copy {
-        sqlx::query("DELETE files WHERE id = $1")
-            .bind(id.get_id())
-            .execute(&mut *db)
-            .await?;
+    // A file missing from the index is only missing from the results of the searches
+    if let Err(err) = index_file(&mut *db, file, content_type, file_path).await {
+        eprintln!("Cannot index {}: {:?}", file.id.get_id(), err);
+    }
+    Ok(StoredContent {
+        content_type,
+        digest: digest.to_string(),
+    })
+}
+
+/// Function that deletes the file *id* and releases its blob.
+///
+/// Both happen in one transaction, the row is kept if the content cannot be deleted. Return the
+/// digest of the blob if its content has been deleted, because no other file references it.
+pub async fn delete_file(
+    db: &mut AnyConnection,
+    storage: &Storage,
+    id: &FileId,
+) -> Result<Option<String>, RoxideError> {
+    let mut tx = db.begin().await?;
+    let row = sqlx::query("DELETE FROM files WHERE id = $1 RETURNING blob")
+        .bind(id.get_id())
+        .fetch_one(&mut *tx)
+        .await?;
+    delete_grants(&mut tx, id).await?;
+    delete_links(&mut tx, id).await?;
+    delete_index(&mut tx, id).await?;
+    delete_tags(&mut tx, id).await?;
+    let digest = row.get::<String, &str>("blob");
+    let deleted = blob::release(&mut tx, storage, &digest).await?;
+    tx.commit().await?;
+    Ok(deleted.then_some(digest))
+}
+
+/// Function that deletes the file *id* because it expired or reached its number of downloads.
+///
+/// A tombstone of its id is kept for 30 days, so that requests for the file keep getting 410
+/// rather than 404. A private file, or a file that requires a signature, gets no tombstone: the
+/// requesters could not tell it existed before, they cannot tell it once it is deleted.
+pub async fn expire_file(
+    db: &mut AnyConnection,
+    storage: &Storage,
+    id: &FileId,
+) -> Result<Option<String>, RoxideError> {
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO tombstones (id, deletion_date) SELECT id, $2 FROM files WHERE id = $1 AND visibility <> 'private' AND NOT require_signature ON CONFLICT DO NOTHING")
+        .bind(id.get_id())
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+    let deleted = delete_file(&mut tx, storage, id).await?;
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+/// Function that tells whether the file *id* has been deleted because it expired, and still has
+/// its tombstone.
+async fn is_tombstone(db: &mut AnyConnection, id: &FileId) -> Result<bool, RoxideError> {
+    Ok(sqlx::query("SELECT id FROM tombstones WHERE id = $1")
+        .bind(id.get_id())
+        .fetch_optional(&mut *db)
+        .await?
+        .is_some())
+}
 
-        return Err(RoxideError::IO(copy));
+/// Function that deletes the tombstones older than 30 days: their ids answer 404 again, and can
+/// be given to new files.
+pub async fn clean_tombstones(db: &mut AnyConnection) -> Result<(), RoxideError> {
+    sqlx::query("DELETE FROM tombstones WHERE deletion_date <= $1")
+        .bind(Utc::now().timestamp() - TOMBSTONE_DURATION)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Function that checks an upload of *token* and registers it as a new file.
+///
+/// This function checks the following:
+/// - the token is valid.
+/// - the duration is correct.
+/// - the maximum number of downloads, if any, is positive.
+/// - the collection, if any, was created by the token and has not expired.
+/// - the tags are valid.
+/// - the token did not upload too much.
+///
+/// A file added to a collection expires at the latest with the collection.
+pub async fn upload_file(
+    app_config: &AppConfig,
+    storage: &Storage,
+    db: &mut AnyConnection,
+    token: &str,
+    settings: &UploadSettings<'_>,
+    upload: &HashedFile,
+) -> Result<(FileId, StoredContent), RoxideError> {
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    let id = new_file_id(app_config, db).await?;
+
+    let now = Utc::now().timestamp();
+    let mut expiration = expiration_date(app_config, now, settings.duration)?;
+    let max_downloads = check_max_downloads(settings.max_downloads)?;
+    if let Some(collection) = settings.collection {
+        expiration = expiration.min(check_collection(db, collection, token, now).await?);
+    }
+    let tags = parse_tags(settings.tags)?;
+    check_upload_rate(app_config, db, token, now).await?;
+
+    // Set the visibility of the file, from the unlisted parameter for the previous clients
+    let visibility = match settings.visibility {
+        Some(visibility) => Visibility::parse(visibility)?,
+        None => Visibility::from_public(!settings.unlisted.unwrap_or(false)),
+    };
+
+    // An empty password leaves the file unprotected
+    let password_hash = match settings.password {
+        Some(password) if !password.is_empty() => Some(hash_password(password.to_string()).await?),
+        _ => None,
+    };
+
+    let file = NewFile {
+        id: &id,
+        title: settings.title,
+        token,
+        upload_date: now,
+        expiration,
+        visibility,
+        max_downloads,
+        password_hash: password_hash.as_deref(),
+        require_signature: settings.require_signature.unwrap_or(false),
+        collection: settings.collection,
+        language: settings.language,
+        paste: settings.paste,
+    };
+    // The file and its tags are registered together
+    let mut tx = db.begin().await?;
+    let stored = register_file(&mut tx, storage, &app_config.sanitize, &file, upload).await?;
+    if !tags.is_empty() {
+        set_tags(&mut tx, &id, &tags).await?;
+    }
+    tx.commit().await?;
+    Ok((id, stored))
+}
+
+/// Function that process a new posted file (see upload_file).
+///
+/// The thumbnail of an image is created right after the upload, unless the number of downloads
+/// of the file is limited.
+#[post("/post/<token>", data = "<upload_form>")]
+async fn post(
+    app_config: &State<AppConfig>,
+    storage: &State<Storage>,
+    renditions: &State<RenditionCache>,
+    mut db: Connection<Canard>,
+    token: &str,
+    upload_form: Form<UploadFile>,
+) -> Result<String, RoxideError> {
+    let settings = UploadSettings {
+        title: &upload_form.title,
+        duration: upload_form.duration,
+        unlisted: upload_form.unlisted,
+        visibility: upload_form.visibility.as_deref(),
+        max_downloads: upload_form.max_downloads,
+        password: upload_form.password.as_deref(),
+        require_signature: upload_form.require_signature,
+        collection: upload_form.collection.as_deref(),
+        language: None,
+        paste: false,
+        tags: &upload_form.tags,
+    };
+    let (id, stored) = upload_file(
+        app_config,
+        storage,
+        &mut db,
+        token,
+        &settings,
+        &upload_form.upload,
+    )
+    .await?;
+
+    // Prepare the thumbnail of images in the background, so that it is ready when displayed
+    let rendition = RenditionRequest::thumbnail().resolve(stored.content_type);
+    if let (Ok(rendition), None) = (rendition, upload_form.max_downloads) {
+        let storage = Storage::clone(storage);
+        let renditions = RenditionCache::clone(renditions);
+        let digest = stored.digest;
+        rocket::tokio::spawn(async move {
+            if let Err(err) = renditions.render(&storage, &digest, &rendition).await {
+                eprintln!("Cannot create the thumbnail of {}: {:?}", digest, err);
+            }
+        });
     }
     Ok(id.get_id().to_string())
 }
 
 /// Function that retrieve and return a file based on its id.
 ///
-/// An error is return if the id doesn't exist or if the file has expired. In the case of an
-/// expired file, the function triggers a cleanning of the database.
-#[get("/get/<id>")]
+/// An error is return if the id doesn't exist (404) or if the file has expired (410). In the case
+/// of an expired file, the function deletes it.
+///
+/// The function supports ranges (`Range` and `If-Range`) and conditional requests
+/// (`If-None-Match` and `If-Modified-Since`). A download is counted only when the beginning of
+/// the file is sent: a whole file or a range starting at the first byte. Answers that the cached
+/// copy is still valid are not counted, neither are the ranges used to seek in a file.
+///
+/// For images, the query can ask for a resized or re-encoded version
+/// (`?w=<width>&h=<height>&fit=<contain|cover|fill>&format=<png|jpeg|webp>`). These versions are
+/// not counted as downloads.
+///
+/// A file whose number of downloads is limited expires with its last download, later requests
+/// get 410. Every request that sends its content counts (including its resized versions), and
+/// ranges and conditional requests are ignored, so that the content cannot be read without being
+/// counted.
+///
+/// A private file is only sent to its uploader and to the tokens it is shared with, sent as a
+/// bearer token (`Authorization: Bearer <token>`). For the other requests, it does not exist
+/// (404).
+///
+/// A share link (`?exp=<expiration>&sig=<signature>`, see `/file/<id>/sign`) gives access to
+/// the file until it expires, whatever its visibility. A file uploaded with `require_signature`
+/// is only sent through a share link (403 otherwise).
+///
+/// A password-protected file is sent once unlocked, by the cookie set by `/unlock/<id>` or by
+/// its password in the `X-File-Password` header. Otherwise the request gets 401, or 403 with a
+/// wrong password.
+#[get("/get/<id>?<rendition..>")]
 async fn get(
-    app_config: &State<AppConfig>,
+    storage: &State<Storage>,
+    renditions: &State<RenditionCache>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    headers: DownloadHeaders<'_>,
+    access: FileAccess<'_>,
+    rendition: RenditionRequest,
+) -> Result<Download, RoxideError> {
+    let rendition = (!rendition.is_original()).then_some(rendition);
+    download(storage, renditions, &mut db, id, headers, access, rendition).await
+}
+
+/// Function that retrieve the thumbnail of an image, an image that fits in a 256x256 box.
+#[get("/thumbnail/<id>")]
+async fn thumbnail(
+    storage: &State<Storage>,
+    renditions: &State<RenditionCache>,
     mut db: Connection<Canard>,
     id: FileId,
-) -> Result<(ContentType, File), RoxideError> {
+    headers: DownloadHeaders<'_>,
+    access: FileAccess<'_>,
+) -> Result<Download, RoxideError> {
+    let rendition = RenditionRequest::thumbnail();
+    download(
+        storage,
+        renditions,
+        &mut db,
+        id,
+        headers,
+        access,
+        Some(rendition),
+    )
+    .await
+}
+
+/// A file the requester is allowed to download.
+pub struct OpenedFile {
+    pub title: String,
+    pub content_type: String,
+    pub digest: String,
+    pub size: i64,
+    pub upload_date: i64,
+    pub expiration_date: i64,
+    pub download_count: i64,
+    pub max_downloads: Option<i64>,
+    pub language: Option<String>,
+}
+
+/// Function that checks that the requester can see the file *id*, with the rules of get: its
+/// visibility, its expiration date and its number of downloads. Return the row of the file, whose
+/// password is not checked.
+///
+/// An expired file is deleted. Once deleted, a file that expired still gets 410 as long as it has
+/// its tombstone.
+pub async fn find_file(
+    storage: &Storage,
+    db: &mut AnyConnection,
+    id: &FileId,
+    access: &FileAccess<'_>,
+) -> Result<AnyRow, RoxideError> {
     //Retrieve the database entry
-    let row = sqlx::query("SELECT expiration_date, content_type FROM files WHERE id = $1")
-        .bind(id.get_id())
-        .fetch_one(&mut *db)
+    let row = sqlx::query(
+        "SELECT expiration_date, upload_date, content_type, blob, size, title, download_count, max_downloads, password_hash, token_used, visibility, require_signature, language FROM files WHERE id = $1",
+    )
+    .bind(id.get_id())
+    .fetch_optional(&mut *db)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None if is_tombstone(db, id).await? => return Err(RoxideError::Expired),
+        None => return Err(RoxideError::NotFound),
+    };
+    let visibility = Visibility::from_column(row.get::<&str, &str>("visibility"));
+    let token_used = row.get::<&str, &str>("token_used");
+    let requires_signature = row.get::<bool, &str>("require_signature");
+    access
+        .check(db, id, visibility, token_used, requires_signature)
         .await?;
-    let expiration_date = row.get::<i64, &str>("expiration_date");
     let now = Utc::now().timestamp();
 
-    //Check expiration date and clean the database if expired
-    if expiration_date <= now {
-        // FIXME ok, this a copy-paste of clean_expired_files as Rust and Rocket won't allow to
-        // passe connections through async function.
-
-        //Select all expired files
-        let expired_rows = sqlx::query("SELECT id FROM files WHERE expiration_date < $1")
-            .bind(&now)
-            .fetch_all(&mut **db)
-            .await?;
-
-        //Iterate over the row to delete the files
-        for id in expired_rows.iter().map(|row| row.get::<&str, &str>("id")) {
-            let id = FileId::from(id);
-            fs::remove_file(id.file_path(&app_config.upload_directory))?;
+    //Check expiration date and delete the file if expired
+    if row.get::<i64, &str>("expiration_date") <= now {
+        match expire_file(db, storage, id).await {
+            // Deleted in the meantime
+            Ok(_) | Err(RoxideError::NotFound) => {}
+            Err(err) => eprintln!("Cannot delete {}: {:?}", id.get_id(), err),
         }
+        return Err(RoxideError::Expired);
+    }
 
-        //Delete the expired files from the database
-        sqlx::query("DELETE FROM files WHERE expiration_date < $1")
-            .bind(&now)
-            .execute(&mut **db)
-            .await?;
+    let max_downloads = row.get::<Option<i64>, &str>("max_downloads");
+    let download_count = row.get::<i64, &str>("download_count");
+    if let Some(max_downloads) = max_downloads {
+        if download_count >= max_downloads {
+            return Err(RoxideError::Expired);
+        }
     }
+    Ok(row)
+}
 
-    let content_type = ContentType::parse_flexible(row.get::<&str, &str>("content_type"))
-        .unwrap_or(ContentType::Any);
+/// Function that checks that the requester can download the file *id*, with the rules of get:
+/// the rules of `find_file`, and its password.
+pub async fn open_file(
+    storage: &Storage,
+    db: &mut AnyConnection,
+    id: &FileId,
+    access: &FileAccess<'_>,
+) -> Result<OpenedFile, RoxideError> {
+    let row = find_file(storage, db, id, access).await?;
+    if let Some(hash) = row.get::<Option<&str>, &str>("password_hash") {
+        check_unlocked(db, id, hash, access.unlock()).await?;
+    }
+    Ok(OpenedFile {
+        title: row.get::<String, &str>("title"),
+        content_type: row.get::<String, &str>("content_type"),
+        digest: row.get::<String, &str>("blob"),
+        size: row.get::<i64, &str>("size"),
+        upload_date: row.get::<i64, &str>("upload_date"),
+        expiration_date: row.get::<i64, &str>("expiration_date"),
+        download_count: row.get::<i64, &str>("download_count"),
+        max_downloads: row.get::<Option<i64>, &str>("max_downloads"),
+        language: row.get::<Option<String>, &str>("language"),
+    })
+}
 
-    //Delete the expired files from the database
+/// Function that counts a download of the opened *file* *id*.
+///
+/// The last allowed download of a limited file deletes it, the content must be opened before.
+pub async fn count_download(
+    storage: &Storage,
+    db: &mut AnyConnection,
+    id: &FileId,
+    file: &OpenedFile,
+) -> Result<(), RoxideError> {
+    if file.max_downloads.is_some() {
+        return count_limited_download(storage, db, id).await;
+    }
     sqlx::query("UPDATE files SET download_count = download_count+1 WHERE id = $1")
         .bind(id.get_id())
-        .execute(&mut **db)
+        .execute(&mut *db)
         .await?;
+    Ok(())
+}
 
-    //ContentType is set to PNG as there is no ContentType that designates a file in general.
-    Ok((
-        content_type,
-        File::open(id.file_path(&app_config.upload_directory)).await?,
-    ))
+/// Function that sends a file, or a rendition of it (see get).
+pub async fn download(
+    storage: &Storage,
+    renditions: &RenditionCache,
+    db: &mut AnyConnection,
+    id: FileId,
+    headers: DownloadHeaders<'_>,
+    access: FileAccess<'_>,
+    rendition: Option<RenditionRequest>,
+) -> Result<Download, RoxideError> {
+    let file = open_file(storage, db, &id, &access).await?;
+    let headers = match file.max_downloads {
+        Some(_) => DownloadHeaders::default(),
+        None => headers,
+    };
+
+    let counted = rendition.is_none();
+    let download = if let Some(rendition) = rendition {
+        let rendition = rendition.resolve(&file.content_type)?;
+        let validators = Validators::new(
+            &format!("{}-{}", file.digest, rendition.name()),
+            file.upload_date,
+        );
+        if headers.is_not_modified(&validators) {
+            return Ok(Download::NotModified { validators });
+        }
+        let key = renditions.render(storage, &file.digest, &rendition).await?;
+        let content_type = rendition.content_type();
+        Download::from_storage(
+            renditions.storage(),
+            &key,
+            content_type,
+            validators,
+            &headers,
+        )
+        .await?
+    } else {
+        let validators = Validators::new(&file.digest, file.upload_date);
+        if headers.is_not_modified(&validators) {
+            return Ok(Download::NotModified { validators });
+        }
+        let content_type =
+            ContentType::parse_flexible(&file.content_type).unwrap_or(ContentType::Any);
+        Download::from_storage(&**storage, &file.digest, content_type, validators, &headers).await?
+    };
+
+    //Count every download of a limited file, the others once the beginning of the file is sent
+    if file.max_downloads.is_some() || (counted && download.is_from_start()) {
+        count_download(storage, db, &id, &file).await?;
+    }
+    Ok(download)
+}
+
+/// Function that counts a download of the file *id*, whose number of downloads is limited.
+///
+/// The download is counted only if the limit is not reached yet, in a single statement, so that
+/// concurrent requests cannot send the file more times than allowed: the requests that lose the
+/// race get 410. The download that reaches the limit deletes the file with its content, in the
+/// same transaction. It still succeeds, its content being already opened.
+async fn count_limited_download(
+    storage: &Storage,
+    db: &mut AnyConnection,
+    id: &FileId,
+) -> Result<(), RoxideError> {
+    let mut tx = db.begin().await?;
+    let row = sqlx::query(
+        "UPDATE files SET download_count = download_count+1 WHERE id = $1 AND download_count < max_downloads RETURNING download_count, max_downloads",
+    )
+    .bind(id.get_id())
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(RoxideError::Expired)?;
+    if row.get::<i64, &str>("download_count") >= row.get::<i64, &str>("max_downloads") {
+        expire_file(&mut tx, storage, id).await?;
+    }
+    tx.commit().await?;
+    Ok(())
 }
 
 #[derive(Debug, Serialize)]
@@ -184,82 +690,826 @@ pub struct FileData {
     download_count: i64,
     size: i64,
     title: String,
+    /// The file needs a password to be downloaded.
+    protected: bool,
+    tags: Vec<String>,
 }
 
-type ListFiles = Vec<FileData>;
-
-#[get("/list/<token>")]
-async fn list(
-    app_config: &State<AppConfig>,
-    mut db: Connection<Canard>,
-    token: &str,
-) -> Result<Json<ListFiles>, RoxideError> {
-    if !is_token_valid(token, app_config) {
-        return Err(RoxideError::Roxide("Token not valid".to_string()));
-    }
-    //Retrieve the database entry
-    let now = Utc::now().timestamp();
-    let public_files = sqlx::query("SELECT id, upload_date, content_type, download_count, size, title FROM files WHERE public = true AND expiration_date > $1")
-        .bind(&now)
-        .fetch_all(&mut *db)
-        .await?;
-
-    let it = public_files
-        .iter()
-        .map(|row| FileData {
+impl FileData {
+    /// Read a file from a row with the columns of `FileData`, `protected` being
+    /// `password_hash IS NOT NULL`.
+    pub fn from_row(row: &AnyRow) -> Self {
+        Self {
             id: row.get::<String, &str>("id"),
             upload_date: row.get::<i64, &str>("upload_date"),
             content_type: row.get::<String, &str>("content_type"),
             download_count: row.get::<i64, &str>("download_count"),
             size: row.get::<i64, &str>("size"),
             title: row.get::<String, &str>("title"),
+            protected: row.get::<bool, &str>("protected"),
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// Function that reads the tags of *files*.
+pub async fn add_tags(db: &mut AnyConnection, files: &mut [FileData]) -> Result<(), RoxideError> {
+    let ids = files
+        .iter()
+        .map(|file| file.id.as_str())
+        .collect::<Vec<_>>();
+    let mut tags = file_tags(db, &ids).await?;
+    for file in files {
+        file.tags = tags.remove(&file.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Number of files of a page of `/list/<token>`, by default.
+const LIST_LIMIT: i64 = 50;
+
+/// Largest number of files of a page of `/list/<token>`.
+const MAX_LIST_LIMIT: i64 = 500;
+
+/// Column by which `/list/<token>` sorts the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+enum SortKey {
+    #[field(value = "upload_date")]
+    UploadDate,
+    Size,
+    #[field(value = "download_count")]
+    DownloadCount,
+    Title,
+}
+
+impl SortKey {
+    fn column(self) -> &'static str {
+        match self {
+            SortKey::UploadDate => "upload_date",
+            SortKey::Size => "size",
+            SortKey::DownloadCount => "download_count",
+            SortKey::Title => "title",
+        }
+    }
+
+    /// The order of the files by default: the titles in alphabetical order, the largest values
+    /// first for the other columns.
+    fn default_order(self) -> Order {
+        match self {
+            SortKey::Title => Order::Asc,
+            _ => Order::Desc,
+        }
+    }
+}
+
+/// Direction in which `/list/<token>` sorts the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+enum Order {
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn keyword(self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+}
+
+//Structure use to receive the search, the filters, the order and the page of the list
+#[derive(Debug, FromForm)]
+struct ListQuery {
+    /// Substring of the titles, regardless of the case.
+    q: Option<String>,
+    /// Prefix of the content types, like `image/`.
+    #[field(name = "type")]
+    content_type: Option<String>,
+    uploaded_after: Option<i64>,
+    uploaded_before: Option<i64>,
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    /// Tags of the files, all of them.
+    tag: Vec<String>,
+    sort: Option<SortKey>,
+    order: Option<Order>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+/// Position of a page of the list: the files come after the file *id*, whose sort column has the
+/// value *value*, in the order *order* of *sort*.
+struct Cursor {
+    sort: SortKey,
+    order: Order,
+    id: String,
+    value: SqlValue,
+}
+
+impl Cursor {
+    /// Build the cursor of the page that follows *file*.
+    fn after(sort: SortKey, order: Order, file: &FileData) -> Self {
+        let value = match sort {
+            SortKey::UploadDate => SqlValue::Int(file.upload_date),
+            SortKey::Size => SqlValue::Int(file.size),
+            SortKey::DownloadCount => SqlValue::Int(file.download_count),
+            SortKey::Title => SqlValue::Text(file.title.clone()),
+        };
+        Self {
+            sort,
+            order,
+            id: file.id.clone(),
+            value,
+        }
+    }
+
+    /// Encode the cursor as an opaque string, `<sort>:<order>:<id>:<value>` in base64.
+    fn encode(&self) -> String {
+        let value = match &self.value {
+            SqlValue::Int(value) => value.to_string(),
+            SqlValue::Text(value) => value.clone(),
+        };
+        let cursor = format!(
+            "{}:{}:{}:{}",
+            self.sort.column(),
+            self.order.keyword(),
+            self.id,
+            value
+        );
+        base64::encode_config(cursor, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decode a cursor of the list sorted by *sort* in the order *order*.
+    fn decode(cursor: &str, sort: SortKey, order: Order) -> Result<Self, RoxideError> {
+        let cursor = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|cursor| String::from_utf8(cursor).ok())
+            .ok_or(RoxideError::InvalidCursor)?;
+        let mut parts = cursor.splitn(4, ':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(column), Some(keyword), Some(id), Some(value))
+                if column == sort.column() && keyword == order.keyword() =>
+            {
+                let value = match sort {
+                    SortKey::Title => SqlValue::Text(value.to_string()),
+                    _ => SqlValue::Int(value.parse().map_err(|_| RoxideError::InvalidCursor)?),
+                };
+                Ok(Self {
+                    sort,
+                    order,
+                    id: id.to_string(),
+                    value,
+                })
+            }
+            _ => Err(RoxideError::InvalidCursor),
+        }
+    }
+}
+
+/// Value bound to a query built at run time.
+#[derive(Debug, Clone)]
+enum SqlValue {
+    Int(i64),
+    Text(String),
+}
+
+/// Conditions of a query built at run time, with their values.
+#[derive(Default)]
+struct Conditions {
+    conditions: Vec<String>,
+    values: Vec<SqlValue>,
+}
+
+impl Conditions {
+    /// Add *condition*, whose `?` are replaced by the parameters of *values*, in order.
+    fn push(&mut self, condition: &str, values: Vec<SqlValue>) {
+        let mut values = values.into_iter();
+        let mut parts = condition.split('?');
+        let mut condition = parts.next().unwrap_or("").to_string();
+        for part in parts {
+            self.values.extend(values.next());
+            condition.push_str(&format!("${}{}", self.values.len(), part));
+        }
+        self.conditions.push(condition);
+    }
+
+    fn to_sql(&self) -> String {
+        self.conditions.join(" AND ")
+    }
+
+    /// Bind the values of the conditions to *query*.
+    fn bind<'q>(
+        &'q self,
+        query: Query<'q, Any, AnyArguments<'q>>,
+    ) -> Query<'q, Any, AnyArguments<'q>> {
+        self.values.iter().fold(query, |query, value| match value {
+            SqlValue::Int(value) => query.bind(*value),
+            SqlValue::Text(value) => query.bind(value.as_str()),
         })
-        .collect::<ListFiles>();
+    }
+}
 
-    Ok(Json(it))
+/// Escape the wildcards of *pattern*, for a `LIKE` with `ESCAPE '\'`.
+fn escape_like(pattern: &str) -> String {
+    pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
 
-/// Function that clean the database from expired files.
-#[get("/clean")]
-async fn clean(app_config: &State<AppConfig>, db: Connection<Canard>) -> Option<File> {
-    clean_expired_files(app_config, db).await.unwrap();
-    None
+/// A page of the list of the public files.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct FileList {
+    files: Vec<FileData>,
+    /// Cursor of the next page, if there are more files.
+    next_cursor: Option<String>,
+    /// Number of files matching the search and the filters, in every page.
+    total: i64,
 }
 
-/// Function that clean the database from expired files (it is called by clean and get).
-async fn clean_expired_files(
+/// Function that lists the public files, a page at a time.
+///
+/// The files can be searched by title (`q`, regardless of the case) and filtered by content
+/// type prefix (`type`), upload date (`uploaded_after` and `uploaded_before`) and size
+/// (`min_size` and `max_size`), bounds included. They are sorted by `sort` (`upload_date` by
+/// default, `size`, `download_count` or `title`) in the order `order` (`asc` or `desc`), and the
+/// ties by id. A page has `limit` files (50 by default, up to 500), the next one is given by
+/// the `cursor` returned with the page, with the same query.
+#[get("/list/<token>?<query..>")]
+async fn list(
     app_config: &State<AppConfig>,
     mut db: Connection<Canard>,
-) -> Result<(), RoxideError> {
+    token: &str,
+    query: ListQuery,
+) -> Result<Json<FileList>, RoxideError> {
+    if !is_token_valid(token, app_config) {
+        return Err(RoxideError::InvalidToken);
+    }
+    let sort = query.sort.unwrap_or(SortKey::UploadDate);
+    let order = query.order.unwrap_or_else(|| sort.default_order());
+    let limit = query.limit.unwrap_or(LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(Cursor::decode(cursor, sort, order)?),
+        None => None,
+    };
+
     let now = Utc::now().timestamp();
+    let mut conditions = Conditions::default();
+    conditions.push(
+        "visibility = 'public' AND NOT require_signature AND expiration_date > ? AND (max_downloads IS NULL OR download_count < max_downloads)",
+        vec![SqlValue::Int(now)],
+    );
+    if let Some(q) = &query.q {
+        conditions.push(
+            "LOWER(title) LIKE ? ESCAPE '\\'",
+            vec![SqlValue::Text(format!(
+                "%{}%",
+                escape_like(&q.to_lowercase())
+            ))],
+        );
+    }
+    if let Some(content_type) = &query.content_type {
+        conditions.push(
+            "content_type LIKE ? ESCAPE '\\'",
+            vec![SqlValue::Text(format!(
+                "{}%",
+                escape_like(&content_type.to_lowercase())
+            ))],
+        );
+    }
+    let bounds = [
+        ("upload_date >= ?", query.uploaded_after),
+        ("upload_date <= ?", query.uploaded_before),
+        ("size >= ?", query.min_size),
+        ("size <= ?", query.max_size),
+    ];
+    for (condition, bound) in bounds {
+        if let Some(bound) = bound {
+            conditions.push(condition, vec![SqlValue::Int(bound)]);
+        }
+    }
+    for tag in &query.tag {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM file_tags JOIN tags ON tags.id = file_tags.tag_id WHERE file_tags.file_id = files.id AND tags.name = ?)",
+            vec![SqlValue::Text(normalize_tag(tag))],
+        );
+    }
 
-    //Select all expired files
-    let expired_rows = sqlx::query("SELECT id FROM files WHERE expiration_date < $1")
-        .bind(&now)
-        .fetch_all(&mut **db)
-        .await?;
+    //Count the files before the cursor restricts them to the page
+    let total = conditions
+        .bind(sqlx::query(&format!(
+            "SELECT count(1) AS total FROM files WHERE {}",
+            conditions.to_sql()
+        )))
+        .fetch_one(&mut *db)
+        .await?
+        .get::<i64, &str>("total");
 
-    //Iterate over the row to delete the files
-    for id in expired_rows.iter().map(|row| row.get::<&str, &str>("id")) {
-        let id = FileId::from(id);
-        fs::remove_file(id.file_path(&app_config.upload_directory))?;
+    if let Some(cursor) = cursor {
+        let comparison = match order {
+            Order::Asc => ">",
+            Order::Desc => "<",
+        };
+        conditions.push(
+            &format!(
+                "({0} {1} ? OR ({0} = ? AND id {1} ?))",
+                sort.column(),
+                comparison
+            ),
+            vec![
+                cursor.value.clone(),
+                cursor.value,
+                SqlValue::Text(cursor.id),
+            ],
+        );
     }
+    //One more file than the page tells whether there is a next page
+    let sql = format!(
+        "SELECT id, upload_date, content_type, download_count, size, title, password_hash IS NOT NULL AS protected FROM files WHERE {} ORDER BY {1} {2}, id {2} LIMIT {3}",
+        conditions.to_sql(),
+        sort.column(),
+        order.keyword(),
+        limit + 1
+    );
+    let mut files = conditions
+        .bind(sqlx::query(&sql))
+        .fetch_all(&mut *db)
+        .await?
+        .iter()
+        .map(FileData::from_row)
+        .collect::<Vec<_>>();
 
-    //Delete the expired files from the database
-    sqlx::query("DELETE FROM files WHERE expiration_date < $1")
-        .bind(&now)
-        .execute(&mut **db)
-        .await?;
+    let next_cursor = if files.len() as i64 > limit {
+        files.truncate(limit as usize);
+        files
+            .last()
+            .map(|file| Cursor::after(sort, order, file).encode())
+    } else {
+        None
+    };
+    add_tags(&mut db, &mut files).await?;
+    Ok(Json(FileList {
+        files,
+        next_cursor,
+        total,
+    }))
+}
 
-    Ok(())
+/// Token of the uploader of a file, sent as a bearer token (`Authorization: Bearer <token>`).
+pub struct OwnerToken<'r>(pub &'r str);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OwnerToken<'r> {
+    type Error = RoxideError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = bearer_token(req);
+        let valid = match (token, req.rocket().state::<AppConfig>()) {
+            (Some(token), Some(app_config)) => is_token_valid(token, app_config),
+            _ => false,
+        };
+        match token {
+            Some(token) if valid => request::Outcome::Success(OwnerToken(token)),
+            _ => request::Outcome::Failure((Status::Unauthorized, RoxideError::InvalidToken)),
+        }
+    }
+}
+
+/// Changes that the uploader can apply to a file, the missing fields are left untouched.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct FileChanges {
+    title: Option<String>,
+    visibility: Option<Visibility>,
+    /// Previous form of the visibility, `false` for an unlisted file.
+    public: Option<bool>,
+    expiration_date: Option<i64>,
+    require_signature: Option<bool>,
+}
+
+/// Function that checks that the file *id* exists, has not expired and was uploaded with *token*.
+///
+/// Return the upload date of the file.
+pub async fn check_owner(
+    db: &mut AnyConnection,
+    id: &FileId,
+    token: &str,
+) -> Result<i64, RoxideError> {
+    let row =
+        sqlx::query("SELECT token_used, upload_date, expiration_date FROM files WHERE id = $1")
+            .bind(id.get_id())
+            .fetch_one(&mut *db)
+            .await?;
+    if row.get::<i64, &str>("expiration_date") <= Utc::now().timestamp() {
+        return Err(RoxideError::Expired);
+    }
+    if row.get::<&str, &str>("token_used") != token {
+        return Err(RoxideError::Forbidden);
+    }
+    Ok(row.get::<i64, &str>("upload_date"))
+}
+
+/// Function that deletes a file, on behalf of its uploader.
+#[delete("/file/<id>")]
+async fn delete(
+    storage: &State<Storage>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    token: OwnerToken<'_>,
+) -> Result<Status, RoxideError> {
+    check_owner(&mut db, &id, token.0).await?;
+    delete_file(&mut db, storage, &id).await?;
+    Ok(Status::NoContent)
+}
+
+/// Function that changes the title, the visibility, the expiration date or the need of a signed
+/// link of a file, on behalf of its uploader.
+///
+/// The new expiration date must be in the future, and at most `max_duration` after the upload
+/// date.
+#[patch("/file/<id>", data = "<changes>")]
+async fn update(
+    app_config: &State<AppConfig>,
+    mut db: Connection<Canard>,
+    id: FileId,
+    token: OwnerToken<'_>,
+    changes: Json<FileChanges>,
+) -> Result<Status, RoxideError> {
+    let upload_date = check_owner(&mut db, &id, token.0).await?;
+    if let Some(expiration) = changes.expiration_date {
+        let max_expiration = upload_date.saturating_add(app_config.max_duration);
+        if expiration <= Utc::now().timestamp() || expiration > max_expiration {
+            return Err(RoxideError::InvalidDuration);
+        }
+    }
+
+    let visibility = changes
+        .visibility
+        .or_else(|| changes.public.map(Visibility::from_public));
+    // The title of the index changes with the title of the file
+    let mut tx = db.begin().await?;
+    sqlx::query("UPDATE files SET title = COALESCE($1, title), visibility = COALESCE($2, visibility), expiration_date = COALESCE($3, expiration_date), require_signature = COALESCE($4, require_signature) WHERE id = $5")
+        .bind(changes.title.as_deref())
+        .bind(visibility.map(Visibility::as_str))
+        .bind(changes.expiration_date)
+        .bind(changes.require_signature)
+        .bind(id.get_id())
+        .execute(&mut *tx)
+        .await?;
+    if let Some(title) = &changes.title {
+        rename_file(&mut tx, &id, title).await?;
+    }
+    tx.commit().await?;
+    Ok(Status::NoContent)
 }
 
 /// Function that mounts the routes for user URL in Rocket.
 /// - get (to retrieve a file).
+/// - thumbnail (to retrieve the thumbnail of an image).
 /// - post (to upload a file).
-/// - clean (to trigger a cleanning of the database)
+/// - delete and update (to manage a file uploaded with a token)
 pub fn stage() -> AdHoc {
     AdHoc::on_ignite("User stage", |rocket| async {
-        rocket.mount("/", routes![get, post, clean, list])
+        rocket.mount("/", routes![get, thumbnail, post, list, delete, update])
     })
 }
+
+/// Function that registers the file *id* for the tests: public, uploaded with the token `token`
+/// and expiring in an hour, then changed by the SQL assignments *changes* (e.g.
+/// `visibility = 'private'`), if any.
+#[cfg(test)]
+pub async fn test_file(db: &mut AnyConnection, id: &str, changes: &str) {
+    sqlx::query("INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, visibility, title, blob, require_signature) VALUES ($1, $2, 0, 'token', 'text/plain', 0, 0, 'public', $1, 'digest', FALSE)")
+        .bind(id)
+        .bind(Utc::now().timestamp() + 3600)
+        .execute(&mut *db)
+        .await
+        .unwrap();
+    if !changes.is_empty() {
+        sqlx::query(&format!("UPDATE files SET {} WHERE id = $1", changes))
+            .bind(id)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::Value;
+    use rocket::tokio;
+
+    use super::*;
+    use crate::db::test_pool;
+    use crate::storage::LocalStorage;
+    use crate::test_client;
+
+    /// Register a file of *content* limited to *max_downloads*, stored under the digest *digest*.
+    async fn limited_file(
+        db: &mut AnyConnection,
+        storage_dir: &std::path::Path,
+        id: &str,
+        digest: &str,
+        max_downloads: i64,
+    ) {
+        std::fs::write(storage_dir.join(digest), b"secret").unwrap();
+        sqlx::query("INSERT INTO blobs (digest, size, ref_count) VALUES ($1, 6, 1)")
+            .bind(digest)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, visibility, title, blob, max_downloads, require_signature) VALUES ($1, $2, 0, 'token', 'text/plain', 6, 0, 'public', '', $3, $4, FALSE)")
+            .bind(id)
+            .bind(Utc::now().timestamp() + 3600)
+            .bind(digest)
+            .bind(max_downloads)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+    }
+
+    fn opened_file(digest: &str, max_downloads: i64) -> OpenedFile {
+        OpenedFile {
+            title: String::new(),
+            content_type: "text/plain".to_string(),
+            digest: digest.to_string(),
+            size: 6,
+            upload_date: 0,
+            expiration_date: i64::MAX,
+            download_count: 0,
+            max_downloads: Some(max_downloads),
+            language: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn one_time_file_is_sent_once_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_dir = dir.path().join("upload");
+        std::fs::create_dir(&storage_dir).unwrap();
+        let pool = test_pool(dir.path()).await;
+        let storage: Storage = Arc::new(LocalStorage::new(storage_dir.to_str().unwrap()));
+        limited_file(
+            &mut pool.acquire().await.unwrap(),
+            &storage_dir,
+            "once",
+            "d1",
+            1,
+        )
+        .await;
+
+        let downloads = (0..8).map(|_| {
+            let (pool, storage) = (pool.clone(), storage.clone());
+            tokio::spawn(async move {
+                let mut db = pool.acquire().await.unwrap();
+                let file = opened_file("d1", 1);
+                count_download(&storage, &mut db, &FileId::from("once"), &file).await
+            })
+        });
+        let mut sent = 0;
+        for download in downloads.collect::<Vec<_>>() {
+            match download.await.unwrap() {
+                Ok(()) => sent += 1,
+                Err(RoxideError::Expired) => {}
+                Err(err) => panic!("unexpected error: {:?}", err),
+            }
+        }
+        assert_eq!(sent, 1);
+
+        let mut db = pool.acquire().await.unwrap();
+        let rows = sqlx::query("SELECT id FROM files WHERE id = 'once'")
+            .fetch_all(&mut *db)
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        assert!(!storage_dir.join("d1").exists());
+    }
+
+    #[rocket::async_test]
+    async fn limited_file_is_kept_until_its_last_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_dir = dir.path().join("upload");
+        std::fs::create_dir(&storage_dir).unwrap();
+        let pool = test_pool(dir.path()).await;
+        let storage: Storage = Arc::new(LocalStorage::new(storage_dir.to_str().unwrap()));
+        let mut db = pool.acquire().await.unwrap();
+        limited_file(&mut db, &storage_dir, "twice", "d2", 2).await;
+
+        let (id, file) = (FileId::from("twice"), opened_file("d2", 2));
+        count_download(&storage, &mut db, &id, &file).await.unwrap();
+        assert!(storage_dir.join("d2").exists());
+        count_download(&storage, &mut db, &id, &file).await.unwrap();
+        assert!(!storage_dir.join("d2").exists());
+        assert!(matches!(
+            count_download(&storage, &mut db, &id, &file).await,
+            Err(RoxideError::Expired)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn expired_files_keep_a_tombstone_unless_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_dir = dir.path().join("upload");
+        std::fs::create_dir(&storage_dir).unwrap();
+        let pool = test_pool(dir.path()).await;
+        let storage: Storage = Arc::new(LocalStorage::new(storage_dir.to_str().unwrap()));
+        let mut db = pool.acquire().await.unwrap();
+        limited_file(&mut db, &storage_dir, "public", "d3", 1).await;
+        limited_file(&mut db, &storage_dir, "private", "d4", 1).await;
+        sqlx::query("UPDATE files SET visibility = 'private' WHERE id = 'private'")
+            .execute(&mut *db)
+            .await
+            .unwrap();
+
+        let (public, private) = (FileId::from("public"), FileId::from("private"));
+        count_download(&storage, &mut db, &public, &opened_file("d3", 1))
+            .await
+            .unwrap();
+        expire_file(&mut db, &storage, &private).await.unwrap();
+        assert!(is_tombstone(&mut db, &public).await.unwrap());
+        assert!(!is_tombstone(&mut db, &private).await.unwrap());
+
+        clean_tombstones(&mut db).await.unwrap();
+        assert!(is_tombstone(&mut db, &public).await.unwrap());
+        sqlx::query("UPDATE tombstones SET deletion_date = $1")
+            .bind(Utc::now().timestamp() - TOMBSTONE_DURATION)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+        clean_tombstones(&mut db).await.unwrap();
+        assert!(!is_tombstone(&mut db, &public).await.unwrap());
+    }
+
+    /// Register a public file *id* of *size* bytes named *title*.
+    async fn public_file(db: &mut AnyConnection, id: &str, title: &str, size: i64) {
+        sqlx::query("INSERT INTO files (id, expiration_date, upload_date, token_used, content_type, size, download_count, visibility, title, blob, require_signature) VALUES ($1, $2, 0, 'token', 'text/plain', $3, 0, 'public', $4, 'digest', FALSE)")
+            .bind(id)
+            .bind(Utc::now().timestamp() + 3600)
+            .bind(size)
+            .bind(title)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+    }
+
+    /// Read the ids of the files of `/list/token?<query>`, following the cursors.
+    async fn list_ids(client: &Client, query: &str) -> Vec<String> {
+        let mut ids = Vec::new();
+        let mut uri = format!("/list/token?{}", query);
+        loop {
+            let page = client
+                .get(uri.as_str())
+                .dispatch()
+                .await
+                .into_json::<Value>()
+                .await
+                .unwrap();
+            assert_eq!(page["total"], 5);
+            let files = page["files"].as_array().unwrap();
+            assert!(files.len() <= 2);
+            ids.extend(
+                files
+                    .iter()
+                    .map(|file| file["id"].as_str().unwrap().to_string()),
+            );
+            match page["next_cursor"].as_str() {
+                Some(cursor) => uri = format!("/list/token?{}&cursor={}", query, cursor),
+                None => return ids,
+            }
+        }
+    }
+
+    #[rocket::async_test]
+    async fn list_pages_follow_their_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        for (id, title, size) in [
+            ("a", "b:2", 10),
+            ("b", "b:1", 30),
+            ("c", "a", 30),
+            ("d", "c", 20),
+            ("e", "b:1", 30),
+        ] {
+            public_file(&mut db, id, title, size).await;
+        }
+        let client = test_client(dir.path(), routes![list]).await;
+
+        assert_eq!(
+            list_ids(&client, "sort=size&limit=2").await,
+            ["e", "c", "b", "d", "a"]
+        );
+        assert_eq!(
+            list_ids(&client, "sort=size&order=asc&limit=2").await,
+            ["a", "d", "b", "c", "e"]
+        );
+        assert_eq!(
+            list_ids(&client, "sort=title&limit=2").await,
+            ["c", "b", "e", "a", "d"]
+        );
+
+        // A cursor is only valid for the order that gave it
+        let page = client
+            .get("/list/token?sort=size&limit=2")
+            .dispatch()
+            .await
+            .into_json::<Value>()
+            .await
+            .unwrap();
+        let cursor = page["next_cursor"].as_str().unwrap();
+        let response = client
+            .get(format!("/list/token?sort=title&limit=2&cursor={}", cursor))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .get("/list/token?limit=2&cursor=not-a-cursor")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn list_is_filtered_by_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        let files = [
+            ("a", "", vec!["logo", "draft"]),
+            ("b", "", vec!["logo"]),
+            ("c", "", vec!["draft"]),
+            ("d", "visibility = 'private'", vec!["logo"]),
+        ];
+        for (id, changes, tags) in files {
+            test_file(&mut db, id, changes).await;
+            let tags = tags.into_iter().map(String::from).collect::<Vec<_>>();
+            set_tags(&mut db, &FileId::from(id), &tags).await.unwrap();
+        }
+        let client = test_client(dir.path(), routes![list]).await;
+        let list = |query: &'static str| {
+            let client = &client;
+            async move {
+                let page = client
+                    .get(format!("/list/token?sort=title&{}", query))
+                    .dispatch()
+                    .await
+                    .into_json::<Value>()
+                    .await
+                    .unwrap();
+                page["files"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|file| file["id"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(list("tag=Logo").await, ["a", "b"]);
+        assert_eq!(list("tag=logo&tag=draft").await, ["a"]);
+        assert!(list("tag=unknown").await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn update_renames_the_file_in_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = test_pool(dir.path()).await;
+        let mut db = pool.acquire().await.unwrap();
+        test_file(&mut db, "a", "").await;
+        sqlx::query("INSERT INTO file_search (file_id, title, content) VALUES ('a', 'a', '')")
+            .execute(&mut *db)
+            .await
+            .unwrap();
+        let client = test_client(dir.path(), routes![update]).await;
+        let rename = |token: &str| {
+            client
+                .patch("/file/a")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .header(ContentType::JSON)
+                .body(r#"{"title": "Report"}"#)
+                .dispatch()
+        };
+        let titles = || async {
+            let mut db = pool.acquire().await.unwrap();
+            let row = sqlx::query("SELECT files.title AS title, file_search.title AS indexed FROM files JOIN file_search ON file_search.file_id = files.id WHERE files.id = 'a'")
+                .fetch_one(&mut *db)
+                .await
+                .unwrap();
+            (
+                row.get::<String, &str>("title"),
+                row.get::<String, &str>("indexed"),
+            )
+        };
+
+        assert_eq!(rename("other").await.status(), Status::Forbidden);
+        assert_eq!(titles().await, ("a".to_string(), "a".to_string()));
+        assert_eq!(rename("token").await.status(), Status::NoContent);
+        assert_eq!(titles().await, ("Report".to_string(), "Report".to_string()));
+    }
+}
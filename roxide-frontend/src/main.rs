@@ -10,15 +10,22 @@ pub struct Token(String);
 
 pub enum Msg {
     Files(Vec<File>),
+    Password(String),
+    Grouped(bool),
     Upload(Token),
+    CollectionCreated(Token, String),
     Uploaded(String),
+    Failed(String),
 }
 
 #[derive(Debug, Default)]
 pub struct Model {
     files: Vec<File>,
     results: Vec<String>,
+    errors: Vec<String>,
     duration: Option<i64>,
+    password: String,
+    grouped: bool,
 }
 
 fn get_location_token() -> Option<Token> {
@@ -30,15 +37,51 @@ fn get_location_token() -> Option<Token> {
         .map(Token)
 }
 
-fn get_url_of(upload_id: &str) -> Option<String> {
+fn error_message(err: JsValue) -> String {
+    err.as_string().unwrap_or_else(|| format!("{:?}", err))
+}
+
+fn get_url_of(path: &str) -> Option<String> {
     let window = web_sys::window()?;
     let location = window.location();
     let protocol = location.protocol().ok()?;
     let host = location.host().ok()?;
-    Some(format!("{protocol}//{host}/get/{upload_id}"))
+    Some(format!("{protocol}//{host}{path}"))
+}
+
+async fn create_collection(token: Token, duration: Option<i64>) -> Result<Msg, JsValue> {
+    let form = web_sys::FormData::new()?;
+    if let Some(duration) = duration {
+        form.append_with_str("duration", &duration.to_string())?;
+    }
+
+    let res = Request::post(&format!("/collection/{}", token.0))
+        .body(form)
+        .send()
+        .await
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+    if res.status() == 200 {
+        let text = res
+            .text()
+            .await
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(Msg::CollectionCreated(token, text))
+    } else {
+        Err(JsValue::from_str(&format!(
+            "http error: {}",
+            res.status_text()
+        )))
+    }
 }
 
-async fn upload_file(file: File, token: &Token, duration: Option<i64>) -> Result<Msg, JsValue> {
+async fn upload_file(
+    file: File,
+    token: &Token,
+    duration: Option<i64>,
+    password: &str,
+    collection: Option<&str>,
+) -> Result<Msg, JsValue> {
     let name = file.name();
 
     let form = web_sys::FormData::new()?;
@@ -47,6 +90,12 @@ async fn upload_file(file: File, token: &Token, duration: Option<i64>) -> Result
     if let Some(duration) = duration {
         form.append_with_str("duration", &duration.to_string())?;
     }
+    if !password.is_empty() {
+        form.append_with_str("password", password)?;
+    }
+    if let Some(collection) = collection {
+        form.append_with_str("collection", collection)?;
+    }
 
     let res = Request::post(&format!("/post/{}", token.0))
         .body(form)
@@ -82,21 +131,44 @@ impl Component for Model {
                 self.files.extend(files);
                 true
             }
+            Msg::Password(password) => {
+                self.password = password;
+                false
+            }
+            Msg::Grouped(grouped) => {
+                self.grouped = grouped;
+                false
+            }
             Msg::Upload(token) => {
-                let duration = self.duration;
-                self.files.drain(..).for_each(|file| {
-                    let token = token.clone();
+                // Several files are put in a new collection first, if asked
+                if self.grouped && self.files.len() > 1 {
+                    let duration = self.duration;
                     ctx.link().send_future(async move {
-                        match upload_file(file, &token, duration).await {
+                        match create_collection(token, duration).await {
                             Ok(msg) => msg,
-                            Err(_) => todo!(),
+                            Err(err) => Msg::Failed(format!(
+                                "Cannot create the collection: {}",
+                                error_message(err)
+                            )),
                         }
                     });
-                });
+                    return false;
+                }
+                self.upload_files(ctx, token, None);
+                true
+            }
+            Msg::CollectionCreated(token, collection) => {
+                self.results
+                    .extend(get_url_of(&format!("/c/{}", collection)));
+                self.upload_files(ctx, token, Some(collection));
                 true
             }
             Msg::Uploaded(res) => {
-                self.results.extend(get_url_of(&res));
+                self.results.extend(get_url_of(&format!("/f/{}", res)));
+                true
+            }
+            Msg::Failed(error) => {
+                self.errors.push(error);
                 true
             }
         }
@@ -119,6 +191,14 @@ impl Component for Model {
             }
             Msg::Files(result)
         };
+        let on_password = |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            Msg::Password(input.value())
+        };
+        let on_grouped = |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            Msg::Grouped(input.checked())
+        };
 
         if let Some(token) = get_location_token() {
             let upload_callback = move |_| Msg::Upload(token.clone());
@@ -132,9 +212,22 @@ impl Component for Model {
                     <ul>
                         { for self.files.iter().map(Self::view_file) }
                     </ul>
+                    <div>
+                        <p>{ "Password (optional)" }</p>
+                        <input type="password" onchange={ ctx.link().callback(on_password) } />
+                    </div>
+                    <div>
+                        <label>
+                            <input type="checkbox" onchange={ ctx.link().callback(on_grouped) } />
+                            { "Put the files in one collection" }
+                        </label>
+                    </div>
                     <div>
                         <input value="Upload" type="button" onclick={ctx.link().callback(upload_callback)} />
                     </div>
+                    <div>
+                        { for self.errors.iter().map(|error| Self::view_error(error)) }
+                    </div>
                     <div>
                         { for self.results.iter().map(|url| Self::view_url(url.clone())) }
                     </div>
@@ -149,6 +242,25 @@ impl Component for Model {
 }
 
 impl Model {
+    fn upload_files(&mut self, ctx: &Context<Self>, token: Token, collection: Option<String>) {
+        let duration = self.duration;
+        let password = self.password.clone();
+        self.files.drain(..).for_each(|file| {
+            let token = token.clone();
+            let password = password.clone();
+            let collection = collection.clone();
+            let name = file.name();
+            ctx.link().send_future(async move {
+                match upload_file(file, &token, duration, &password, collection.as_deref()).await {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        Msg::Failed(format!("Cannot upload {}: {}", name, error_message(err)))
+                    }
+                }
+            });
+        });
+    }
+
     fn view_file(data: &File) -> Html {
         let name = data.name();
         let mimetype = data.raw_mime_type();
@@ -158,6 +270,12 @@ impl Model {
         }
     }
 
+    fn view_error(error: &str) -> Html {
+        html! {
+            <p class="error">{ error }</p>
+        }
+    }
+
     fn view_url(url: String) -> Html {
         let url2 = url.clone();
         html! {